@@ -52,6 +52,10 @@ fn main() {
             "cargo::rustc-env=FUSE-OVERLAYFS-BIN={}",
             &exec_path.to_string_lossy()
         );
+        println!(
+            "cargo::rustc-env=FUSE-OVERLAYFS-VERSION={}",
+            vendored::version(&exec_path)
+        );
     }
 
     #[cfg(all(feature = "unionfs-fuse-vendored", target_os = "linux"))]
@@ -83,6 +87,10 @@ fn main() {
             "cargo::rustc-env=UNIONFS-FUSE-BIN={}",
             &exec_path.to_string_lossy()
         );
+        println!(
+            "cargo::rustc-env=UNIONFS-FUSE-VERSION={}",
+            vendored::version(&exec_path)
+        );
     }
 }
 
@@ -102,6 +110,24 @@ mod vendored {
         Err(std::io::Error::other("Cannot get submodule"))
     }
 
+    /// Retrieve the version line reported by the built binary
+    #[inline]
+    #[allow(dead_code)]
+    pub fn version(exec: &std::path::Path) -> String {
+        std::process::Command::new(exec)
+            .arg("--version")
+            .output()
+            .ok()
+            .and_then(|x| {
+                String::from_utf8_lossy(&x.stdout)
+                    .lines()
+                    .chain(String::from_utf8_lossy(&x.stderr).lines())
+                    .find(|x| x.to_lowercase().contains("version"))
+                    .map(|x| x.to_string())
+            })
+            .unwrap_or_default()
+    }
+
     #[inline]
     #[allow(dead_code)]
     #[cfg(feature = "build-cache")]
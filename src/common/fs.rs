@@ -63,6 +63,128 @@ pub trait StackableFilesystem: Filesystem {
     fn set_upper(&mut self, upper: impl Into<PathBuf>) -> Result<()>;
 }
 
+/// Object-safe counterpart of Filesystem, allowing handle of different kind to be stored together
+/// ex: `Vec<Box<dyn DynFilesystem>>`
+///
+/// Automatically implemented for every Filesystem, methods are prefixed with `dyn_` so both
+/// traits can be imported together
+pub trait DynFilesystem {
+    #[must_use = "Error on filesystem operation should be handled"]
+    /// Request a handle to mount the filesystem, returning a PathBuf pointing to the mount point
+    fn dyn_mount(&mut self) -> Result<PathBuf>;
+
+    #[must_use = "Error on filesystem operation should be handled"]
+    /// Request a handle to unmount the filesystem
+    fn dyn_unmount(&mut self) -> Result<()>;
+
+    /// Retrieve unmount_on_drop property
+    fn dyn_unmount_on_drop(&self) -> bool;
+
+    /// Set unmount_on_drop property
+    fn dyn_set_unmount_on_drop(&mut self, drop: bool);
+
+    /// Retrieve the partition Identifier
+    fn dyn_id(&self) -> Option<&PartitionID>;
+
+    /// Retrieve the mount point as PathBuf
+    fn dyn_target(&self) -> PathBuf;
+
+    /// Set Target mount point
+    fn dyn_set_target(&mut self, target: &Path) -> Result<()>;
+
+    /// Get if the filesystem backing this handle is available
+    fn dyn_is_available(&self) -> bool;
+
+    /// Check if the partition is mounted
+    fn dyn_mounted(&self) -> bool;
+}
+
+impl<T: Filesystem> DynFilesystem for T {
+    #[inline]
+    fn dyn_mount(&mut self) -> Result<PathBuf> {
+        Filesystem::mount(self)
+    }
+
+    #[inline]
+    fn dyn_unmount(&mut self) -> Result<()> {
+        Filesystem::unmount(self)
+    }
+
+    #[inline]
+    fn dyn_unmount_on_drop(&self) -> bool {
+        Filesystem::unmount_on_drop(self)
+    }
+
+    #[inline]
+    fn dyn_set_unmount_on_drop(&mut self, drop: bool) {
+        Filesystem::set_unmount_on_drop(self, drop)
+    }
+
+    #[inline]
+    fn dyn_id(&self) -> Option<&PartitionID> {
+        Filesystem::id(self)
+    }
+
+    #[inline]
+    fn dyn_target(&self) -> PathBuf {
+        Filesystem::target(self)
+    }
+
+    #[inline]
+    fn dyn_set_target(&mut self, target: &Path) -> Result<()> {
+        Filesystem::set_target(self, target)
+    }
+
+    #[inline]
+    fn dyn_is_available(&self) -> bool {
+        T::is_available()
+    }
+
+    #[inline]
+    fn dyn_mounted(&self) -> bool {
+        Filesystem::mounted(self)
+    }
+}
+
+/// Object-safe counterpart of StackableFilesystem
+///
+/// Automatically implemented for every StackableFilesystem
+pub trait DynStackableFilesystem: DynFilesystem {
+    /// Retrieve a list of lower layer
+    fn dyn_lower(&self) -> Vec<&Path>;
+
+    /// Set lower layer
+    fn dyn_set_lower(&mut self, lower: Vec<PathBuf>) -> Result<()>;
+
+    /// Retrieve upper layer if set
+    fn dyn_upper(&self) -> Option<&Path>;
+
+    /// Set upper layer
+    fn dyn_set_upper(&mut self, upper: PathBuf) -> Result<()>;
+}
+
+impl<T: StackableFilesystem> DynStackableFilesystem for T {
+    #[inline]
+    fn dyn_lower(&self) -> Vec<&Path> {
+        StackableFilesystem::lower(self)
+    }
+
+    #[inline]
+    fn dyn_set_lower(&mut self, lower: Vec<PathBuf>) -> Result<()> {
+        StackableFilesystem::set_lower(self, lower)
+    }
+
+    #[inline]
+    fn dyn_upper(&self) -> Option<&Path> {
+        StackableFilesystem::upper(self)
+    }
+
+    #[inline]
+    fn dyn_set_upper(&mut self, upper: PathBuf) -> Result<()> {
+        StackableFilesystem::set_upper(self, upper)
+    }
+}
+
 /// Common trait for all case-insensitive filesystem handles
 #[allow(dead_code)]
 pub trait CaseInsensitive: Filesystem {}
@@ -0,0 +1,251 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+};
+
+use tracing::{debug, error};
+
+use super::fs::StackableFilesystem;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// Named layer of a profile
+pub struct Layer {
+    pub id: String,
+    pub path: PathBuf,
+    pub enabled: bool,
+    /// Layers with an higher priority are placed on top and win over the others
+    pub priority: i32,
+}
+
+impl Layer {
+    #[must_use = "initialised Layer should be added to a Profile"]
+    #[inline]
+    pub fn new(id: impl Into<String>, path: impl AsRef<Path>) -> Self {
+        Self {
+            id: id.into(),
+            path: path.as_ref().to_path_buf(),
+            enabled: true,
+            priority: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// Load order of named layers
+///
+/// Layers are kept sorted from the lowest to the highest priority, layers sharing a
+/// priority keep the order in which they were added
+pub struct Profile {
+    name: String,
+    layers: Vec<Layer>,
+}
+
+impl Profile {
+    #[must_use = "initialised Profile should be used"]
+    #[inline]
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            layers: vec![],
+        }
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub fn set_name(&mut self, name: impl Into<String>) {
+        self.name = name.into();
+    }
+
+    /// Layers in load order, from the bottom to the top
+    #[inline]
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    #[inline]
+    pub fn layer(&self, id: &str) -> Option<&Layer> {
+        self.layers.iter().find(|x| x.id == id)
+    }
+
+    /// Add a layer according to its priority, ids must be unique
+    pub fn add(&mut self, layer: Layer) -> Result<()> {
+        if self.layer(&layer.id).is_some() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("layer {} is already part of the profile", layer.id),
+            ));
+        }
+        self.layers.push(layer);
+        self.sort();
+        Ok(())
+    }
+
+    /// Add a layer on top of every other
+    pub fn push(&mut self, mut layer: Layer) -> Result<()> {
+        layer.priority = self.layers.last().map_or(0, |x| x.priority + 1);
+        self.add(layer)
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<Layer> {
+        let idx = self.position(id).ok()?;
+        Some(self.layers.remove(idx))
+    }
+
+    #[inline]
+    pub fn enable(&mut self, id: &str) -> Result<()> {
+        self.set_enabled(id, true)
+    }
+
+    #[inline]
+    pub fn disable(&mut self, id: &str) -> Result<()> {
+        self.set_enabled(id, false)
+    }
+
+    pub fn set_enabled(&mut self, id: &str, enabled: bool) -> Result<()> {
+        let idx = self.position(id)?;
+        self.layers[idx].enabled = enabled;
+        Ok(())
+    }
+
+    pub fn set_priority(&mut self, id: &str, priority: i32) -> Result<()> {
+        let idx = self.position(id)?;
+        self.layers[idx].priority = priority;
+        self.sort();
+        Ok(())
+    }
+
+    /// Move a layer to index in load order, priorities are renumbered to match the new order
+    pub fn move_to(&mut self, id: &str, index: usize) -> Result<()> {
+        let idx = self.position(id)?;
+        let layer = self.layers.remove(idx);
+        self.layers.insert(index.min(self.layers.len()), layer);
+        for (priority, layer) in self.layers.iter_mut().enumerate() {
+            layer.priority = priority as i32;
+        }
+        Ok(())
+    }
+
+    /// Enabled layers ordered from the top to the bottom, as expected by every backend
+    pub fn lower(&self) -> Vec<PathBuf> {
+        self.layers
+            .iter()
+            .rev()
+            .filter(|x| x.enabled)
+            .map(|x| x.path.clone())
+            .collect()
+    }
+
+    /// Update the lower layers of handle, return false when they were already up to date
+    ///
+    /// No backend can swap layers on a live remount, a mounted handle is unmounted and mounted
+    /// again with the new layers, the previous layers are restored when the mount fail
+    pub fn apply<F: StackableFilesystem>(&self, handle: &mut F) -> Result<bool> {
+        let lower = self.lower();
+        if handle
+            .lower()
+            .iter()
+            .copied()
+            .eq(lower.iter().map(|x| x.as_path()))
+        {
+            return Ok(false);
+        }
+        if !handle.mounted() {
+            handle.set_lower(lower)?;
+            return Ok(true);
+        }
+        let previous: Vec<PathBuf> = handle.lower().iter().map(|x| x.to_path_buf()).collect();
+        debug!(
+            "Damascus: remounting {:?} with profile {}",
+            handle.target(),
+            self.name
+        );
+        handle.unmount()?;
+        if let Err(err) = handle.set_lower(lower).and_then(|_| handle.mount()) {
+            if let Err(err) = handle.set_lower(previous).and_then(|_| handle.mount()) {
+                error!(
+                    "Damascus: unable to restore previous layers of {:?} because : {}",
+                    handle.target(),
+                    err
+                );
+            }
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    fn position(&self, id: &str) -> Result<usize> {
+        self.layers
+            .iter()
+            .position(|x| x.id == id)
+            .ok_or(Error::new(
+                ErrorKind::NotFound,
+                format!("layer {} is not part of the profile", id),
+            ))
+    }
+
+    #[inline]
+    fn sort(&mut self) {
+        self.layers.sort_by_key(|x| x.priority);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_order() {
+        let mut profile = Profile::new("default");
+        profile.push(Layer::new("base", "/mods/base")).unwrap();
+        profile
+            .push(Layer::new("textures", "/mods/textures"))
+            .unwrap();
+        profile.push(Layer::new("patch", "/mods/patch")).unwrap();
+        assert!(profile.push(Layer::new("patch", "/mods/other")).is_err());
+        assert_eq!(
+            profile.lower(),
+            [
+                PathBuf::from("/mods/patch"),
+                PathBuf::from("/mods/textures"),
+                PathBuf::from("/mods/base")
+            ]
+        );
+
+        profile.disable("textures").unwrap();
+        profile.move_to("patch", 0).unwrap();
+        assert_eq!(
+            profile.lower(),
+            [PathBuf::from("/mods/base"), PathBuf::from("/mods/patch")]
+        );
+        assert_eq!(profile.layer("patch").unwrap().priority, 0);
+
+        profile.set_priority("patch", 10).unwrap();
+        let mut layer = Layer::new("fix", "/mods/fix");
+        layer.priority = 5;
+        profile.add(layer).unwrap();
+        assert_eq!(
+            profile.lower(),
+            [
+                PathBuf::from("/mods/patch"),
+                PathBuf::from("/mods/fix"),
+                PathBuf::from("/mods/base")
+            ]
+        );
+        assert!(profile.enable("missing").is_err());
+        assert_eq!(profile.remove("fix").unwrap().id, "fix");
+    }
+}
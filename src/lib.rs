@@ -12,7 +12,11 @@
 mod common;
 mod os;
 pub use common::{
-    fs::{CaseInsensitive, Filesystem, StackableFilesystem, StateRecovery},
+    fs::{
+        CaseInsensitive, DynFilesystem, DynStackableFilesystem, Filesystem, StackableFilesystem,
+        StateRecovery,
+    },
+    profile::{Layer, Profile},
     utils::partition::PartitionID,
 };
 pub use os::*;
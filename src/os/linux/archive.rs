@@ -0,0 +1,747 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+/*
+* Read-only FUSE filesystem exposing the content of a zip archive
+*/
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    ffi::{CString, OsStr, OsString},
+    fs::{File, OpenOptions},
+    io::{Error, ErrorKind, Read, Result},
+    os::unix::fs::{FileExt, OpenOptionsExt},
+    path::{Component, Path, PathBuf},
+    sync::{
+        Arc, Mutex, MutexGuard, Weak,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+use fuser::{
+    BackgroundSession, Config, Errno, FileAttr, FileHandle, FileType, FopenFlags, Generation,
+    INodeNo, LockOwner, OpenFlags, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
+    ReplyOpen, Request, SessionACL,
+};
+use nix::{
+    libc,
+    unistd::{getegid, geteuid},
+};
+use tracing::{debug, error};
+use zip::ZipArchive;
+
+use crate::{AsCString, AsPath, Filesystem, PartitionID};
+
+/// FUSE subtype reported in mountinfo
+const ARCHIVE_SUBTYPE: &str = "damascus-zip";
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+/// Archive content never change, let the kernel cache attributes for long
+const TTL: Duration = Duration::from_secs(3600);
+/// Default amount of decompressed data kept in memory
+const DEFAULT_CACHE_SIZE: u64 = 64 * 1024 * 1024;
+/// Files bigger than this are never decompressed to memory
+const MAX_INLINE: u64 = 16 * 1024 * 1024;
+/// Prefix of the temporary files holding big decompressed files when O_TMPFILE is unsupported
+const SPILL_PREFIX: &str = "damascus-zip-";
+
+#[derive(Debug)]
+/// Handle of a zip archive exposed as a read-only directory tree
+///
+/// Files are decompressed on first open then kept in a bounded cache, the ones too big for it
+/// are decompressed to an unlinked file of the temporary directory instead. The filesystem is
+/// served by a thread of the current process and go away with it
+pub struct ArchiveMount {
+    archive: PathBuf,
+    target: CString,
+    cache_size: u64,
+    session: Option<BackgroundSession>,
+    id: Option<PartitionID>,
+    drop: bool,
+}
+
+impl ArchiveMount {
+    #[must_use = "initialised ArchiveMount handle should be used"]
+    #[inline]
+    pub fn new<A, T>(archive: A, target: T) -> Self
+    where
+        A: AsRef<Path>,
+        T: AsRef<Path>,
+    {
+        Self {
+            archive: archive.as_ref().to_path_buf(),
+            target: target.as_ref().as_cstring(),
+            cache_size: DEFAULT_CACHE_SIZE,
+            session: None,
+            id: None,
+            drop: true,
+        }
+    }
+
+    #[inline]
+    pub fn archive(&self) -> &Path {
+        &self.archive
+    }
+
+    /// Maximum amount of decompressed data kept in memory, in bytes
+    #[inline]
+    pub fn cache_size(&self) -> u64 {
+        self.cache_size
+    }
+
+    #[inline]
+    pub fn set_cache_size(&mut self, cache_size: u64) -> Result<()> {
+        if self.id.is_some() {
+            return Err(Error::other(
+                "cache size cannot be change when the FileSystem is mounted",
+            ));
+        }
+        self.cache_size = cache_size;
+        Ok(())
+    }
+
+    /// Check that a file is an archive which can be mounted
+    pub fn is_archive(path: impl AsRef<Path>) -> Result<bool> {
+        let mut magic = [0u8; 4];
+        Ok(File::open(path)?.read_exact(&mut magic).is_ok() && magic == ZIP_MAGIC)
+    }
+}
+
+impl Filesystem for ArchiveMount {
+    fn mount(&mut self) -> Result<PathBuf> {
+        if matches!(self.id,Some(x) if x == PartitionID::try_from(self.target.as_path())?) {
+            debug!("Damascus: partition already mounted");
+            return Ok(self.target.as_path().to_path_buf());
+        }
+        let fs = ZipFs::open(&self.archive, self.cache_size)?;
+        let mut config = Config::default();
+        config.mount_options = vec![
+            fuser::MountOption::RO,
+            fuser::MountOption::FSName(self.archive.to_string_lossy().to_string()),
+            fuser::MountOption::Subtype(ARCHIVE_SUBTYPE.to_string()),
+            fuser::MountOption::DefaultPermissions,
+        ];
+        // other users reach the archive through the stack built on top of it
+        if geteuid().is_root() {
+            config.acl = SessionACL::All;
+        }
+        self.session = Some(fuser::spawn_mount(fs, self.target.as_path(), &config)?);
+        self.id = Some(
+            PartitionID::try_from(self.target.as_path())
+                .map_err(|_| Error::other("unable to get PartitionID"))?,
+        );
+        Ok(self.target.as_path().to_path_buf())
+    }
+
+    fn unmount(&mut self) -> Result<()> {
+        if let Some(session) = self.session.take() {
+            session.umount_and_join()?;
+        }
+        self.id = None;
+        Ok(())
+    }
+
+    #[inline]
+    fn unmount_on_drop(&self) -> bool {
+        self.drop
+    }
+
+    #[inline]
+    fn set_unmount_on_drop(&mut self, drop: bool) {
+        self.drop = drop;
+    }
+
+    #[inline]
+    fn id(&self) -> Option<&PartitionID> {
+        self.id.as_ref()
+    }
+
+    #[inline]
+    fn target(&self) -> PathBuf {
+        self.target.as_path().to_path_buf()
+    }
+
+    #[inline]
+    fn set_target(&mut self, target: impl AsRef<Path>) -> Result<()> {
+        if self.id.is_some() {
+            return Err(Error::other(
+                "mount point cannot be change when the FileSystem is mounted",
+            ));
+        }
+        self.target = target.as_ref().as_cstring();
+        Ok(())
+    }
+
+    #[inline]
+    fn is_available() -> bool {
+        Path::new("/dev/fuse").exists()
+    }
+}
+
+impl Drop for ArchiveMount {
+    #[inline]
+    fn drop(&mut self) {
+        if !self.drop {
+            // keep serving the archive until the process exit
+            if let Some(session) = self.session.take() {
+                std::mem::forget(session);
+            }
+        } else if let Err(err) = self.unmount() {
+            error!(
+                "Damascus: unable to unmount archive at {:?} because : {}",
+                self.target, err
+            )
+        }
+    }
+}
+
+#[derive(Debug)]
+/// Entry of the archive tree, its inode number is its index plus one
+struct Node {
+    parent: usize,
+    kind: FileType,
+    perm: u16,
+    size: u64,
+    /// Index of the entry in the archive, None for implicit directories
+    entry: Option<usize>,
+    children: BTreeMap<OsString, usize>,
+}
+
+impl Node {
+    fn dir(parent: usize) -> Self {
+        Self {
+            parent,
+            kind: FileType::Directory,
+            perm: 0o755,
+            size: 0,
+            entry: None,
+            children: BTreeMap::new(),
+        }
+    }
+}
+
+/// Decompressed files kept in memory, least recently used are evicted first
+///
+/// Cached files are chained from the least to the most recently used one
+struct Cache {
+    capacity: u64,
+    used: u64,
+    files: HashMap<usize, Cached>,
+    /// Least recently used node
+    head: Option<usize>,
+    /// Most recently used node
+    tail: Option<usize>,
+}
+
+struct Cached {
+    data: Arc<Vec<u8>>,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+impl Cache {
+    fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            used: 0,
+            files: HashMap::new(),
+            head: None,
+            tail: None,
+        }
+    }
+
+    fn get(&mut self, node: usize) -> Option<Arc<Vec<u8>>> {
+        let data = self.files.get(&node)?.data.clone();
+        self.unlink(node);
+        self.push_back(node);
+        Some(data)
+    }
+
+    fn insert(&mut self, node: usize, data: Arc<Vec<u8>>) {
+        let size = data.len() as u64;
+        if size > self.capacity || self.files.contains_key(&node) {
+            return;
+        }
+        while self.used + size > self.capacity {
+            let Some(old) = self.head else {
+                break;
+            };
+            self.unlink(old);
+            if let Some(x) = self.files.remove(&old) {
+                self.used -= x.data.len() as u64;
+            }
+        }
+        self.used += size;
+        self.files.insert(
+            node,
+            Cached {
+                data,
+                prev: None,
+                next: None,
+            },
+        );
+        self.push_back(node);
+    }
+
+    /// Remove a node from the chain, it stay cached
+    fn unlink(&mut self, node: usize) {
+        let Some((prev, next)) = self
+            .files
+            .get_mut(&node)
+            .map(|x| (x.prev.take(), x.next.take()))
+        else {
+            return;
+        };
+        match prev.and_then(|x| self.files.get_mut(&x)) {
+            Some(x) => x.next = next,
+            None => self.head = next,
+        }
+        match next.and_then(|x| self.files.get_mut(&x)) {
+            Some(x) => x.prev = prev,
+            None => self.tail = prev,
+        }
+    }
+
+    /// Chain a node as the most recently used one
+    fn push_back(&mut self, node: usize) {
+        let tail = self.tail;
+        let Some(x) = self.files.get_mut(&node) else {
+            return;
+        };
+        x.prev = tail;
+        match tail.and_then(|x| self.files.get_mut(&x)) {
+            Some(x) => x.next = Some(node),
+            None => self.head = Some(node),
+        }
+        self.tail = Some(node);
+    }
+}
+
+#[derive(Clone)]
+/// Decompressed content of a file
+enum Content {
+    /// Small enough to be kept in the cache
+    Memory(Arc<Vec<u8>>),
+    /// Decompressed to an unlinked temporary file
+    Spilled(Arc<File>),
+}
+
+impl Content {
+    /// Read up to size bytes starting at offset
+    fn read(&self, offset: u64, size: u32) -> Result<Cow<'_, [u8]>> {
+        match self {
+            Content::Memory(data) => {
+                let start = (offset as usize).min(data.len());
+                let end = start.saturating_add(size as usize).min(data.len());
+                Ok(Cow::Borrowed(&data[start..end]))
+            }
+            Content::Spilled(file) => {
+                let mut buf = vec![0u8; size as usize];
+                let mut len = 0;
+                while len < buf.len() {
+                    match file.read_at(&mut buf[len..], offset + len as u64)? {
+                        0 => break,
+                        n => len += n,
+                    }
+                }
+                buf.truncate(len);
+                Ok(Cow::Owned(buf))
+            }
+        }
+    }
+}
+
+struct ZipFs {
+    nodes: Vec<Node>,
+    zip: Mutex<ZipArchive<File>>,
+    cache: Mutex<Cache>,
+    /// Files bigger than this are spilled to disk instead of being cached
+    inline: u64,
+    /// Spilled files, shared by every handle opened on them
+    spilled: Mutex<HashMap<usize, Weak<File>>>,
+    /// Spilled files of opened handles, kept until released
+    handles: Mutex<HashMap<u64, Content>>,
+    next_fh: AtomicU64,
+    mtime: SystemTime,
+    uid: u32,
+    gid: u32,
+}
+
+impl ZipFs {
+    fn open(archive: &Path, cache_size: u64) -> Result<Self> {
+        let file = File::open(archive)?;
+        let mtime = file.metadata()?.modified()?;
+        let mut zip = ZipArchive::new(file).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let mut nodes = vec![Node::dir(0)];
+        for i in 0..zip.len() {
+            let entry = zip
+                .by_index_raw(i)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            // entries escaping the archive root are ignored
+            let Some(path) = entry.enclosed_name() else {
+                continue;
+            };
+            let mut node = 0;
+            let components: Vec<&OsStr> = path
+                .components()
+                .filter_map(|x| match x {
+                    Component::Normal(x) => Some(x),
+                    _ => None,
+                })
+                .collect();
+            let Some((name, dirs)) = components.split_last() else {
+                continue;
+            };
+            for dir in dirs {
+                node = match nodes[node].children.get(*dir) {
+                    Some(x) => *x,
+                    None => {
+                        nodes.push(Node::dir(node));
+                        let child = nodes.len() - 1;
+                        nodes[node].children.insert(dir.to_os_string(), child);
+                        child
+                    }
+                };
+            }
+            let kind = if entry.is_dir() {
+                FileType::Directory
+            } else if entry.is_symlink() {
+                FileType::Symlink
+            } else {
+                FileType::RegularFile
+            };
+            let perm = entry.unix_mode().map(|x| (x & 0o7777) as u16).unwrap_or(
+                if kind == FileType::Directory {
+                    0o755
+                } else {
+                    0o644
+                },
+            );
+            match nodes[node].children.get(*name).copied() {
+                // directory created implicitly by a previous entry
+                Some(existing) if kind == FileType::Directory => {
+                    nodes[existing].perm = perm;
+                    nodes[existing].entry = Some(i);
+                }
+                Some(_) => continue,
+                None => {
+                    nodes.push(Node {
+                        parent: node,
+                        kind,
+                        perm,
+                        size: if kind == FileType::Directory {
+                            0
+                        } else {
+                            entry.size()
+                        },
+                        entry: Some(i),
+                        children: BTreeMap::new(),
+                    });
+                    let child = nodes.len() - 1;
+                    nodes[node].children.insert(name.to_os_string(), child);
+                }
+            }
+        }
+        Ok(Self {
+            nodes,
+            zip: Mutex::new(zip),
+            cache: Mutex::new(Cache::new(cache_size)),
+            // keep room for a few files so the cache doesn't thrash
+            inline: (cache_size / 4).min(MAX_INLINE),
+            spilled: Mutex::new(HashMap::new()),
+            handles: Mutex::new(HashMap::new()),
+            next_fh: AtomicU64::new(1),
+            mtime,
+            uid: geteuid().as_raw(),
+            gid: getegid().as_raw(),
+        })
+    }
+
+    fn node(&self, ino: INodeNo) -> std::result::Result<(usize, &Node), Errno> {
+        let idx = (ino.0 as usize).checked_sub(1).ok_or(Errno::ENOENT)?;
+        self.nodes.get(idx).map(|x| (idx, x)).ok_or(Errno::ENOENT)
+    }
+
+    fn attr(&self, idx: usize) -> FileAttr {
+        let node = &self.nodes[idx];
+        FileAttr {
+            ino: INodeNo(idx as u64 + 1),
+            size: node.size,
+            blocks: node.size.div_ceil(512),
+            atime: self.mtime,
+            mtime: self.mtime,
+            ctime: self.mtime,
+            crtime: self.mtime,
+            kind: node.kind,
+            perm: node.perm,
+            nlink: if node.kind == FileType::Directory {
+                2
+            } else {
+                1
+            },
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        }
+    }
+
+    /// Decompressed content of a node, from the cache when it is hot
+    fn content(&self, idx: usize) -> std::result::Result<Content, Errno> {
+        let size = self.nodes[idx].size;
+        if size > self.inline {
+            return self.spill(idx).map(Content::Spilled);
+        }
+        if let Some(data) = lock(&self.cache).get(idx) {
+            return Ok(Content::Memory(data));
+        }
+        let entry = self.nodes[idx].entry.ok_or(Errno::EIO)?;
+        let mut data = Vec::new();
+        data.try_reserve(size as usize).map_err(|_| Errno::EFBIG)?;
+        {
+            let mut zip = lock(&self.zip);
+            let file = zip.by_index(entry).map_err(|_| Errno::EIO)?;
+            file.take(size)
+                .read_to_end(&mut data)
+                .map_err(Errno::from)?;
+        }
+        let data = Arc::new(data);
+        lock(&self.cache).insert(idx, data.clone());
+        Ok(Content::Memory(data))
+    }
+
+    /// Decompress a node to a temporary file, or reuse the one of a handle still open
+    fn spill(&self, idx: usize) -> std::result::Result<Arc<File>, Errno> {
+        let mut spilled = lock(&self.spilled);
+        if let Some(file) = spilled.get(&idx).and_then(Weak::upgrade) {
+            return Ok(file);
+        }
+        let entry = self.nodes[idx].entry.ok_or(Errno::EIO)?;
+        let mut file = temp_file().map_err(Errno::from)?;
+        {
+            let mut zip = lock(&self.zip);
+            let src = zip.by_index(entry).map_err(|_| Errno::EIO)?;
+            std::io::copy(&mut src.take(self.nodes[idx].size), &mut file).map_err(Errno::from)?;
+        }
+        let file = Arc::new(file);
+        spilled.retain(|_, x| x.strong_count() > 0);
+        spilled.insert(idx, Arc::downgrade(&file));
+        Ok(file)
+    }
+}
+
+/// Anonymous file of the temporary directory, removed once closed
+fn temp_file() -> Result<File> {
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+    let dir = std::env::temp_dir();
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).mode(0o600);
+    options
+        .clone()
+        .custom_flags(libc::O_TMPFILE)
+        .open(&dir)
+        .or_else(|_| {
+            let path = dir.join(format!(
+                "{}{}-{}",
+                SPILL_PREFIX,
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::Relaxed)
+            ));
+            let file = options.create_new(true).open(&path)?;
+            std::fs::remove_file(&path)?;
+            Ok(file)
+        })
+}
+
+impl fuser::Filesystem for ZipFs {
+    fn lookup(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
+        match self
+            .node(parent)
+            .and_then(|(_, x)| x.children.get(name).ok_or(Errno::ENOENT))
+        {
+            Ok(idx) => reply.entry(&TTL, &self.attr(*idx), Generation(0)),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getattr(&self, _req: &Request, ino: INodeNo, _fh: Option<FileHandle>, reply: ReplyAttr) {
+        match self.node(ino) {
+            Ok((idx, _)) => reply.attr(&TTL, &self.attr(idx)),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readlink(&self, _req: &Request, ino: INodeNo, reply: ReplyData) {
+        match self.node(ino).and_then(|(idx, _)| {
+            let content = self.content(idx)?;
+            Ok(content.read(0, libc::PATH_MAX as u32)?.into_owned())
+        }) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn open(&self, _req: &Request, ino: INodeNo, _flags: OpenFlags, reply: ReplyOpen) {
+        match self.node(ino).and_then(|(idx, _)| self.content(idx)) {
+            Ok(content) => {
+                let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+                // cached files are fetched again on read so memory stay bounded by the cache
+                if let Content::Spilled(_) = content {
+                    lock(&self.handles).insert(fh, content);
+                }
+                reply.opened(FileHandle(fh), FopenFlags::FOPEN_KEEP_CACHE)
+            }
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn read(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        fh: FileHandle,
+        offset: u64,
+        size: u32,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        reply: ReplyData,
+    ) {
+        let data = match lock(&self.handles).get(&fh.0).cloned() {
+            Some(x) => Ok(x),
+            None => self.node(ino).and_then(|(idx, _)| self.content(idx)),
+        };
+        match data.and_then(|x| Ok(x.read(offset, size)?.into_owned())) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn release(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        fh: FileHandle,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        lock(&self.handles).remove(&fh.0);
+        reply.ok()
+    }
+
+    fn readdir(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        mut reply: ReplyDirectory,
+    ) {
+        let (idx, node) = match self.node(ino) {
+            Ok(x) => x,
+            Err(e) => return reply.error(e),
+        };
+        if node.kind != FileType::Directory {
+            return reply.error(Errno::ENOTDIR);
+        }
+        let entries = [(idx, OsStr::new(".")), (node.parent, OsStr::new(".."))]
+            .into_iter()
+            .chain(node.children.iter().map(|(name, x)| (*x, name.as_os_str())));
+        for (i, (child, name)) in entries.enumerate().skip(offset as usize) {
+            let ino = INodeNo(child as u64 + 1);
+            if reply.add(ino, (i + 1) as u64, self.nodes[child].kind, name) {
+                break;
+            }
+        }
+        reply.ok()
+    }
+}
+
+/// Lock ignoring poisoning, state is never left inconsistent by a panic
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use temp_testdir::TempDir;
+    use zip::{ZipWriter, write::SimpleFileOptions};
+
+    #[test]
+    fn index_archive() {
+        let dir = TempDir::default();
+        let path = dir.join("mod.zip");
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        let options = SimpleFileOptions::default().unix_permissions(0o600);
+        zip.start_file("data/textures/a.dds", options).unwrap();
+        zip.write_all(b"texture").unwrap();
+        zip.add_directory(
+            "data/",
+            SimpleFileOptions::default().unix_permissions(0o750),
+        )
+        .unwrap();
+        zip.add_symlink("link", "data/textures/a.dds", SimpleFileOptions::default())
+            .unwrap();
+        zip.start_file("../escape", options).unwrap();
+        zip.finish().unwrap();
+        assert!(ArchiveMount::is_archive(&path).unwrap());
+
+        let fs = ZipFs::open(&path, 64).unwrap();
+        let root = &fs.nodes[0];
+        assert_eq!(
+            root.children.keys().collect::<Vec<_>>(),
+            vec![OsStr::new("data"), OsStr::new("link")]
+        );
+        let data = root.children[OsStr::new("data")];
+        assert_eq!(fs.nodes[data].perm, 0o750);
+        let textures = fs.nodes[data].children[OsStr::new("textures")];
+        let file = fs.nodes[textures].children[OsStr::new("a.dds")];
+        assert_eq!(fs.nodes[file].perm, 0o600);
+        assert_eq!(fs.nodes[file].size, 7);
+        let content = fs.content(file).unwrap();
+        assert!(matches!(content, Content::Memory(_)));
+        assert_eq!(content.read(0, 64).unwrap().as_ref(), b"texture");
+        assert_eq!(content.read(3, 2).unwrap().as_ref(), b"tu");
+        assert!(lock(&fs.cache).get(file).is_some());
+        // too big for a quarter of a 64 bytes cache
+        let link = root.children[OsStr::new("link")];
+        assert_eq!(fs.nodes[link].kind, FileType::Symlink);
+        let content = fs.content(link).unwrap();
+        assert!(matches!(content, Content::Spilled(_)));
+        assert_eq!(
+            content.read(0, 64).unwrap().as_ref(),
+            b"data/textures/a.dds"
+        );
+        assert_eq!(content.read(14, 64).unwrap().as_ref(), b"a.dds");
+        assert!(lock(&fs.cache).get(link).is_none());
+    }
+
+    #[test]
+    fn cache_eviction() {
+        let mut cache = Cache::new(8);
+        let data = |x: usize| Arc::new(vec![0u8; x]);
+        cache.insert(1, data(3));
+        cache.insert(2, data(3));
+        // already cached, must not be counted twice
+        cache.insert(1, data(3));
+        assert_eq!(cache.used, 6);
+        // 1 become the most recently used, 2 is evicted first
+        assert!(cache.get(1).is_some());
+        cache.insert(3, data(3));
+        assert!(cache.get(2).is_none());
+        assert!(cache.get(1).is_some());
+        assert!(cache.get(3).is_some());
+        assert_eq!(cache.used, 6);
+        assert_eq!((cache.head, cache.tail), (Some(1), Some(3)));
+    }
+}
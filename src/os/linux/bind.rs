@@ -0,0 +1,310 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use std::{
+    ffi::CString,
+    io::{Error, ErrorKind, Result},
+    os::fd::{AsFd, BorrowedFd, OwnedFd},
+    path::{Path, PathBuf},
+};
+
+use nix::{
+    libc,
+    mount::{MntFlags, MsFlags, mount, umount2},
+};
+use tracing::{debug, error};
+
+use super::{
+    busy::unmount_with,
+    mount_api::{move_mount, open_tree, set_readonly},
+    mountinfo::MountInfo,
+};
+use crate::{AsCString, AsPath, Filesystem, PartitionID, Propagation, StateRecovery, UnmountMode};
+
+#[derive(Debug)]
+/// Bind mount handle, expose a directory at another place
+pub struct BindMount {
+    source: PathBuf,
+    target: CString,
+    recursive: bool,
+    readonly: bool,
+    propagation: Option<Propagation>,
+    unmount_mode: UnmountMode,
+    id: Option<PartitionID>,
+    drop: bool,
+}
+
+impl BindMount {
+    #[must_use = "initialised BindMount handle should be used"]
+    #[inline]
+    pub fn new<S, T>(source: S, target: T) -> Self
+    where
+        S: AsRef<Path>,
+        T: AsRef<Path>,
+    {
+        Self {
+            source: source.as_ref().to_path_buf(),
+            target: target.as_ref().as_cstring(),
+            recursive: false,
+            readonly: false,
+            propagation: None,
+            unmount_mode: UnmountMode::default(),
+            id: None,
+            drop: true,
+        }
+    }
+
+    #[inline]
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    #[inline]
+    pub fn recursive(&self) -> bool {
+        self.recursive
+    }
+
+    /// Also bind every mount found below source
+    #[inline]
+    pub fn set_recursive(&mut self, recursive: bool) -> Result<()> {
+        if self.id.is_some() {
+            return Err(Error::other(
+                "recursion cannot be change when the FileSystem is mounted",
+            ));
+        }
+        self.recursive = recursive;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn readonly(&self) -> bool {
+        self.readonly
+    }
+
+    #[inline]
+    pub fn set_readonly(&mut self, readonly: bool) -> Result<()> {
+        if self.id.is_some() {
+            return Err(Error::other(
+                "read-only flag cannot be change when the FileSystem is mounted",
+            ));
+        }
+        self.readonly = readonly;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn propagation(&self) -> Option<Propagation> {
+        self.propagation
+    }
+
+    /// Set propagation type, applied right away when already mounted
+    #[inline]
+    pub fn set_propagation(&mut self, propagation: Option<Propagation>) -> Result<()> {
+        if let Some(p) = propagation
+            && self.id.is_some()
+        {
+            p.apply(self.target.as_path())?;
+        }
+        self.propagation = propagation;
+        Ok(())
+    }
+
+    /// Behavior of unmount when processes still use the mount point
+    #[inline]
+    pub fn unmount_mode(&self) -> UnmountMode {
+        self.unmount_mode
+    }
+
+    /// Set the behavior of unmount, lazy by default
+    #[inline]
+    pub fn set_unmount_mode(&mut self, mode: UnmountMode) {
+        self.unmount_mode = mode;
+    }
+
+    /// Clone source into a mount tree which is not attached anywhere yet
+    ///
+    /// Require the new mount API, kernel 5.12 or later
+    pub fn detached(&self) -> Result<DetachedMount> {
+        let tree = open_tree(&self.source, self.recursive)?;
+        if self.readonly {
+            set_readonly(tree.as_fd(), true, self.recursive)?;
+        }
+        Ok(DetachedMount(tree))
+    }
+
+    /// Bind with mount(2) then remount read-only, for kernel without the new mount API
+    fn legacy_mount(&self) -> Result<()> {
+        let mut flags = MsFlags::MS_BIND;
+        if self.recursive {
+            flags |= MsFlags::MS_REC;
+        }
+        mount(
+            Some(self.source.as_path()),
+            self.target.as_c_str(),
+            None::<&str>,
+            flags,
+            None::<&str>,
+        )?;
+        if self.readonly
+            && let Err(err) = mount(
+                None::<&str>,
+                self.target.as_c_str(),
+                None::<&str>,
+                MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY,
+                None::<&str>,
+            )
+        {
+            umount2(self.target.as_c_str(), MntFlags::MNT_DETACH)?;
+            return Err(err.into());
+        }
+        Ok(())
+    }
+}
+
+impl Filesystem for BindMount {
+    fn mount(&mut self) -> Result<PathBuf> {
+        if matches!(self.id,Some(x) if x == PartitionID::try_from(self.target.as_path())?) {
+            debug!("Damascus: partition already mounted");
+            return Ok(self.target.as_path().to_path_buf());
+        }
+        match self.detached() {
+            Ok(tree) => tree.attach(self.target.as_path())?,
+            Err(err) if err.raw_os_error() == Some(libc::ENOSYS) => self.legacy_mount()?,
+            Err(err) => return Err(err),
+        }
+        self.id = Some(
+            PartitionID::try_from(self.target.as_path())
+                .map_err(|_| Error::other("unable to get PartitionID"))?,
+        );
+        if let Some(p) = self.propagation {
+            p.apply(self.target.as_path())?;
+        }
+        Ok(self.target.as_path().to_path_buf())
+    }
+
+    fn unmount(&mut self) -> Result<()> {
+        if matches!(self.id,Some(x) if x == PartitionID::try_from(self.target.as_path())?) {
+            unmount_with(self.target.as_path(), self.unmount_mode, |flags| {
+                Ok(umount2(self.target.as_c_str(), flags)?)
+            })?;
+            self.id = None;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn unmount_on_drop(&self) -> bool {
+        self.drop
+    }
+
+    #[inline]
+    fn set_unmount_on_drop(&mut self, drop: bool) {
+        self.drop = drop;
+    }
+
+    #[inline]
+    fn id(&self) -> Option<&PartitionID> {
+        self.id.as_ref()
+    }
+
+    #[inline]
+    fn target(&self) -> PathBuf {
+        self.target.as_path().to_path_buf()
+    }
+
+    #[inline]
+    fn set_target(&mut self, target: impl AsRef<Path>) -> Result<()> {
+        if self.id.is_some() {
+            return Err(Error::other(
+                "mount point cannot be change when the FileSystem is mounted",
+            ));
+        }
+        self.target = target.as_ref().as_cstring();
+        Ok(())
+    }
+
+    #[inline]
+    fn is_available() -> bool {
+        true
+    }
+}
+
+impl StateRecovery for BindMount {
+    fn recover<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mounts = MountInfo::all()?;
+        let not_found = || {
+            Error::new(
+                ErrorKind::NotFound,
+                "BindMount not found at mount point : ".to_string() + &path.to_string_lossy(),
+            )
+        };
+        let entry = mounts
+            .iter()
+            .rfind(|x| x.mount_point == path)
+            .ok_or_else(not_found)?;
+        // a bind mount expose a directory of a filesystem which is mounted elsewhere
+        let source = mounts
+            .iter()
+            .filter(|x| x.id != entry.id && x.dev == entry.dev && !x.mount_point.starts_with(path))
+            .find_map(|x| {
+                entry
+                    .root
+                    .strip_prefix(&x.root)
+                    .ok()
+                    .map(|r| x.mount_point.join(r))
+                    .filter(|x| x.exists())
+            })
+            .ok_or_else(not_found)?;
+        Ok(Self {
+            source,
+            target: path.as_cstring(),
+            recursive: mounts.iter().any(|x| x.parent == entry.id),
+            readonly: entry.options.iter().any(|x| x == "ro"),
+            propagation: Some(Propagation::from_optional(&entry.optional)),
+            id: Some(
+                PartitionID::try_from(path)
+                    .map_err(|_| Error::other("unable to get PartitionID"))?,
+            ),
+            unmount_mode: UnmountMode::default(),
+            drop: false,
+        })
+    }
+}
+
+impl Drop for BindMount {
+    #[inline]
+    fn drop(&mut self) {
+        if self.drop
+            && let Err(err) = self.unmount()
+        {
+            error!(
+                "Damascus: unable to unmount bind mount at {:?} because : {}",
+                self.target, err
+            )
+        }
+    }
+}
+
+#[derive(Debug)]
+/// Mount tree cloned from a directory which is not attached anywhere yet
+///
+/// Dropping it without attaching it release the clone
+pub struct DetachedMount(OwnedFd);
+
+impl DetachedMount {
+    /// Attach the mount tree on target
+    pub fn attach(self, target: impl AsRef<Path>) -> Result<()> {
+        move_mount(self.0.as_fd(), target.as_ref())
+    }
+}
+
+impl AsFd for DetachedMount {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
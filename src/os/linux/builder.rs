@@ -0,0 +1,566 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use std::{
+    fmt::Display,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+};
+
+use tracing::debug;
+
+#[cfg(feature = "fuse-overlayfs")]
+use crate::FuseOverlayFs;
+#[cfg(feature = "overlayfs")]
+use crate::OverlayFs;
+#[cfg(feature = "unionfs-fuse")]
+use crate::UnionFsFuse;
+use crate::{
+    Filesystem, LinuxFilesystem, PartitionID, StackableFilesystem, UnmountMode, UserNamespace,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// Stackable filesystem backend, ordered by preference
+pub enum Backend {
+    OverlayFs,
+    FuseOverlayFs,
+    UnionFsFuse,
+}
+
+impl Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Backend::OverlayFs => "overlayfs",
+                Backend::FuseOverlayFs => "fuse-overlayfs",
+                Backend::UnionFsFuse => "unionfs-fuse",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// Requirements the selected backend must satisfy
+pub struct Requirements {
+    /// Modification must be written to the upper layer
+    pub writable: bool,
+    /// Mounting must not require root privilege
+    pub unprivileged: bool,
+    /// Lookup must be case-insensitive
+    pub case_insensitive: bool,
+    /// Files must be executable from the mount point
+    pub executable: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Backend rejected during selection and the reason why
+pub struct Skipped {
+    pub backend: Backend,
+    pub reason: String,
+}
+
+impl Display for Skipped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.backend, self.reason)
+    }
+}
+
+#[derive(Debug)]
+/// Handle over any stackable filesystem backend
+pub enum Stack {
+    #[cfg(feature = "overlayfs")]
+    OverlayFs(OverlayFs),
+    #[cfg(feature = "fuse-overlayfs")]
+    FuseOverlayFs(FuseOverlayFs),
+    #[cfg(feature = "unionfs-fuse")]
+    UnionFsFuse(UnionFsFuse),
+}
+
+#[cfg(feature = "overlayfs")]
+impl From<OverlayFs> for Stack {
+    fn from(value: OverlayFs) -> Self {
+        Stack::OverlayFs(value)
+    }
+}
+
+#[cfg(feature = "fuse-overlayfs")]
+impl From<FuseOverlayFs> for Stack {
+    fn from(value: FuseOverlayFs) -> Self {
+        Stack::FuseOverlayFs(value)
+    }
+}
+
+#[cfg(feature = "unionfs-fuse")]
+impl From<UnionFsFuse> for Stack {
+    fn from(value: UnionFsFuse) -> Self {
+        Stack::UnionFsFuse(value)
+    }
+}
+
+macro_rules! dispatch {
+    ($self:expr, $fs:ident => $e:expr) => {
+        match $self {
+            #[cfg(feature = "overlayfs")]
+            Stack::OverlayFs($fs) => $e,
+            #[cfg(feature = "fuse-overlayfs")]
+            Stack::FuseOverlayFs($fs) => $e,
+            #[cfg(feature = "unionfs-fuse")]
+            Stack::UnionFsFuse($fs) => $e,
+        }
+    };
+}
+
+impl Stack {
+    /// Retrieve the backend used by this handle
+    pub fn backend(&self) -> Backend {
+        match self {
+            #[cfg(feature = "overlayfs")]
+            Stack::OverlayFs(_) => Backend::OverlayFs,
+            #[cfg(feature = "fuse-overlayfs")]
+            Stack::FuseOverlayFs(_) => Backend::FuseOverlayFs,
+            #[cfg(feature = "unionfs-fuse")]
+            Stack::UnionFsFuse(_) => Backend::UnionFsFuse,
+        }
+    }
+
+    /// Replace the lower layers of the mounted stack without unmounting it
+    #[inline]
+    pub fn hot_swap(&mut self, lower: impl Into<Vec<PathBuf>>) -> Result<()> {
+        dispatch!(self, fs => fs.hot_swap(lower))
+    }
+
+    /// Behavior of unmount when processes still use the mount point
+    #[inline]
+    pub fn unmount_mode(&self) -> UnmountMode {
+        dispatch!(self, fs => fs.unmount_mode())
+    }
+
+    /// Set the behavior of unmount, lazy by default
+    #[inline]
+    pub fn set_unmount_mode(&mut self, mode: UnmountMode) {
+        dispatch!(self, fs => fs.set_unmount_mode(mode))
+    }
+}
+
+impl Filesystem for Stack {
+    #[inline]
+    fn mount(&mut self) -> Result<PathBuf> {
+        dispatch!(self, fs => fs.mount())
+    }
+
+    #[inline]
+    fn unmount(&mut self) -> Result<()> {
+        dispatch!(self, fs => fs.unmount())
+    }
+
+    #[inline]
+    fn unmount_on_drop(&self) -> bool {
+        dispatch!(self, fs => fs.unmount_on_drop())
+    }
+
+    #[inline]
+    fn set_unmount_on_drop(&mut self, drop: bool) {
+        dispatch!(self, fs => fs.set_unmount_on_drop(drop))
+    }
+
+    #[inline]
+    fn id(&self) -> Option<&PartitionID> {
+        dispatch!(self, fs => fs.id())
+    }
+
+    #[inline]
+    fn target(&self) -> PathBuf {
+        dispatch!(self, fs => fs.target())
+    }
+
+    #[inline]
+    fn set_target(&mut self, target: impl AsRef<Path>) -> Result<()> {
+        dispatch!(self, fs => fs.set_target(target))
+    }
+
+    /// Get if any of the enabled backend is available
+    fn is_available() -> bool {
+        #[cfg(feature = "overlayfs")]
+        if OverlayFs::is_available() {
+            return true;
+        }
+        #[cfg(feature = "fuse-overlayfs")]
+        if FuseOverlayFs::is_available() {
+            return true;
+        }
+        #[cfg(feature = "unionfs-fuse")]
+        if UnionFsFuse::is_available() {
+            return true;
+        }
+        false
+    }
+}
+
+impl StackableFilesystem for Stack {
+    #[inline]
+    fn lower(&self) -> Vec<&Path> {
+        dispatch!(self, fs => fs.lower())
+    }
+
+    #[inline]
+    fn set_lower(&mut self, lower: impl Into<Vec<PathBuf>>) -> Result<()> {
+        dispatch!(self, fs => fs.set_lower(lower))
+    }
+
+    #[inline]
+    fn upper(&self) -> Option<&Path> {
+        dispatch!(self, fs => fs.upper())
+    }
+
+    #[inline]
+    fn set_upper(&mut self, upper: impl Into<PathBuf>) -> Result<()> {
+        dispatch!(self, fs => fs.set_upper(upper))
+    }
+}
+
+#[derive(Debug)]
+/// Backend selected by a StackBuilder along with every rejected backend
+pub struct Selected {
+    pub handle: Stack,
+    /// User namespace the handle must be mounted in, see UserNamespace::run, set when
+    /// overlayfs has been selected for a process lacking CAP_SYS_ADMIN
+    pub namespace: Option<UserNamespace>,
+    pub skipped: Vec<Skipped>,
+}
+
+#[derive(Debug, Clone)]
+/// Backend agnostic stackable filesystem builder
+///
+/// Probe every enabled backend in order of preference and keep the first one able to
+/// satisfy the requirements
+pub struct StackBuilder {
+    lower: Vec<PathBuf>,
+    upper: Option<PathBuf>,
+    work: Option<PathBuf>,
+    target: PathBuf,
+    requirements: Requirements,
+    drop: bool,
+}
+
+impl StackBuilder {
+    #[must_use = "StackBuilder should be built"]
+    pub fn new(target: impl AsRef<Path>) -> Self {
+        Self {
+            lower: vec![],
+            upper: None,
+            work: None,
+            target: target.as_ref().to_path_buf(),
+            requirements: Requirements::default(),
+            drop: true,
+        }
+    }
+
+    /// Set lower layers ordered from the top to the bottom
+    #[must_use = "StackBuilder should be built"]
+    pub fn lower<I, A>(mut self, lower: I) -> Self
+    where
+        I: IntoIterator<Item = A>,
+        A: AsRef<Path>,
+    {
+        self.lower = lower
+            .into_iter()
+            .map(|x| x.as_ref().to_path_buf())
+            .collect();
+        self
+    }
+
+    #[must_use = "StackBuilder should be built"]
+    pub fn upper(mut self, upper: impl AsRef<Path>) -> Self {
+        self.upper = Some(upper.as_ref().to_path_buf());
+        self
+    }
+
+    #[must_use = "StackBuilder should be built"]
+    pub fn work(mut self, work: impl AsRef<Path>) -> Self {
+        self.work = Some(work.as_ref().to_path_buf());
+        self
+    }
+
+    #[must_use = "StackBuilder should be built"]
+    pub fn requirements(mut self, requirements: Requirements) -> Self {
+        self.requirements = requirements;
+        self
+    }
+
+    #[must_use = "StackBuilder should be built"]
+    pub fn writable(mut self, writable: bool) -> Self {
+        self.requirements.writable = writable;
+        self
+    }
+
+    #[must_use = "StackBuilder should be built"]
+    pub fn unprivileged(mut self, unprivileged: bool) -> Self {
+        self.requirements.unprivileged = unprivileged;
+        self
+    }
+
+    #[must_use = "StackBuilder should be built"]
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.requirements.case_insensitive = case_insensitive;
+        self
+    }
+
+    #[must_use = "StackBuilder should be built"]
+    pub fn executable(mut self, executable: bool) -> Self {
+        self.requirements.executable = executable;
+        self
+    }
+
+    #[must_use = "StackBuilder should be built"]
+    pub fn unmount_on_drop(mut self, drop: bool) -> Self {
+        self.drop = drop;
+        self
+    }
+
+    /// Select the best available backend and initialise its handle
+    pub fn build(self) -> Result<Selected> {
+        let mut skipped = vec![];
+        for backend in [
+            Backend::OverlayFs,
+            Backend::FuseOverlayFs,
+            Backend::UnionFsFuse,
+        ] {
+            match self.try_backend(backend) {
+                Ok((mut handle, namespace)) => {
+                    handle.set_unmount_on_drop(self.drop);
+                    debug!("Damascus: selected {} backend", backend);
+                    return Ok(Selected {
+                        handle,
+                        namespace,
+                        skipped,
+                    });
+                }
+                Err(err) => {
+                    debug!("Damascus: skipped {} backend because : {}", backend, err);
+                    skipped.push(Skipped {
+                        backend,
+                        reason: err.to_string(),
+                    });
+                }
+            }
+        }
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "no backend can satisfy the requirements, ".to_string()
+                + &skipped
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+        ))
+    }
+
+    /// Check shared requirements then initialise the backend handle along with the user
+    /// namespace it must be mounted in, if any
+    fn try_backend(&self, backend: Backend) -> Result<(Stack, Option<UserNamespace>)> {
+        if self.requirements.case_insensitive {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "case-insensitive lookup is not supported",
+            ));
+        }
+        if self.requirements.writable && self.upper.is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "writable stack need an upper directory",
+            ));
+        }
+        if !self.requirements.writable && self.upper.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "upper directory is only used by writable stack",
+            ));
+        }
+        match backend {
+            Backend::OverlayFs => {
+                #[cfg(feature = "overlayfs")]
+                {
+                    if !OverlayFs::is_available() {
+                        return Err(Error::new(
+                            ErrorKind::NotFound,
+                            "overlayfs is not available",
+                        ));
+                    }
+                    let caps = crate::OverlayCapabilities::get();
+                    if self.lower.len() > caps.max_lower {
+                        return Err(Error::new(
+                            ErrorKind::Unsupported,
+                            format!("overlayfs support at most {} lower layers", caps.max_lower),
+                        ));
+                    }
+                    // without privilege overlay can still be mounted inside a user namespace
+                    let namespace = if self.requirements.unprivileged
+                        || !super::capability::sys_admin()
+                    {
+                        if !caps.unprivileged || !UserNamespace::is_available() {
+                            return Err(Error::new(
+                                ErrorKind::PermissionDenied,
+                                "overlayfs need CAP_SYS_ADMIN to be mounted outside of a user namespace",
+                            ));
+                        }
+                        if self.lower.iter().any(|x| x.is_file()) {
+                            return Err(Error::new(
+                                ErrorKind::Unsupported,
+                                "image layers cannot be mounted inside a user namespace",
+                            ));
+                        }
+                        Some(UserNamespace::new())
+                    } else {
+                        None
+                    };
+                    let fs = match (&self.upper, &self.work) {
+                        (Some(upper), Some(work)) if self.requirements.writable => {
+                            OverlayFs::writable(self.lower.iter(), upper, work, &self.target)?
+                        }
+                        (_, None) if self.requirements.writable => {
+                            return Err(Error::new(
+                                ErrorKind::InvalidInput,
+                                "overlayfs need a work directory to be writable",
+                            ));
+                        }
+                        _ => OverlayFs::readonly(self.lower.iter(), &self.target)?,
+                    };
+                    if self.requirements.executable {
+                        check_executable(fs.options())?;
+                    }
+                    let handle = Stack::OverlayFs(fs);
+                    Ok((handle, namespace))
+                }
+                #[cfg(not(feature = "overlayfs"))]
+                Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "support not enabled at compile time",
+                ))
+            }
+            Backend::FuseOverlayFs => {
+                #[cfg(feature = "fuse-overlayfs")]
+                {
+                    if !FuseOverlayFs::is_available() {
+                        return Err(Error::new(
+                            ErrorKind::NotFound,
+                            "fuse-overlayfs is not available",
+                        ));
+                    }
+                    check_fuse()?;
+                    // an unknown version is given a chance, the mount fail if it isn't supported
+                    if self.lower.contains(&self.target)
+                        && FuseOverlayFs::features().is_ok_and(|x| !x.mount_on_lower)
+                    {
+                        return Err(Error::new(
+                            ErrorKind::Unsupported,
+                            format!(
+                                "fuse-overlayfs {} cannot be mounted on top of a lower directory",
+                                FuseOverlayFs::version()?
+                            ),
+                        ));
+                    }
+                    let fs = match (&self.upper, &self.work) {
+                        (Some(upper), Some(work)) if self.requirements.writable => {
+                            FuseOverlayFs::writable(self.lower.iter(), upper, work, &self.target)?
+                        }
+                        (_, None) if self.requirements.writable => {
+                            return Err(Error::new(
+                                ErrorKind::InvalidInput,
+                                "fuse-overlayfs need a work directory to be writable",
+                            ));
+                        }
+                        _ => FuseOverlayFs::readonly(self.lower.iter(), &self.target)?,
+                    };
+                    if self.requirements.executable {
+                        check_executable(fs.options())?;
+                    }
+                    let handle = Stack::FuseOverlayFs(fs);
+                    Ok((handle, None))
+                }
+                #[cfg(not(feature = "fuse-overlayfs"))]
+                Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "support not enabled at compile time",
+                ))
+            }
+            Backend::UnionFsFuse => {
+                #[cfg(feature = "unionfs-fuse")]
+                {
+                    if !UnionFsFuse::is_available() {
+                        return Err(Error::new(
+                            ErrorKind::NotFound,
+                            "unionfs-fuse is not available",
+                        ));
+                    }
+                    check_fuse()?;
+                    if self.lower.contains(&self.target) {
+                        return Err(Error::new(
+                            ErrorKind::Unsupported,
+                            "unionfs-fuse cannot be mounted on top of a lower directory",
+                        ));
+                    }
+                    if self.lower.iter().any(|x| x.is_file()) {
+                        return Err(Error::new(
+                            ErrorKind::Unsupported,
+                            "unionfs-fuse cannot use image layers",
+                        ));
+                    }
+                    let fs = match &self.upper {
+                        Some(upper) if self.requirements.writable => {
+                            UnionFsFuse::writable(self.lower.iter(), upper, &self.target)?
+                        }
+                        _ => UnionFsFuse::readonly(self.lower.iter(), &self.target)?,
+                    };
+                    if self.requirements.executable {
+                        check_executable(fs.options())?;
+                    }
+                    let handle = Stack::UnionFsFuse(fs);
+                    Ok((handle, None))
+                }
+                #[cfg(not(feature = "unionfs-fuse"))]
+                Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "support not enabled at compile time",
+                ))
+            }
+        }
+    }
+}
+
+/// Check that no option prevent execution from the mount point, backends are mounted
+/// without MS_NOEXEC so options are the only source of noexec
+#[cfg(any(
+    feature = "overlayfs",
+    feature = "fuse-overlayfs",
+    feature = "unionfs-fuse"
+))]
+fn check_executable<O: crate::FsOption>(options: &[crate::MountOption<O>]) -> Result<()> {
+    if options
+        .iter()
+        .any(|x| x.to_string().split(',').any(|x| x == "noexec"))
+    {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "noexec option prevent execution from the mount point",
+        ));
+    }
+    Ok(())
+}
+
+/// Check that FUSE filesystem can be mounted
+#[cfg(any(feature = "fuse-overlayfs", feature = "unionfs-fuse"))]
+fn check_fuse() -> Result<()> {
+    if !Path::new("/dev/fuse").exists() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            "/dev/fuse is not available",
+        ));
+    }
+    Ok(())
+}
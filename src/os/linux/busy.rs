@@ -0,0 +1,295 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use std::{
+    fmt::Display,
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use nix::{
+    mount::{MntFlags, umount2},
+    sys::signal::{Signal, kill},
+    unistd::Pid,
+};
+use tracing::{debug, error};
+
+/// How long killed holders are given to release the mount point
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// Behavior of unmount when processes still use the mount point
+pub enum UnmountMode {
+    /// Fail with the list of holders while the mount point is busy
+    Strict,
+    /// Detach right away, the filesystem is released once every holder is gone
+    #[default]
+    Lazy,
+    /// Abort pending FUSE requests then detach, holders get errors instead of stale data,
+    /// require privilege
+    Force,
+    /// Kill every process holding the mount point then unmount as with Strict
+    KillHolders,
+}
+
+impl UnmountMode {
+    /// Flags passed to umount2
+    #[inline]
+    pub(crate) fn flags(&self) -> MntFlags {
+        match self {
+            UnmountMode::Strict | UnmountMode::KillHolders => MntFlags::empty(),
+            UnmountMode::Lazy => MntFlags::MNT_DETACH,
+            UnmountMode::Force => MntFlags::MNT_FORCE | MntFlags::MNT_DETACH,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// Way a process reference a file
+pub enum HoldKind {
+    /// Open file descriptor
+    Fd,
+    /// Working directory
+    Cwd,
+    /// Root directory
+    Root,
+    /// Memory mapped file, such as a shared library
+    Map,
+}
+
+impl Display for HoldKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                HoldKind::Fd => "fd",
+                HoldKind::Cwd => "cwd",
+                HoldKind::Root => "root",
+                HoldKind::Map => "map",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// Reference held by a process on a file below a mount point
+pub struct Holder {
+    pub pid: u32,
+    pub kind: HoldKind,
+    pub path: PathBuf,
+}
+
+impl Display for Holder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {:?}", self.pid, self.kind, self.path)
+    }
+}
+
+/// List every reference held by a process on a file below target
+///
+/// Processes which exited or whose /proc entries cannot be read, such as the ones owned by
+/// another user when unprivileged, are skipped
+pub fn holders(target: impl AsRef<Path>) -> Result<Vec<Holder>> {
+    let target = fs::canonicalize(target.as_ref())?;
+    let mut res = vec![];
+    for entry in fs::read_dir("/proc")? {
+        let entry = entry?;
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|x| x.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        let proc = entry.path();
+        let mut hold = |kind, path: PathBuf| {
+            if path.starts_with(&target) {
+                res.push(Holder { pid, kind, path });
+            }
+        };
+        for (kind, link) in [(HoldKind::Cwd, "cwd"), (HoldKind::Root, "root")] {
+            if let Ok(path) = fs::read_link(proc.join(link)) {
+                hold(kind, path);
+            }
+        }
+        if let Ok(fds) = fs::read_dir(proc.join("fd")) {
+            for fd in fds.flatten() {
+                if let Ok(path) = fs::read_link(fd.path()) {
+                    hold(HoldKind::Fd, strip_deleted(path));
+                }
+            }
+        }
+        if let Ok(maps) = fs::read_to_string(proc.join("maps")) {
+            let mut mapped: Vec<PathBuf> = maps.lines().filter_map(map_path).collect();
+            mapped.dedup();
+            for path in mapped {
+                hold(HoldKind::Map, path);
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Unmount target according to mode, umount is called with the flags to use
+pub(crate) fn unmount_with(
+    target: &Path,
+    mode: UnmountMode,
+    umount: impl FnOnce(MntFlags) -> Result<()>,
+) -> Result<()> {
+    if mode == UnmountMode::KillHolders {
+        kill_holders(target)?;
+    }
+    umount(mode.flags()).map_err(|err| match mode {
+        UnmountMode::Strict | UnmountMode::KillHolders => match holders(target) {
+            Ok(holders) if !holders.is_empty() => Error::new(
+                ErrorKind::ResourceBusy,
+                format!(
+                    "{:?} is busy :\n{}",
+                    target,
+                    holders
+                        .iter()
+                        .map(|x| x.to_string())
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                ),
+            ),
+            _ => err,
+        },
+        _ => err,
+    })
+}
+
+/// Unmount a FUSE filesystem through fusermount, forced unmount go through umount2 since
+/// fusermount cannot abort the connection
+pub(crate) fn fusermount(target: &Path, flags: MntFlags) -> Result<()> {
+    if flags.contains(MntFlags::MNT_FORCE) {
+        return Ok(umount2(target, flags)?);
+    }
+    let mut cmd = Command::new("fusermount");
+    if flags.contains(MntFlags::MNT_DETACH) {
+        cmd.arg("-z");
+    }
+    let output = cmd
+        .arg("-u")
+        .arg(target)
+        .stderr(Stdio::piped())
+        .spawn()?
+        .wait_with_output()?;
+    if !output.status.success() {
+        error!(
+            "Damascus: unable to unmount {:?}\n{}",
+            target,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "Failed to unmount vfs",
+        ));
+    }
+    Ok(())
+}
+
+/// Send SIGKILL to every process holding target, except the current one, then wait for
+/// them to release it
+fn kill_holders(target: &Path) -> Result<()> {
+    let own = std::process::id();
+    let mut pids: Vec<u32> = holders(target)?
+        .into_iter()
+        .map(|x| x.pid)
+        .filter(|x| *x != own)
+        .collect();
+    pids.sort_unstable();
+    pids.dedup();
+    for pid in &pids {
+        debug!("Damascus: killing {} holding {:?}", pid, target);
+        match kill(Pid::from_raw(*pid as i32), Signal::SIGKILL) {
+            Ok(()) | Err(nix::errno::Errno::ESRCH) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    let start = Instant::now();
+    while holders(target)?.iter().any(|x| pids.contains(&x.pid)) {
+        if start.elapsed() > KILL_TIMEOUT {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("holders of {:?} didn't exit after being killed", target),
+            ));
+        }
+        sleep(Duration::from_millis(20));
+    }
+    Ok(())
+}
+
+/// Path of a /proc/PID/maps line, none for anonymous mappings
+fn map_path(line: &str) -> Option<PathBuf> {
+    // address perms offset dev inode path
+    let path = line.splitn(6, ' ').nth(5)?.trim_start();
+    path.starts_with('/')
+        .then(|| strip_deleted(PathBuf::from(path)))
+}
+
+/// Remove the marker appended by the kernel to files deleted while still referenced
+fn strip_deleted(path: PathBuf) -> PathBuf {
+    match path.to_str().and_then(|x| x.strip_suffix(" (deleted)")) {
+        Some(x) => PathBuf::from(x),
+        None => path,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::fs::File;
+    use temp_testdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn parse_maps_line() {
+        assert_eq!(
+            map_path(
+                "7f1c2a000000-7f1c2a022000 r--p 00000000 08:01 1048602                    /usr/lib/libc.so.6"
+            ),
+            Some(PathBuf::from("/usr/lib/libc.so.6"))
+        );
+        assert_eq!(
+            map_path("7f1c2a000000-7f1c2a022000 rw-p 00000000 00:00 0 "),
+            None
+        );
+        assert_eq!(
+            map_path(
+                "7ffd4e1c0000-7ffd4e1e1000 rw-p 00000000 00:00 0                          [stack]"
+            ),
+            None
+        );
+        assert_eq!(
+            map_path("7f1c2a000000-7f1c2a022000 r--p 00000000 08:01 12 /tmp/lib a.so (deleted)"),
+            Some(PathBuf::from("/tmp/lib a.so"))
+        );
+    }
+
+    #[test]
+    fn list_own_holders() {
+        let dir = TempDir::default();
+        let file = dir.join("held");
+        let _held = File::create(&file).unwrap();
+        let holders = holders(&dir).unwrap();
+        assert!(holders.contains(&Holder {
+            pid: std::process::id(),
+            kind: HoldKind::Fd,
+            path: fs::canonicalize(&file).unwrap(),
+        }));
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use std::{
+    fs::create_dir_all,
+    io::Result,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use nix::{
+    mount::{MntFlags, MsFlags, mount, umount2},
+    unistd::geteuid,
+};
+use tracing::debug;
+
+use crate::Version;
+
+/// Maximum number of lower layers accepted by overlayfs (OVL_MAX_STACK)
+const OVL_MAX_STACK: usize = 500;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Report of the overlayfs features supported by the running kernel
+pub struct OverlayCapabilities {
+    pub kernel: Version,
+    /// overlay is listed in /proc/filesystems
+    pub available: bool,
+    /// overlay can be mounted by an unprivileged user inside a user namespace
+    pub unprivileged: bool,
+    /// "userxattr" mount option
+    pub userxattr: bool,
+    /// "metacopy" mount option
+    pub metacopy: bool,
+    /// "redirect_dir" mount option
+    pub redirect_dir: bool,
+    /// data-only lower layers, `lowerdir=a::b`
+    pub data_only_lower: bool,
+    /// append lower layers one by one with `lowerdir+`
+    pub lowerdir_append: bool,
+    /// "volatile" mount option
+    pub volatile: bool,
+    /// idmapped mounts can be used as layers
+    pub idmapped_lower: bool,
+    /// "verity" mount option, fs-verity digests of metacopy files
+    pub verity: bool,
+    /// maximum number of lower layers
+    pub max_lower: usize,
+    /// Feature have been confirmed by trial mounts instead of guessed from the kernel version
+    pub verified: bool,
+}
+
+impl OverlayCapabilities {
+    /// Retrieve capabilities of the running kernel, probed once and cached
+    ///
+    /// Only rely on system information, see probe to confirm them with trial mounts
+    pub fn get() -> &'static Self {
+        static CAPS: OnceLock<OverlayCapabilities> = OnceLock::new();
+        CAPS.get_or_init(|| Self::probe(false))
+    }
+
+    /// Probe capabilities of the running kernel
+    ///
+    /// When trial is set and the process is privileged, every feature is confirmed by
+    /// mounting an overlay on a private tmpfs
+    pub fn probe(trial: bool) -> Self {
+        let kernel = Version::kernel().unwrap_or_default();
+        let available = std::fs::read_to_string("/proc/filesystems")
+            .map(|x| x.contains("overlay"))
+            .unwrap_or(false);
+        let at_least = |major, minor| available && kernel >= Version::new(major, minor, 0);
+        let param = |name: &str| {
+            Path::new("/sys/module/overlay/parameters")
+                .join(name)
+                .exists()
+        };
+        let mut caps = Self {
+            kernel,
+            available,
+            unprivileged: at_least(5, 11) && userns_allowed(),
+            userxattr: at_least(5, 11),
+            // module parameters are only present when overlay is loaded, fallback on version
+            metacopy: param("metacopy") || at_least(4, 19),
+            redirect_dir: param("redirect_dir") || at_least(4, 10),
+            data_only_lower: at_least(6, 5),
+            lowerdir_append: at_least(6, 8),
+            volatile: at_least(5, 10),
+            idmapped_lower: at_least(5, 19),
+            verity: at_least(6, 6),
+            max_lower: OVL_MAX_STACK,
+            verified: false,
+        };
+        caps.metacopy &= available;
+        caps.redirect_dir &= available;
+        if trial
+            && available
+            && geteuid().is_root()
+            && let Err(err) = caps.trial()
+        {
+            debug!("Damascus: overlay trial mounts failed because : {}", err);
+        }
+        caps
+    }
+
+    /// Confirm features by mounting overlays on a private tmpfs
+    fn trial(&mut self) -> Result<()> {
+        let base = std::env::temp_dir().join(format!("damascus-probe-{}", std::process::id()));
+        create_dir_all(&base)?;
+        if let Err(err) = mount(
+            Some(c"tmpfs"),
+            &base,
+            Some(c"tmpfs"),
+            MsFlags::empty(),
+            None::<&str>,
+        ) {
+            let _ = std::fs::remove_dir(&base);
+            return Err(err.into());
+        }
+        let res = (|| -> Result<()> {
+            // keep trial mounts from propagating to the peers of the parent mount
+            mount(
+                None::<&str>,
+                &base,
+                None::<&str>,
+                MsFlags::MS_PRIVATE,
+                None::<&str>,
+            )?;
+            for d in ["l1", "l2", "u", "w", "m"] {
+                create_dir_all(base.join(d))?;
+            }
+            let p = |x: &str| base.join(x).to_string_lossy().to_string();
+            let lower = format!("lowerdir={}:{}", p("l1"), p("l2"));
+            let writable = format!("{},upperdir={},workdir={}", lower, p("u"), p("w"));
+            let target = base.join("m");
+            let try_mount = |data: &str| trial_mount(&target, data);
+            self.metacopy = try_mount(&(lower.clone() + ",metacopy=on"));
+            self.redirect_dir = try_mount(&(lower.clone() + ",redirect_dir=on"));
+            self.userxattr = try_mount(&(lower.clone() + ",userxattr"));
+            self.volatile = try_mount(&(writable + ",volatile"));
+            self.verity = try_mount(&(lower.clone() + ",metacopy=on,verity=on"));
+            self.data_only_lower =
+                try_mount(&format!("lowerdir={}::{},metacopy=on", p("l1"), p("l2")));
+            self.lowerdir_append =
+                try_mount(&format!("lowerdir+={},lowerdir+={}", p("l1"), p("l2")));
+            self.verified = true;
+            Ok(())
+        })();
+        let cleanup = umount2(&base, MntFlags::MNT_DETACH)
+            .map_err(Into::into)
+            .and_then(|_| std::fs::remove_dir(&base));
+        res.and(cleanup)
+    }
+}
+
+/// Mount then unmount an overlay, reporting if the mount succeeded
+fn trial_mount(target: &PathBuf, data: &str) -> bool {
+    let res = mount(
+        Some(c"overlay"),
+        target,
+        Some(c"overlay"),
+        MsFlags::empty(),
+        Some(data),
+    );
+    if res.is_ok() {
+        let _ = umount2(target, MntFlags::MNT_DETACH);
+    }
+    res.is_ok()
+}
+
+/// Check that the current process hold CAP_SYS_ADMIN in its user namespace
+#[cfg(feature = "overlayfs")]
+pub(crate) fn sys_admin() -> bool {
+    const CAP_SYS_ADMIN: u32 = 21;
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|x| x.strip_prefix("CapEff:"))
+                .and_then(|x| u64::from_str_radix(x.trim(), 16).ok())
+        })
+        .is_some_and(|x| x & (1 << CAP_SYS_ADMIN) != 0)
+}
+
+/// Check that unprivileged user namespaces are not disabled by sysctl
+pub(crate) fn userns_allowed() -> bool {
+    let read = |x: &str| std::fs::read_to_string(x).map(|x| x.trim().to_string());
+    if read("/proc/sys/user/max_user_namespaces").is_ok_and(|x| x == "0") {
+        return false;
+    }
+    // debian and ubuntu specific knobs
+    if read("/proc/sys/kernel/unprivileged_userns_clone").is_ok_and(|x| x == "0") {
+        return false;
+    }
+    if read("/proc/sys/kernel/apparmor_restrict_unprivileged_userns").is_ok_and(|x| x == "1") {
+        return false;
+    }
+    true
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "overlayfs")]
+    #[test]
+    fn capabilities() {
+        if geteuid().is_root() {
+            assert!(sys_admin());
+        }
+    }
+
+    #[test]
+    fn probe() {
+        let caps = OverlayCapabilities::probe(true);
+        if caps.available && caps.verified {
+            assert_eq!(caps.lowerdir_append, caps.kernel >= Version::new(6, 8, 0));
+        }
+    }
+}
@@ -0,0 +1,440 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    fs::{self, File, Metadata},
+    io::{Error, ErrorKind, Read, Result},
+    os::{fd::AsRawFd, unix::fs::MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use nix::libc::{self, c_ulong};
+use tracing::debug;
+
+use super::tmpfs::read_xattrs;
+use crate::PartitionID;
+
+const FIDEDUPERANGE: c_ulong = 0xC0189436;
+const FILE_DEDUPE_RANGE_SAME: i32 = 0;
+const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+/// Prefix of the temporary links renamed over duplicates
+const DEDUP_STAGING: &str = ".damascus-dedup-";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// How duplicates share their content
+pub enum DedupMethod {
+    /// Share extents through FIDEDUPERANGE, each file keep its own inode and metadata
+    Reflink,
+    /// Replace duplicates by a hardlink, only done when mode, owner, mtime and extended
+    /// attributes are identical
+    Hardlink,
+}
+
+#[derive(Debug, Clone)]
+/// Deduplication pass over a set of lower layers
+pub struct Dedup {
+    layers: Vec<PathBuf>,
+    method: DedupMethod,
+    dry_run: bool,
+    min_size: u64,
+}
+
+impl Dedup {
+    #[must_use = "initialised Dedup should be used"]
+    #[inline]
+    pub fn new<I, A>(layers: I) -> Self
+    where
+        I: Iterator<Item = A>,
+        A: AsRef<Path>,
+    {
+        Self {
+            layers: layers.map(|x| x.as_ref().to_path_buf()).collect(),
+            method: DedupMethod::Reflink,
+            dry_run: false,
+            min_size: 1,
+        }
+    }
+
+    #[inline]
+    pub fn layers(&self) -> &[PathBuf] {
+        &self.layers
+    }
+
+    #[inline]
+    pub fn method(&self) -> DedupMethod {
+        self.method
+    }
+
+    #[inline]
+    pub fn set_method(&mut self, method: DedupMethod) {
+        self.method = method;
+    }
+
+    /// Only report duplicates without touching the layers
+    #[inline]
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    #[inline]
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Files smaller than this are ignored
+    #[inline]
+    pub fn min_size(&self) -> u64 {
+        self.min_size
+    }
+
+    #[inline]
+    pub fn set_min_size(&mut self, min_size: u64) {
+        self.min_size = min_size.max(1);
+    }
+
+    /// Find identical files and make them share their content unless dry run is set
+    pub fn run(&self) -> Result<DedupReport> {
+        let mut candidates: HashMap<Key, Vec<(PathBuf, Metadata)>> = HashMap::new();
+        for layer in self.layers.iter() {
+            self.collect(layer, &mut candidates)?;
+        }
+        let mut report = DedupReport {
+            dry_run: self.dry_run,
+            ..Default::default()
+        };
+        for files in candidates.into_values().filter(|x| x.len() > 1) {
+            let mut by_hash: HashMap<[u8; 32], Vec<(PathBuf, Metadata)>> = HashMap::new();
+            for (path, meta) in files {
+                by_hash.entry(hash(&path)?).or_default().push((path, meta));
+            }
+            for mut files in by_hash.into_values().filter(|x| x.len() > 1) {
+                files.sort_by(|a, b| a.0.cmp(&b.0));
+                if let Some(group) = self.dedup_group(files)? {
+                    report.files += group.duplicates.len();
+                    report.saved += group.size * group.duplicates.len() as u64;
+                    report.groups.push(group);
+                }
+            }
+        }
+        report.groups.sort_by(|a, b| a.source.cmp(&b.source));
+        debug!("Damascus: {}", report);
+        Ok(report)
+    }
+
+    fn collect(
+        &self,
+        dir: &Path,
+        candidates: &mut HashMap<Key, Vec<(PathBuf, Metadata)>>,
+    ) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let meta = fs::symlink_metadata(&path)?;
+            if meta.is_dir() {
+                self.collect(&path, candidates)?;
+            } else if meta.is_file() && meta.len() >= self.min_size {
+                let key = Key {
+                    partition: PartitionID::try_from(path.as_path())?,
+                    size: meta.len(),
+                    attrs: match self.method {
+                        DedupMethod::Reflink => None,
+                        DedupMethod::Hardlink => Some((
+                            meta.mode(),
+                            meta.uid(),
+                            meta.gid(),
+                            meta.mtime(),
+                            meta.mtime_nsec(),
+                            sorted_xattrs(&path)?,
+                        )),
+                    },
+                };
+                candidates.entry(key).or_default().push((path, meta));
+            }
+        }
+        Ok(())
+    }
+
+    /// Share the content of the first file with the others, already shared files are skipped
+    fn dedup_group(&self, files: Vec<(PathBuf, Metadata)>) -> Result<Option<DuplicateGroup>> {
+        let mut files = files.into_iter();
+        let Some((source, smeta)) = files.next() else {
+            return Ok(None);
+        };
+        let mut duplicates = vec![];
+        let mut seen = HashSet::from([smeta.ino()]);
+        for (path, meta) in files {
+            // a reflinked inode reached through another hardlink is already shared
+            let first = seen.insert(meta.ino());
+            if meta.ino() == smeta.ino() || (self.method == DedupMethod::Reflink && !first) {
+                continue;
+            }
+            // hash collision are unlikely but contents must never change, the kernel compare
+            // them itself while sharing extents
+            let shared = match (self.dry_run, self.method) {
+                (false, DedupMethod::Reflink) => reflink(&source, &path)?,
+                (false, DedupMethod::Hardlink) => {
+                    let same = same_content(&source, &path)?;
+                    if same {
+                        hardlink(&source, &path)?;
+                    }
+                    same
+                }
+                (true, _) => same_content(&source, &path)?,
+            };
+            if shared {
+                duplicates.push(path);
+            }
+        }
+        if duplicates.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(DuplicateGroup {
+            source,
+            duplicates,
+            size: smeta.len(),
+        }))
+    }
+}
+
+/// Files can only be shared when this match
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct Key {
+    partition: PartitionID,
+    size: u64,
+    /// mode, owner, mtime and xattrs for hardlinks
+    #[allow(clippy::type_complexity)]
+    attrs: Option<(u32, u32, u32, i64, i64, Vec<(Vec<u8>, Vec<u8>)>)>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// Outcome of a deduplication pass
+pub struct DedupReport {
+    /// Files sharing the same content
+    pub groups: Vec<DuplicateGroup>,
+    /// Number of duplicates, shared with their source unless dry run
+    pub files: usize,
+    /// Bytes saved, or that would be saved on dry run
+    pub saved: u64,
+    pub dry_run: bool,
+}
+
+impl Display for DedupReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} duplicate files in {} groups, {} bytes saved",
+            if self.dry_run {
+                "found"
+            } else {
+                "deduplicated"
+            },
+            self.files,
+            self.groups.len(),
+            self.saved
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// Identical files, duplicates share the content of source
+pub struct DuplicateGroup {
+    pub source: PathBuf,
+    pub duplicates: Vec<PathBuf>,
+    /// Size of each file
+    pub size: u64,
+}
+
+fn sorted_xattrs(path: &Path) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut xattrs: Vec<_> = read_xattrs(path)?
+        .into_iter()
+        .map(|(n, v)| (n.into_bytes(), v))
+        .collect();
+    xattrs.sort();
+    Ok(xattrs)
+}
+
+fn hash(path: &Path) -> Result<[u8; 32]> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(File::open(path)?)?;
+    Ok(*hasher.finalize().as_bytes())
+}
+
+fn same_content(a: &Path, b: &Path) -> Result<bool> {
+    let (mut a, mut b) = (File::open(a)?, File::open(b)?);
+    let (mut buf_a, mut buf_b) = (vec![0u8; 64 * 1024], vec![0u8; 64 * 1024]);
+    loop {
+        let len = a.read(&mut buf_a)?;
+        if len == 0 {
+            return Ok(b.read(&mut buf_b[..1])? == 0);
+        }
+        b.read_exact(&mut buf_b[..len])?;
+        if buf_a[..len] != buf_b[..len] {
+            return Ok(false);
+        }
+    }
+}
+
+#[repr(C)]
+struct FileDedupeRange {
+    src_offset: u64,
+    src_length: u64,
+    dest_count: u16,
+    reserved1: u16,
+    reserved2: u32,
+    info: [FileDedupeRangeInfo; 1],
+}
+
+#[repr(C)]
+struct FileDedupeRangeInfo {
+    dest_fd: i64,
+    dest_offset: u64,
+    bytes_deduped: u64,
+    status: i32,
+    reserved: u32,
+}
+
+/// Share source extents with dest through FIDEDUPERANGE, return false when contents differ
+///
+/// The kernel compare both ranges and share them atomically, dest keep its inode and
+/// timestamps and only need to be readable by its owner
+fn reflink(source: &Path, dest: &Path) -> Result<bool> {
+    let src = File::open(source)?;
+    let dst = File::open(dest)?;
+    let len = src.metadata()?.len();
+    let mut offset = 0;
+    // filesystems may share less than requested in a single call
+    while offset < len {
+        let mut arg = FileDedupeRange {
+            src_offset: offset,
+            src_length: len - offset,
+            dest_count: 1,
+            reserved1: 0,
+            reserved2: 0,
+            info: [FileDedupeRangeInfo {
+                dest_fd: dst.as_raw_fd() as i64,
+                dest_offset: offset,
+                bytes_deduped: 0,
+                status: 0,
+                reserved: 0,
+            }],
+        };
+        let errno = if unsafe { libc::ioctl(src.as_raw_fd(), FIDEDUPERANGE, &mut arg) } < 0 {
+            Error::last_os_error()
+                .raw_os_error()
+                .unwrap_or(libc::EINVAL)
+        } else {
+            match arg.info[0].status {
+                FILE_DEDUPE_RANGE_SAME => 0,
+                FILE_DEDUPE_RANGE_DIFFERS => return Ok(false),
+                status => -status,
+            }
+        };
+        match errno {
+            0 => {}
+            libc::EOPNOTSUPP | libc::ENOTTY | libc::EINVAL | libc::EXDEV => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("filesystem of {:?} doesn't support reflinks", dest),
+                ));
+            }
+            errno => return Err(Error::from_raw_os_error(errno)),
+        }
+        if arg.info[0].bytes_deduped == 0 {
+            return Ok(false);
+        }
+        offset += arg.info[0].bytes_deduped;
+    }
+    Ok(true)
+}
+
+/// Atomically replace dest by a hardlink to source
+fn hardlink(source: &Path, dest: &Path) -> Result<()> {
+    let parent = dest.parent().ok_or(Error::new(
+        ErrorKind::InvalidInput,
+        "invalid duplicate path",
+    ))?;
+    let staging = parent.join(format!("{}{}", DEDUP_STAGING, std::process::id()));
+    fs::hard_link(source, &staging)?;
+    fs::rename(&staging, dest).inspect_err(|_| {
+        let _ = fs::remove_file(&staging);
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use temp_testdir::TempDir;
+
+    #[test]
+    fn dedup_layers() {
+        let root = TempDir::default();
+        let (l1, l2) = (root.join("l1"), root.join("l2"));
+        fs::create_dir_all(l1.join("framework")).unwrap();
+        fs::create_dir_all(l2.join("framework")).unwrap();
+        fs::write(l1.join("framework/lib"), b"shared framework").unwrap();
+        fs::write(l2.join("framework/lib"), b"shared framework").unwrap();
+        fs::write(l1.join("unique"), b"shared frameworK").unwrap();
+        fs::write(l2.join("empty"), b"").unwrap();
+        fs::write(l1.join("empty"), b"").unwrap();
+        // hardlinks require identical mtimes, don't rely on both writes sharing a timestamp
+        let mtime = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1 << 30);
+        for path in [l1.join("framework/lib"), l2.join("framework/lib")] {
+            File::options()
+                .write(true)
+                .open(path)
+                .and_then(|x| x.set_modified(mtime))
+                .unwrap();
+        }
+
+        let mut dedup = Dedup::new([&l1, &l2].iter());
+        dedup.set_dry_run(true);
+        let report = dedup.run().unwrap();
+        assert_eq!(report.files, 1);
+        assert_eq!(report.saved, 16);
+        assert_eq!(report.groups[0].source, l1.join("framework/lib"));
+        assert_eq!(report.groups[0].duplicates, vec![l2.join("framework/lib")]);
+        let ino = |p: &Path| fs::metadata(p).unwrap().ino();
+        assert_ne!(
+            ino(&l1.join("framework/lib")),
+            ino(&l2.join("framework/lib"))
+        );
+
+        dedup.set_dry_run(false);
+        // reflinks need btrfs, xfs or similar, contents stay untouched either way
+        match dedup.run() {
+            Ok(report) => assert_eq!(report.files, 1),
+            Err(e) => assert_eq!(e.kind(), ErrorKind::Unsupported),
+        }
+        assert_eq!(
+            fs::read(l2.join("framework/lib")).unwrap(),
+            b"shared framework"
+        );
+        dedup.set_method(DedupMethod::Hardlink);
+        assert_eq!(dedup.run().unwrap().files, 1);
+        assert!(!fs::read_dir(l2.join("framework")).unwrap().any(|x| {
+            x.unwrap()
+                .file_name()
+                .to_string_lossy()
+                .starts_with(DEDUP_STAGING)
+        }));
+        assert_eq!(
+            ino(&l1.join("framework/lib")),
+            ino(&l2.join("framework/lib"))
+        );
+        assert_eq!(
+            fs::read(l2.join("framework/lib")).unwrap(),
+            b"shared framework"
+        );
+        // already shared files are not reported again
+        assert_eq!(dedup.run().unwrap().files, 0);
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+#[cfg(any(
+    feature = "overlayfs",
+    feature = "fuse-overlayfs",
+    feature = "unionfs-fuse"
+))]
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+
+#[cfg(any(
+    feature = "overlayfs",
+    feature = "fuse-overlayfs",
+    feature = "unionfs-fuse"
+))]
+use crate::set_option_helper;
+use crate::{FsOption, MountOption, Propagation, UserNamespace};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// Backend agnostic description of a stackable filesystem
+///
+/// Can be persisted and later converted back into a ready to mount handle
+/// with `TryFrom`, the handle state (mounted or not) is not part of the description
+pub struct StackDescription<O: FsOption> {
+    /// Lower layers ordered from the top to the bottom
+    pub lower: Vec<PathBuf>,
+    /// Lower layers which are writable in place, only supported by unionfs-fuse
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub writable_lower: Vec<PathBuf>,
+    /// Data-only lower layers, only supported by overlayfs
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub data: Vec<PathBuf>,
+    pub upper: Option<PathBuf>,
+    /// Ignored by backend without work directory
+    pub work: Option<PathBuf>,
+    pub target: PathBuf,
+    pub options: Vec<MountOption<O>>,
+    /// Id mapping applied to every layer, only supported by overlayfs
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub idmap: Option<UserNamespace>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub propagation: Option<Propagation>,
+    /// Unmount the filesystem when the handle is dropped
+    pub drop: bool,
+}
+
+#[cfg(any(
+    feature = "overlayfs",
+    feature = "fuse-overlayfs",
+    feature = "unionfs-fuse"
+))]
+impl<O: FsOption + PartialEq> StackDescription<O> {
+    /// Validate option set against the incompatibility matrix of the filesystem
+    pub(crate) fn checked_options(&self) -> Result<Vec<MountOption<O>>> {
+        let mut options = vec![];
+        for opt in &self.options {
+            set_option_helper(&mut options, opt.clone())?;
+        }
+        Ok(options)
+    }
+
+    /// Refuse a description using features the backend cannot honour, rather than
+    /// silently building a different stack
+    pub(crate) fn check_supported(
+        &self,
+        backend: &str,
+        writable_lower: bool,
+        data: bool,
+        idmap: bool,
+    ) -> Result<()> {
+        let unsupported = [
+            (
+                !writable_lower && !self.writable_lower.is_empty(),
+                "writable lower layers",
+            ),
+            (!data && !self.data.is_empty(), "data-only layers"),
+            (!idmap && self.idmap.is_some(), "id mapping"),
+        ];
+        match unsupported.iter().find(|(x, _)| *x) {
+            Some((_, feature)) => Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("{} doesn't support {}", backend, feature),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    #[cfg(all(feature = "serde", feature = "overlayfs"))]
+    #[test]
+    fn overlay_roundtrip() {
+        use super::StackDescription;
+        use crate::{
+            LinuxFilesystem, OverlayFs, StackableFilesystem,
+            overlay::{OverlayFsOption, RedirectDir},
+        };
+
+        let mut o = OverlayFs::readonly(["/lower1", "/lower2"].iter(), "/mnt").unwrap();
+        o.set_option(OverlayFsOption::UserXattr).unwrap();
+        o.set_option(RedirectDir::NoFollow).unwrap();
+        let desc = StackDescription::from(&o);
+        let json = serde_json::to_string(&desc).unwrap();
+        let desc: StackDescription<OverlayFsOption> = serde_json::from_str(&json).unwrap();
+        let reco = OverlayFs::try_from(desc).unwrap();
+        assert_eq!(reco.lower(), o.lower());
+        assert_eq!(reco.upper(), o.upper());
+        assert_eq!(reco.options(), o.options());
+    }
+}
@@ -18,14 +18,43 @@ use std::{
     path::{Path, PathBuf},
     process::Command,
     str::FromStr,
+    sync::OnceLock,
+    time::Duration,
 };
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
 use crate::{
     set_option_helper, AsCString, AsPath, Filesystem, LinuxFilesystem, MountOption, PartitionID,
-    StackableFilesystem, StateRecovery,
+    Propagation, StackDescription, StackableFilesystem, StateRecovery, Tmpfs, UnmountMode,
+    Version,
 };
 
+#[cfg(feature = "integrity")]
+use super::integrity::{Manifest, check_layers, prune_manifests, set_manifest};
+use super::{
+    busy::{fusermount, unmount_with},
+    image::ImageLayers,
+    mountinfo::{FUSE_TIMEOUT, MountInfo},
+    swap,
+    tmpfs::{copy_tree, scratch_dirs},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Features supported by a fuse-overlayfs release
+pub struct FuseOverlayFsFeatures {
+    /// Mount point can be one of the lower directory
+    pub mount_on_lower: bool,
+}
+
+impl From<Version> for FuseOverlayFsFeatures {
+    fn from(value: Version) -> Self {
+        Self {
+            // [ 1.7, 1.9 ] doesn't support mounting on top of the base directory
+            mount_on_lower: !(Version::new(1, 7, 0)..Version::new(1, 10, 0)).contains(&value),
+        }
+    }
+}
+
 #[derive(Debug)]
 /// Fuse overlay filesystem handle
 pub struct FuseOverlayFs {
@@ -34,6 +63,13 @@ pub struct FuseOverlayFs {
     work: Option<PathBuf>,
     target: CString,
     options: Vec<MountOption<FuseOverlayFsOption>>,
+    propagation: Option<Propagation>,
+    unmount_mode: UnmountMode,
+    timeout: Duration,
+    scratch: Option<Tmpfs>,
+    images: ImageLayers,
+    #[cfg(feature = "integrity")]
+    manifests: Vec<(PathBuf, Manifest)>,
     id: Option<PartitionID>,
     drop: bool,
 }
@@ -67,6 +103,13 @@ impl FuseOverlayFs {
             work: work.map(|x| x.into()),
             target: target.as_ref().as_cstring(),
             options: MountOption::defaults(),
+            propagation: None,
+            unmount_mode: UnmountMode::default(),
+            timeout: FUSE_TIMEOUT,
+            scratch: None,
+            images: ImageLayers::default(),
+            #[cfg(feature = "integrity")]
+            manifests: vec![],
             id: None,
             drop,
         })
@@ -93,6 +136,13 @@ impl FuseOverlayFs {
             work: None,
             target: target.as_ref().as_cstring(),
             options: MountOption::defaults(),
+            propagation: None,
+            unmount_mode: UnmountMode::default(),
+            timeout: FUSE_TIMEOUT,
+            scratch: None,
+            images: ImageLayers::default(),
+            #[cfg(feature = "integrity")]
+            manifests: vec![],
             id: None,
             drop: true,
         })
@@ -120,11 +170,106 @@ impl FuseOverlayFs {
             work: Some(work.as_ref().to_path_buf()),
             target: target.as_ref().as_cstring(),
             options: MountOption::defaults(),
+            propagation: None,
+            unmount_mode: UnmountMode::default(),
+            timeout: FUSE_TIMEOUT,
+            scratch: None,
+            images: ImageLayers::default(),
+            #[cfg(feature = "integrity")]
+            manifests: vec![],
             id: None,
             drop: true,
         })
     }
 
+    /// Initialise a writable handle whose upper and work directories live on a private tmpfs
+    ///
+    /// scratch is mounted right away and unmounted along with the fuse-overlay, changes can be kept
+    /// with export_changes before teardown
+    pub fn ephemeral<I, A, T>(lower: I, mut scratch: Tmpfs, target: T) -> Result<FuseOverlayFs>
+    where
+        I: Iterator<Item = A>,
+        A: AsRef<Path>,
+        T: AsRef<Path>,
+    {
+        let (upper, work) = scratch_dirs(&mut scratch)?;
+        let mut handle = Self::writable(lower, upper, work, target)?;
+        handle.scratch = Some(scratch);
+        Ok(handle)
+    }
+
+    /// Tmpfs holding upper and work directories of an ephemeral handle
+    #[inline]
+    pub fn scratch(&self) -> Option<&Tmpfs> {
+        self.scratch.as_ref()
+    }
+
+    /// Manifests verified before each mount
+    #[cfg(feature = "integrity")]
+    #[inline]
+    pub fn manifests(&self) -> &[(PathBuf, Manifest)] {
+        &self.manifests
+    }
+
+    /// Refuse to mount while layer has drifted from manifest, None remove the check
+    #[cfg(feature = "integrity")]
+    pub fn set_manifest(
+        &mut self,
+        layer: impl AsRef<Path>,
+        manifest: Option<Manifest>,
+    ) -> Result<()> {
+        if self.id.is_some() {
+            return Err(Error::other(
+                "manifest cannot be change when the FileSystem is mounted",
+            ));
+        }
+        set_manifest(
+            &mut self.manifests,
+            self.lower.iter(),
+            layer.as_ref(),
+            manifest,
+        )
+    }
+
+    /// Copy the upper directory, which hold every change made through the fuse-overlay, to dest
+    pub fn export_changes(&self, dest: impl AsRef<Path>) -> Result<()> {
+        let upper = self
+            .upper
+            .as_ref()
+            .ok_or(Error::new(ErrorKind::NotFound, "upper directory not set"))?;
+        copy_tree(upper, dest.as_ref())
+    }
+
+    /// Retrieve the version of the fuse-overlayfs binary used to mount
+    pub fn version() -> Result<Version> {
+        static VERSION: OnceLock<Option<Version>> = OnceLock::new();
+        VERSION
+            .get_or_init(|| {
+                #[cfg(feature = "fuse-overlayfs-vendored")]
+                let output = env!("FUSE-OVERLAYFS-VERSION").to_string();
+                #[cfg(not(feature = "fuse-overlayfs-vendored"))]
+                let output = Command::new("fuse-overlayfs")
+                    .arg("--version")
+                    .output()
+                    .map(|x| {
+                        String::from_utf8_lossy(&x.stdout).to_string()
+                            + &String::from_utf8_lossy(&x.stderr)
+                    })
+                    .ok()?;
+                Version::from_version_output(&output).ok()
+            })
+            .ok_or(Error::new(
+                ErrorKind::NotFound,
+                "unable to detect fuse-overlayfs version",
+            ))
+    }
+
+    /// Retrieve the features supported by the fuse-overlayfs binary used to mount
+    #[inline]
+    pub fn features() -> Result<FuseOverlayFsFeatures> {
+        Self::version().map(FuseOverlayFsFeatures::from)
+    }
+
     #[inline]
     pub fn work(&self) -> Option<&PathBuf> {
         self.work.as_ref()
@@ -147,6 +292,80 @@ impl FuseOverlayFs {
         self.work = Some(work);
         Ok(())
     }
+
+    /// Time given to the daemon to attach or release the mount point
+    #[inline]
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Set the time given to the daemon to attach or release the mount point
+    #[inline]
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Replace the lower layers of the mounted overlay without unmounting it
+    ///
+    /// The new stack is served by another fuse-overlayfs process and atomically put in place
+    /// of the current one, which is lazily detached and exit once every process released it.
+    /// Only read-only overlays can be swapped, require privilege and kernel 6.5 or later
+    pub fn hot_swap(&mut self, lower: impl Into<Vec<PathBuf>>) -> Result<()> {
+        if self.id.is_none() {
+            return Err(Error::other(
+                "hot swap require the FileSystem to be mounted",
+            ));
+        }
+        let lower = lower.into();
+        let target = self.target.clone();
+        let previous = std::mem::replace(&mut self.lower, lower.clone());
+        let id = self.id.take();
+        // manifests of the dropped layers must not be checked by the replacement
+        #[cfg(feature = "integrity")]
+        let manifests = self.manifests.clone();
+        #[cfg(feature = "integrity")]
+        prune_manifests(&mut self.manifests, self.lower.iter());
+        // the replacement get its own images so a failure only release the ones it mounted
+        let mut images = std::mem::take(&mut self.images);
+        let res = swap::hot_swap(
+            target.as_path(),
+            &lower,
+            self.upper.is_some(),
+            |staging| {
+                self.target = staging.as_cstring();
+                self.mount().map(|_| ())
+            },
+            fusermount,
+        );
+        self.target = target;
+        if let Err(err) = res {
+            self.lower = previous;
+            self.id = id;
+            #[cfg(feature = "integrity")]
+            {
+                self.manifests = manifests;
+            }
+            if let Err(err) = std::mem::replace(&mut self.images, images).release() {
+                error!(
+                    "Damascus: unable to release layers of the replacing stack because : {}",
+                    err
+                )
+            }
+            return Err(err);
+        }
+        self.id = Some(PartitionID::try_from(self.target.as_path())?);
+        // images of the previous stack are lazily detached
+        if let Err(err) = images.release() {
+            error!(
+                "Damascus: unable to release layers of the replaced stack because : {}",
+                err
+            )
+        }
+        if let Some(p) = self.propagation {
+            p.apply(self.target.as_path())?;
+        }
+        Ok(())
+    }
 }
 
 impl Filesystem for FuseOverlayFs {
@@ -163,9 +382,31 @@ impl Filesystem for FuseOverlayFs {
             debug!("Damascus: partition already mounted");
             return Ok(PathBuf::from(&self.target.as_path()));
         }
+        if self.lower.iter().any(|x| x == self.target.as_path()) {
+            match Self::features() {
+                Ok(features) if !features.mount_on_lower => {
+                    return Err(Error::new(
+                        ErrorKind::Unsupported,
+                        format!(
+                            "fuse-overlayfs {} cannot be mounted on top of a lower directory, 1.10 or later is required",
+                            Self::version()?
+                        ),
+                    ));
+                }
+                Ok(_) => {}
+                Err(err) => warn!(
+                    "Damascus: mounting on top of a lower directory may fail because : {}",
+                    err
+                ),
+            }
+        }
+        #[cfg(feature = "integrity")]
+        check_layers(&self.manifests)?;
+        // images given as layers are mounted first then used in place of directories
+        let lower = self.images.resolve(&self.lower)?;
         let mut options = String::new();
         options.push_str("lowerdir=");
-        for (i, p) in self.lower.iter().enumerate() {
+        for (i, p) in lower.iter().enumerate() {
             if i != 0 {
                 options.push(':')
             }
@@ -182,6 +423,8 @@ impl Filesystem for FuseOverlayFs {
             options.push_str(&(",".to_string() + &mo.to_string()))
         }
 
+        let target = std::fs::canonicalize(self.target.as_path())?;
+        let previous = MountInfo::find(&target)?.map(|x| x.id);
         let args = &[
             CString::new("fuse-overlayfs")?,
             CString::new("-o")?,
@@ -190,16 +433,19 @@ impl Filesystem for FuseOverlayFs {
         ];
 
         #[cfg(feature = "fuse-overlayfs-vendored")]
-        {
+        let stderr = {
             use nix::{
+                fcntl::OFlag,
+                libc,
                 sys::{
-                    memfd::{memfd_create, MFdFlags},
-                    wait::waitpid,
+                    memfd::{MFdFlags, memfd_create},
+                    wait::{WaitStatus, waitpid},
                 },
-                unistd::{fexecve, fork, write, ForkResult},
+                unistd::{ForkResult, dup2_stderr, fexecve, fork, pipe2, write},
             };
+            use std::{fs::File, io::Read};
             // init embedded fuse overlay version 1.10 or later since [ 1.7, 1.9 ] doesn't support mounting on top
-            // of the base directory
+            // of the base directory, see FuseOverlayFsFeatures
             let byte = include_bytes!(concat!("../../../", env!("FUSE-OVERLAYFS-BIN")));
             let mem = memfd_create(
                 CString::new("fuse-overlayfs")?.as_c_str(),
@@ -207,12 +453,31 @@ impl Filesystem for FuseOverlayFs {
             )?;
             write(&mem, byte)?;
             let env: Vec<CString> = vec![];
+            // the write end is only kept by the child, closed on exec unless it became its stderr
+            let (reader, writer) = pipe2(OFlag::O_CLOEXEC)?;
             match unsafe { fork() } {
                 Ok(ForkResult::Parent { child, .. }) => {
-                    waitpid(child, None)?;
+                    drop(writer);
+                    let mut output = Vec::new();
+                    let read = File::from(reader).read_to_end(&mut output);
+                    let status = waitpid(child, None)?;
+                    read?;
+                    let stderr = String::from_utf8_lossy(&output).to_string();
+                    if !matches!(status, WaitStatus::Exited(_, 0)) {
+                        error!("Damascus: unable to mount {:?}\n{}", &self, stderr);
+                        return Err(Error::new(
+                            ErrorKind::PermissionDenied,
+                            "Failed to mount vfs",
+                        ));
+                    }
+                    stderr
                 }
                 Ok(ForkResult::Child) => {
-                    fexecve(mem, args, &env)?;
+                    // only async-signal-safe calls until exec, exit without unwinding on failure
+                    if dup2_stderr(&writer).is_ok() {
+                        let _ = fexecve(mem, args, &env);
+                    }
+                    unsafe { libc::_exit(127) }
                 }
                 Err(_) => {
                     return Err(Error::new(
@@ -221,58 +486,67 @@ impl Filesystem for FuseOverlayFs {
                     ));
                 }
             }
-        }
+        };
         #[cfg(not(feature = "fuse-overlayfs-vendored"))]
-        {
-            let options: Vec<&str> = args.iter().skip(1).map(|x| x.to_str().unwrap()).collect();
+        let stderr = {
             let output = Command::new("fuse-overlayfs")
-                .args(options)
-                .spawn()
-                .unwrap()
-                .wait_with_output()
-                .unwrap();
+                .args(args.iter().skip(1).map(|x| x.as_path()))
+                .stderr(std::process::Stdio::piped())
+                .spawn()?
+                .wait_with_output()?;
+            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
             if !output.status.success() {
-                error!(
-                    "Damascus: unable to mount {:?}\n{}",
-                    &self,
-                    String::from_utf8_lossy(&output.stderr)
-                );
+                error!("Damascus: unable to mount {:?}\n{}", &self, stderr);
                 return Err(Error::new(
                     ErrorKind::PermissionDenied,
                     "Failed to mount vfs",
                 ));
             }
+            stderr
         };
+        // the daemon may still be attaching the mount point once the launcher exited
+        if !MountInfo::wait_fuse(&target, previous, self.timeout)? {
+            error!("Damascus: {:?} wasn't mounted in time\n{}", &self, stderr);
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!(
+                    "fuse-overlayfs didn't mount {:?} within {:?}\n{}",
+                    target, self.timeout, stderr
+                ),
+            ));
+        }
 
         self.id = Some(
             PartitionID::try_from(self.target.as_path())
                 .map_err(|_| Error::other("unable to get PartitionID"))?,
         );
+        if let Some(p) = self.propagation {
+            p.apply(self.target.as_path())?;
+        }
         Ok(self.target.as_path().to_path_buf())
     }
 
     #[inline]
     fn unmount(&mut self) -> Result<()> {
         if matches!(self.id,Some(x) if x == PartitionID::try_from(self.target.as_path())?) {
-            let child = Command::new("fusermount")
-                .args(["-z", "-u"])
-                .arg(self.target.as_path())
-                .spawn()?;
-            let output = child.wait_with_output()?;
-            if !output.status.success() {
-                error!(
-                    "Damascus: unable to unmount {:?}\n{}",
-                    &self,
-                    String::from_utf8_lossy(&output.stderr)
-                );
+            let mount = MountInfo::find(&std::path::absolute(self.target.as_path())?)?;
+            unmount_with(self.target.as_path(), self.unmount_mode, |flags| {
+                fusermount(self.target.as_path(), flags)
+            })?;
+            if let Some(mount) = mount
+                && !MountInfo::wait_gone(mount.id, self.timeout)?
+            {
                 return Err(Error::new(
-                    ErrorKind::PermissionDenied,
-                    "Failed to unmount vfs",
+                    ErrorKind::TimedOut,
+                    format!(
+                        "{:?} wasn't unmounted within {:?}",
+                        self.target, self.timeout
+                    ),
                 ));
             }
             self.id = None;
         }
-        Ok(())
+        self.images.release()
     }
 
     #[inline]
@@ -283,6 +557,10 @@ impl Filesystem for FuseOverlayFs {
     #[inline]
     fn set_unmount_on_drop(&mut self, drop: bool) {
         self.drop = drop;
+        if let Some(scratch) = self.scratch.as_mut() {
+            scratch.set_unmount_on_drop(drop);
+        }
+        self.images.set_unmount_on_drop(drop);
     }
 
     #[inline]
@@ -340,6 +618,28 @@ impl LinuxFilesystem<FuseOverlayFsOption> for FuseOverlayFs {
     fn options(&self) -> &[MountOption<FuseOverlayFsOption>] {
         &self.options
     }
+
+    fn propagation(&self) -> Option<Propagation> {
+        self.propagation
+    }
+
+    fn set_propagation(&mut self, propagation: Option<Propagation>) -> Result<()> {
+        if let Some(p) = propagation
+            && self.id.is_some()
+        {
+            p.apply(self.target.as_path())?;
+        }
+        self.propagation = propagation;
+        Ok(())
+    }
+
+    fn unmount_mode(&self) -> UnmountMode {
+        self.unmount_mode
+    }
+
+    fn set_unmount_mode(&mut self, mode: UnmountMode) {
+        self.unmount_mode = mode;
+    }
 }
 
 impl StackableFilesystem for FuseOverlayFs {
@@ -356,6 +656,8 @@ impl StackableFilesystem for FuseOverlayFs {
             ));
         }
         self.lower = lower.into();
+        #[cfg(feature = "integrity")]
+        prune_manifests(&mut self.manifests, self.lower.iter());
         Ok(())
     }
 
@@ -437,12 +739,22 @@ impl StateRecovery for FuseOverlayFs {
                     } else if let Some(target) = Some(CString::new(elem)?)
                         && target.as_path() == path
                     {
+                        let mut images = ImageLayers::default();
+                        images.recover(&mut lower)?;
+                        images.set_unmount_on_drop(true);
                         return Ok(Self {
                             lower,
                             upper,
                             work,
                             target,
                             options,
+                            propagation: Propagation::of(path).ok(),
+                            unmount_mode: UnmountMode::default(),
+                            timeout: FUSE_TIMEOUT,
+                            scratch: None,
+                            images,
+                            #[cfg(feature = "integrity")]
+                            manifests: vec![],
                             id: Some(
                                 PartitionID::try_from(path)
                                     .map_err(|_| Error::other("unable to get PartitionID"))?,
@@ -461,6 +773,48 @@ impl StateRecovery for FuseOverlayFs {
     }
 }
 
+impl TryFrom<StackDescription<FuseOverlayFsOption>> for FuseOverlayFs {
+    type Error = Error;
+
+    fn try_from(value: StackDescription<FuseOverlayFsOption>) -> Result<Self> {
+        value.check_supported("fuse-overlay FileSystem", false, false, false)?;
+        let options = value.checked_options()?;
+        let mut handle = match (&value.upper, &value.work) {
+            (Some(upper), Some(work)) => {
+                Self::writable(value.lower.iter(), upper, work, &value.target)?
+            }
+            (None, None) => Self::readonly(value.lower.iter(), &value.target)?,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "fuse-overlay FileSystem need both upper and work directory to be writable",
+                ));
+            }
+        };
+        handle.options = options;
+        handle.propagation = value.propagation;
+        handle.drop = value.drop;
+        Ok(handle)
+    }
+}
+
+impl From<&FuseOverlayFs> for StackDescription<FuseOverlayFsOption> {
+    fn from(value: &FuseOverlayFs) -> Self {
+        Self {
+            lower: value.lower.clone(),
+            writable_lower: vec![],
+            data: vec![],
+            upper: value.upper.clone(),
+            work: value.work.clone(),
+            target: value.target.as_path().to_path_buf(),
+            options: value.options.clone(),
+            idmap: None,
+            propagation: value.propagation,
+            drop: value.drop,
+        }
+    }
+}
+
 impl Drop for FuseOverlayFs {
     #[inline]
     fn drop(&mut self) {
@@ -483,4 +837,13 @@ mod tests {
         use super::{Filesystem, FuseOverlayFs};
         assert!(FuseOverlayFs::is_available())
     }
+
+    #[test]
+    fn features() {
+        use super::{FuseOverlayFsFeatures, Version};
+        assert!(FuseOverlayFsFeatures::from(Version::new(1, 6, 0)).mount_on_lower);
+        assert!(!FuseOverlayFsFeatures::from(Version::new(1, 7, 1)).mount_on_lower);
+        assert!(!FuseOverlayFsFeatures::from(Version::new(1, 9, 0)).mount_on_lower);
+        assert!(FuseOverlayFsFeatures::from(Version::new(1, 10, 0)).mount_on_lower);
+    }
 }
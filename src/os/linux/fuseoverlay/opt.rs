@@ -5,13 +5,33 @@
 //     http://www.apache.org/licenses/LICENSE-2.0
 //
 // Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
-use crate::{FsOption, MountOption};
+use crate::{FsOption, IdMap, MountOption};
+
+use std::{fmt::Display, path::PathBuf, str::FromStr};
+
+/// Where file permissions are stored when they cannot be set on the underlying file
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum XattrPermission {
+    /// Permissions are not stored in extended attributes
+    Disabled,
+    /// Permissions are stored in the "security.fuseoverlayfs.override_stat" xattr
+    Privileged,
+    /// Permissions are stored in the "user.fuseoverlayfs.override_stat" xattr
+    User,
+}
+
+impl From<XattrPermission> for MountOption<FuseOverlayFsOption> {
+    fn from(val: XattrPermission) -> Self {
+        MountOption::FsSpecific(FuseOverlayFsOption::XattrPermissions(val))
+    }
+}
 
-use std::{fmt::Display, str::FromStr};
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum FuseOverlayFsOption {
-    // /// Use separate fuse device fd for each thread
-    // CloneFd,
+    /// Use separate fuse device fd for each thread
+    CloneFd,
     /// The maximum number of idle worker threads allowed (default: -1)
     MaxIdleThread(isize),
     /// The maximum number of worker threads allowed (default: 10)
@@ -32,7 +52,26 @@ pub enum FuseOverlayFsOption {
     StaticNLink,
     /// Disable ACL support in the FUSE file system
     NoAcl,
-    // TODO : look into uidmapping and gidmapping
+    /// Dynamic uid mapping used by fuse-overlayfs,
+    /// each range map a uid seen inside the mount to the uid stored on the layers
+    UidMapping(IdMap),
+    /// Dynamic gid mapping used by fuse-overlayfs,
+    /// each range map a gid seen inside the mount to the gid stored on the layers
+    GidMapping(IdMap),
+    /// Plugins used to read lower layers, tried in order
+    Plugins(Vec<PathBuf>),
+    /// Where file permissions are stored when they cannot be set on the underlying file
+    XattrPermissions(XattrPermission),
+    /// Use the fast path for inode numbers,
+    /// which skip the lookup of the origin inode on lower layers
+    FastIno(bool),
+    /// Forward fsync requests to the upper layer,
+    /// disabling it trade durability for speed
+    Fsync(bool),
+    /// Disable extended attributes support
+    NoXattrs,
+    /// Enable debug output
+    Debug,
 }
 
 impl FsOption for FuseOverlayFsOption {
@@ -40,8 +79,38 @@ impl FsOption for FuseOverlayFsOption {
         vec![]
     }
 
-    fn incompatible(&self, _other: &MountOption<Self>) -> bool {
-        // TODO : find incompatible mount option and define compatibility matrix
+    fn incompatible(&self, other: &MountOption<Self>) -> bool {
+        let MountOption::FsSpecific(other) = other else {
+            return false;
+        };
+        let incompat_matrix = [
+            |s: &FuseOverlayFsOption, o: &FuseOverlayFsOption| {
+                matches!(s, FuseOverlayFsOption::AllowOther)
+                    && matches!(o, FuseOverlayFsOption::AllowRoot)
+            },
+            |s: &FuseOverlayFsOption, o: &FuseOverlayFsOption| {
+                matches!(s, FuseOverlayFsOption::NoXattrs)
+                    && matches!(o, FuseOverlayFsOption::XattrPermissions(_))
+            },
+            |s: &FuseOverlayFsOption, o: &FuseOverlayFsOption| {
+                matches!(
+                    s,
+                    FuseOverlayFsOption::SquashToRoot | FuseOverlayFsOption::SquashToUid(_)
+                ) && matches!(o, FuseOverlayFsOption::UidMapping(_))
+            },
+            |s: &FuseOverlayFsOption, o: &FuseOverlayFsOption| {
+                matches!(
+                    s,
+                    FuseOverlayFsOption::SquashToRoot | FuseOverlayFsOption::SquashToGid(_)
+                ) && matches!(o, FuseOverlayFsOption::GidMapping(_))
+            },
+        ];
+
+        for incompat in incompat_matrix {
+            if incompat(self, other) || incompat(other, self) {
+                return true;
+            }
+        }
         false
     }
 }
@@ -72,6 +141,36 @@ impl FromStr for FuseOverlayFsOption {
                         return Ok(Self::SquashToGid(u));
                     }
                 }
+                "uidmapping" => {
+                    if let Ok(m) = va.parse() {
+                        return Ok(Self::UidMapping(m));
+                    }
+                }
+                "gidmapping" => {
+                    if let Ok(m) = va.parse() {
+                        return Ok(Self::GidMapping(m));
+                    }
+                }
+                "plugins" => {
+                    return Ok(Self::Plugins(va.split(':').map(PathBuf::from).collect()));
+                }
+                "xattr_permissions" => match va {
+                    "0" => return Ok(Self::XattrPermissions(XattrPermission::Disabled)),
+                    "1" => return Ok(Self::XattrPermissions(XattrPermission::Privileged)),
+                    "2" => return Ok(Self::XattrPermissions(XattrPermission::User)),
+                    _ => {}
+                },
+                "fast_ino" => match va {
+                    "1" => return Ok(Self::FastIno(true)),
+                    "0" => return Ok(Self::FastIno(false)),
+                    _ => {}
+                },
+                "fsync" => match va {
+                    "1" => return Ok(Self::Fsync(true)),
+                    "0" => return Ok(Self::Fsync(false)),
+                    _ => {}
+                },
+                "noxattrs" if va == "1" => return Ok(Self::NoXattrs),
                 _ => {}
             };
         }
@@ -82,7 +181,9 @@ impl FromStr for FuseOverlayFsOption {
             "squash_to_root" => Self::SquashToRoot,
             "static_nlink" => Self::StaticNLink,
             "noacl" => Self::NoAcl,
-            // "clone_fd" => Self::CloneFd,
+            "clone_fd" => Self::CloneFd,
+            "noxattrs" => Self::NoXattrs,
+            "debug" => Self::Debug,
             _ => {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::Unsupported,
@@ -98,7 +199,7 @@ impl Display for FuseOverlayFsOption {
             f,
             "{}",
             match self {
-                //FuseOverlayFsOption::CloneFd => "clone_fd".to_owned(),
+                FuseOverlayFsOption::CloneFd => "clone_fd".to_owned(),
                 FuseOverlayFsOption::MaxIdleThread(x) => format!("max_idle_threads={}", x),
                 FuseOverlayFsOption::MaxThread(x) => format!("max_threads={}", x),
                 FuseOverlayFsOption::AllowOther => "allow_other".to_owned(),
@@ -108,6 +209,25 @@ impl Display for FuseOverlayFsOption {
                 FuseOverlayFsOption::SquashToGid(gid) => format!("squash_to_gid={}", gid),
                 FuseOverlayFsOption::StaticNLink => "static_nlink".to_owned(),
                 FuseOverlayFsOption::NoAcl => "noacl".to_owned(),
+                FuseOverlayFsOption::UidMapping(m) => format!("uidmapping={}", m),
+                FuseOverlayFsOption::GidMapping(m) => format!("gidmapping={}", m),
+                FuseOverlayFsOption::Plugins(p) => format!(
+                    "plugins={}",
+                    p.iter()
+                        .map(|x| x.to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join(":")
+                ),
+                FuseOverlayFsOption::XattrPermissions(x) => match x {
+                    XattrPermission::Disabled => "xattr_permissions=0",
+                    XattrPermission::Privileged => "xattr_permissions=1",
+                    XattrPermission::User => "xattr_permissions=2",
+                }
+                .to_owned(),
+                FuseOverlayFsOption::FastIno(b) => format!("fast_ino={}", *b as u8),
+                FuseOverlayFsOption::Fsync(b) => format!("fsync={}", *b as u8),
+                FuseOverlayFsOption::NoXattrs => "noxattrs=1".to_owned(),
+                FuseOverlayFsOption::Debug => "debug".to_owned(),
             }
         )
     }
@@ -118,3 +238,45 @@ impl From<FuseOverlayFsOption> for MountOption<FuseOverlayFsOption> {
         MountOption::FsSpecific(val)
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::IdRange;
+
+    #[test]
+    fn roundtrip() {
+        let opts = [
+            FuseOverlayFsOption::CloneFd,
+            FuseOverlayFsOption::UidMapping(
+                vec![IdRange::new(0, 1000, 1), IdRange::new(1, 100000, 65536)].into(),
+            ),
+            FuseOverlayFsOption::GidMapping(IdRange::new(0, 1000, 1).into()),
+            FuseOverlayFsOption::Plugins(vec![PathBuf::from("/a.so"), PathBuf::from("/b.so")]),
+            FuseOverlayFsOption::XattrPermissions(XattrPermission::User),
+            FuseOverlayFsOption::FastIno(true),
+            FuseOverlayFsOption::Fsync(false),
+            FuseOverlayFsOption::NoXattrs,
+            FuseOverlayFsOption::Debug,
+        ];
+        for opt in opts {
+            assert_eq!(
+                FuseOverlayFsOption::from_str(&opt.to_string()).unwrap(),
+                opt
+            );
+        }
+    }
+
+    #[test]
+    fn incompatible() {
+        let squash = FuseOverlayFsOption::SquashToRoot;
+        let mapping: MountOption<_> =
+            FuseOverlayFsOption::UidMapping(IdRange::new(0, 1000, 1).into()).into();
+        assert!(squash.incompatible(&mapping));
+        assert!(
+            FuseOverlayFsOption::AllowRoot.incompatible(&FuseOverlayFsOption::AllowOther.into())
+        );
+        assert!(!FuseOverlayFsOption::NoAcl.incompatible(&mapping));
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use std::{
+    fmt::Display,
+    io::{Error, ErrorKind},
+    str::FromStr,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// Contiguous range of id mapped from one id space to another
+pub struct IdRange {
+    /// First id of the range as seen from inside the mapping
+    pub inside: u32,
+    /// First id of the range as seen from outside the mapping
+    pub outside: u32,
+    /// Number of consecutive id mapped
+    pub count: u32,
+}
+
+impl IdRange {
+    #[inline]
+    pub fn new(inside: u32, outside: u32, count: u32) -> Self {
+        Self {
+            inside,
+            outside,
+            count,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// Uid or gid mapping made of one or several id range
+///
+/// Textual representation is the one used by fuse-overlayfs,
+/// `inside:outside:count` triplets joined by `:`
+pub struct IdMap(Vec<IdRange>);
+
+impl IdMap {
+    #[inline]
+    pub fn new() -> Self {
+        Self(vec![])
+    }
+
+    /// Append a range to the mapping
+    #[inline]
+    pub fn push(&mut self, range: IdRange) {
+        self.0.push(range)
+    }
+
+    /// Retrieve every range of the mapping
+    #[inline]
+    pub fn ranges(&self) -> &[IdRange] {
+        &self.0
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Translate an id from outside the mapping to the one seen inside of it
+    pub fn inside(&self, outside: u32) -> Option<u32> {
+        self.0
+            .iter()
+            .find(|x| outside >= x.outside && outside - x.outside < x.count)
+            .map(|x| x.inside + (outside - x.outside))
+    }
+}
+
+impl From<Vec<IdRange>> for IdMap {
+    fn from(value: Vec<IdRange>) -> Self {
+        Self(value)
+    }
+}
+
+impl From<IdRange> for IdMap {
+    fn from(value: IdRange) -> Self {
+        Self(vec![value])
+    }
+}
+
+impl FromStr for IdMap {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, "Invalid id mapping");
+        let values = s
+            .split(':')
+            .map(|x| x.parse::<u32>().map_err(|_| invalid()))
+            .collect::<Result<Vec<u32>, Error>>()?;
+        if values.is_empty() || values.len() % 3 != 0 {
+            return Err(invalid());
+        }
+        Ok(Self(
+            values
+                .chunks_exact(3)
+                .map(|x| IdRange::new(x[0], x[1], x[2]))
+                .collect(),
+        ))
+    }
+}
+
+impl Display for IdMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, r) in self.0.iter().enumerate() {
+            if i != 0 {
+                write!(f, ":")?;
+            }
+            write!(f, "{}:{}:{}", r.inside, r.outside, r.count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_display() {
+        let map = IdMap::from_str("0:1000:1:1:100000:65536").unwrap();
+        assert_eq!(
+            map.ranges(),
+            &[IdRange::new(0, 1000, 1), IdRange::new(1, 100000, 65536)]
+        );
+        assert_eq!(map.to_string(), "0:1000:1:1:100000:65536");
+        assert!(IdMap::from_str("0:1000").is_err());
+        assert!(IdMap::from_str("").is_err());
+        assert_eq!(map.inside(1000), Some(0));
+        assert_eq!(map.inside(100010), Some(11));
+        assert_eq!(map.inside(1001), None);
+    }
+}
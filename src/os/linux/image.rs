@@ -0,0 +1,458 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use std::{
+    ffi::CString,
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{Error, ErrorKind, Read, Result, Seek, SeekFrom},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use nix::{
+    libc::{self, c_ulong},
+    mount::{MsFlags, mount, umount2},
+    unistd::geteuid,
+};
+use tracing::{debug, error};
+
+use super::{
+    busy::{fusermount, unmount_with},
+    mountinfo::MountInfo,
+};
+use crate::{AsCString, AsPath, Filesystem, PartitionID, StateRecovery, UnmountMode};
+
+#[cfg(any(feature = "overlayfs", feature = "fuse-overlayfs"))]
+mod layers;
+#[cfg(any(feature = "overlayfs", feature = "fuse-overlayfs"))]
+pub(crate) use layers::ImageLayers;
+
+const SQUASHFS_MAGIC: &[u8] = b"hsqs";
+const EROFS_MAGIC: u32 = 0xE0F5E1E2;
+/// EROFS superblock is placed after a 1KiB padding
+const EROFS_SUPER_OFFSET: u64 = 1024;
+
+const LOOP_SET_FD: c_ulong = 0x4C00;
+const LOOP_CLR_FD: c_ulong = 0x4C01;
+const LOOP_SET_STATUS64: c_ulong = 0x4C04;
+const LOOP_CTL_GET_FREE: c_ulong = 0x4C82;
+/// Release the loop device once the last user is gone
+const LO_FLAGS_AUTOCLEAR: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// Read-only filesystem image format
+pub enum ImageKind {
+    SquashFs,
+    Erofs,
+}
+
+impl ImageKind {
+    /// Detect the format of an image from its superblock magic
+    pub fn detect(image: impl AsRef<Path>) -> Result<Option<Self>> {
+        let mut file = File::open(image)?;
+        let mut magic = [0u8; 4];
+        if file.read_exact(&mut magic).is_ok() && magic == SQUASHFS_MAGIC {
+            return Ok(Some(ImageKind::SquashFs));
+        }
+        file.seek(SeekFrom::Start(EROFS_SUPER_OFFSET))?;
+        if file.read_exact(&mut magic).is_ok() && u32::from_le_bytes(magic) == EROFS_MAGIC {
+            return Ok(Some(ImageKind::Erofs));
+        }
+        Ok(None)
+    }
+
+    /// Name of the kernel filesystem
+    #[inline]
+    fn fs_type(&self) -> &'static str {
+        match self {
+            ImageKind::SquashFs => "squashfs",
+            ImageKind::Erofs => "erofs",
+        }
+    }
+
+    /// Binary mounting the image without privilege
+    #[inline]
+    fn fuse(&self) -> &'static str {
+        match self {
+            ImageKind::SquashFs => "squashfuse",
+            ImageKind::Erofs => "erofsfuse",
+        }
+    }
+
+    /// Check that the running kernel can mount this format
+    fn in_kernel(&self) -> bool {
+        std::fs::read_to_string("/proc/filesystems")
+            .map(|x| {
+                x.lines()
+                    .any(|l| l.split('\t').nth(1) == Some(self.fs_type()))
+            })
+            .unwrap_or(false)
+    }
+}
+
+impl Display for ImageKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.fs_type())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// How an image get mounted
+pub enum ImageBackend {
+    /// Kernel filesystem through a loop device, require privilege
+    Loop,
+    /// squashfuse or erofsfuse
+    Fuse,
+}
+
+#[derive(Debug)]
+/// Handle of a squashfs or EROFS image mounted read-only
+pub struct ImageMount {
+    image: PathBuf,
+    kind: ImageKind,
+    backend: Option<ImageBackend>,
+    target: CString,
+    mounted: Option<ImageBackend>,
+    unmount_mode: UnmountMode,
+    id: Option<PartitionID>,
+    drop: bool,
+}
+
+impl ImageMount {
+    #[inline]
+    pub fn new<I, T>(image: I, target: T) -> Result<Self>
+    where
+        I: AsRef<Path>,
+        T: AsRef<Path>,
+    {
+        let image = image.as_ref();
+        let kind = ImageKind::detect(image)?.ok_or(Error::new(
+            ErrorKind::InvalidInput,
+            "not a squashfs or EROFS image : ".to_string() + &image.to_string_lossy(),
+        ))?;
+        Ok(Self {
+            image: image.to_path_buf(),
+            kind,
+            backend: None,
+            target: target.as_ref().as_cstring(),
+            mounted: None,
+            unmount_mode: UnmountMode::default(),
+            id: None,
+            drop: true,
+        })
+    }
+
+    #[inline]
+    pub fn image(&self) -> &Path {
+        &self.image
+    }
+
+    #[inline]
+    pub fn kind(&self) -> ImageKind {
+        self.kind
+    }
+
+    /// Backend forced by the caller, picked on mount when unset
+    #[inline]
+    pub fn backend(&self) -> Option<ImageBackend> {
+        self.backend
+    }
+
+    #[inline]
+    pub fn set_backend(&mut self, backend: Option<ImageBackend>) -> Result<()> {
+        if self.id.is_some() {
+            return Err(Error::other(
+                "backend cannot be change when the FileSystem is mounted",
+            ));
+        }
+        self.backend = backend;
+        Ok(())
+    }
+
+    /// Behavior of unmount when processes still use the mount point
+    #[inline]
+    pub fn unmount_mode(&self) -> UnmountMode {
+        self.unmount_mode
+    }
+
+    /// Set the behavior of unmount, lazy by default
+    #[inline]
+    pub fn set_unmount_mode(&mut self, mode: UnmountMode) {
+        self.unmount_mode = mode;
+    }
+
+    /// Loop device when privileged and supported by the kernel, FUSE otherwise
+    fn pick_backend(&self) -> ImageBackend {
+        self.backend
+            .unwrap_or(if geteuid().is_root() && self.kind.in_kernel() {
+                ImageBackend::Loop
+            } else {
+                ImageBackend::Fuse
+            })
+    }
+
+    fn mount_loop(&self) -> Result<()> {
+        let (device, path) = attach_loop(&self.image)?;
+        if let Err(err) = mount(
+            Some(path.as_path()),
+            self.target.as_c_str(),
+            Some(self.kind.fs_type()),
+            MsFlags::MS_RDONLY,
+            None::<&str>,
+        ) {
+            unsafe { libc::ioctl(device.as_raw_fd(), LOOP_CLR_FD, 0) };
+            return Err(err.into());
+        }
+        // autoclear release the device along with the mount
+        Ok(())
+    }
+
+    fn mount_fuse(&self) -> Result<()> {
+        // the image is recorded as source so the handle can be recovered
+        let fsname = self.image.to_string_lossy().replace(',', "\\,");
+        let output = Command::new(self.kind.fuse())
+            .arg("-o")
+            .arg(format!("fsname={}", fsname))
+            .arg(&self.image)
+            .arg(self.target.as_path())
+            .output()?;
+        if !output.status.success() {
+            error!(
+                "Damascus: unable to mount {:?}\n{}",
+                &self,
+                String::from_utf8_lossy(&output.stderr)
+            );
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "Failed to mount image",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Filesystem for ImageMount {
+    fn mount(&mut self) -> Result<PathBuf> {
+        if matches!(self.id,Some(x) if x == PartitionID::try_from(self.target.as_path())?) {
+            debug!("Damascus: partition already mounted");
+            return Ok(self.target.as_path().to_path_buf());
+        }
+        let backend = self.pick_backend();
+        match backend {
+            ImageBackend::Loop => self.mount_loop()?,
+            ImageBackend::Fuse => self.mount_fuse()?,
+        }
+        self.mounted = Some(backend);
+        self.id = Some(
+            PartitionID::try_from(self.target.as_path())
+                .map_err(|_| Error::other("unable to get PartitionID"))?,
+        );
+        Ok(self.target.as_path().to_path_buf())
+    }
+
+    fn unmount(&mut self) -> Result<()> {
+        if matches!(self.id,Some(x) if x == PartitionID::try_from(self.target.as_path())?) {
+            let target = self.target.as_path();
+            unmount_with(target, self.unmount_mode, |flags| match self.mounted {
+                Some(ImageBackend::Fuse) => fusermount(target, flags),
+                _ => Ok(umount2(self.target.as_c_str(), flags)?),
+            })?;
+            self.id = None;
+            self.mounted = None;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn unmount_on_drop(&self) -> bool {
+        self.drop
+    }
+
+    #[inline]
+    fn set_unmount_on_drop(&mut self, drop: bool) {
+        self.drop = drop;
+    }
+
+    #[inline]
+    fn id(&self) -> Option<&PartitionID> {
+        self.id.as_ref()
+    }
+
+    #[inline]
+    fn target(&self) -> PathBuf {
+        self.target.as_path().to_path_buf()
+    }
+
+    #[inline]
+    fn set_target(&mut self, target: impl AsRef<Path>) -> Result<()> {
+        if self.id.is_some() {
+            return Err(Error::other(
+                "mount point cannot be change when the FileSystem is mounted",
+            ));
+        }
+        self.target = target.as_ref().as_cstring();
+        Ok(())
+    }
+
+    #[inline]
+    fn is_available() -> bool {
+        [ImageKind::SquashFs, ImageKind::Erofs]
+            .iter()
+            .any(|x| (geteuid().is_root() && x.in_kernel()) || in_path(x.fuse()))
+    }
+}
+
+impl StateRecovery for ImageMount {
+    fn recover<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let info = MountInfo::find(path)?.ok_or(Error::new(
+            ErrorKind::NotFound,
+            "ImageMount not found at mount point : ".to_string() + &path.to_string_lossy(),
+        ))?;
+        let (image, backend) = if info.fs_type == "squashfs" || info.fs_type == "erofs" {
+            let device = Path::new(&info.source)
+                .file_name()
+                .ok_or(Error::other("unable to find loop device"))?
+                .to_string_lossy()
+                .to_string();
+            let backing =
+                std::fs::read_to_string(format!("/sys/block/{}/loop/backing_file", device))?;
+            (PathBuf::from(backing.trim_end()), ImageBackend::Loop)
+        } else if info.fs_type.starts_with("fuse") {
+            (PathBuf::from(&info.source), ImageBackend::Fuse)
+        } else {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unexpected filesystem {} at {:?}", info.fs_type, path),
+            ));
+        };
+        let mut handle = Self::new(image, path)?;
+        handle.mounted = Some(backend);
+        handle.id = Some(
+            PartitionID::try_from(path).map_err(|_| Error::other("unable to get PartitionID"))?,
+        );
+        handle.drop = false;
+        Ok(handle)
+    }
+}
+
+impl Drop for ImageMount {
+    #[inline]
+    fn drop(&mut self) {
+        if self.drop
+            && let Err(err) = self.unmount()
+        {
+            error!(
+                "Damascus: unable to unmount image at {:?} because : {}",
+                self.target, err
+            )
+        }
+    }
+}
+
+#[repr(C)]
+/// struct loop_info64 from linux/loop.h
+struct LoopInfo64 {
+    lo_device: u64,
+    lo_inode: u64,
+    lo_rdevice: u64,
+    lo_offset: u64,
+    lo_sizelimit: u64,
+    lo_number: u32,
+    lo_encrypt_type: u32,
+    lo_encrypt_key_size: u32,
+    lo_flags: u32,
+    lo_file_name: [u8; 64],
+    lo_crypt_name: [u8; 64],
+    lo_encrypt_key: [u8; 32],
+    lo_init: [u64; 2],
+}
+
+/// Attach image read-only to a free loop device
+fn attach_loop(image: &Path) -> Result<(File, PathBuf)> {
+    let backing = File::open(image)?;
+    let control = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/loop-control")?;
+    // another process may grab the free device first
+    for _ in 0..8 {
+        let n = unsafe { libc::ioctl(control.as_raw_fd(), LOOP_CTL_GET_FREE) };
+        if n < 0 {
+            return Err(Error::last_os_error());
+        }
+        let path = PathBuf::from(format!("/dev/loop{}", n));
+        let device = File::open(&path)?;
+        if unsafe { libc::ioctl(device.as_raw_fd(), LOOP_SET_FD, backing.as_raw_fd()) } < 0 {
+            let err = Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EBUSY) {
+                continue;
+            }
+            return Err(err);
+        }
+        let mut info: LoopInfo64 = unsafe { std::mem::zeroed() };
+        info.lo_flags = LO_FLAGS_AUTOCLEAR;
+        let name = image.as_os_str().as_encoded_bytes();
+        let len = name.len().min(info.lo_file_name.len() - 1);
+        info.lo_file_name[..len].copy_from_slice(&name[..len]);
+        if unsafe {
+            libc::ioctl(
+                device.as_raw_fd(),
+                LOOP_SET_STATUS64,
+                &info as *const LoopInfo64,
+            )
+        } < 0
+        {
+            let err = Error::last_os_error();
+            unsafe { libc::ioctl(device.as_raw_fd(), LOOP_CLR_FD, 0) };
+            return Err(err);
+        }
+        return Ok((device, path));
+    }
+    Err(Error::new(
+        ErrorKind::ResourceBusy,
+        "unable to find a free loop device",
+    ))
+}
+
+/// Check that a binary can be found in PATH
+fn in_path(bin: &str) -> bool {
+    std::env::var_os("PATH")
+        .is_some_and(|x| std::env::split_paths(&x).any(|x| x.join(bin).is_file()))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use temp_testdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn detect_image_kind() {
+        let dir = TempDir::default();
+        let squash = dir.join("squash");
+        std::fs::write(&squash, b"hsqs\0\0\0\0").unwrap();
+        assert_eq!(
+            ImageKind::detect(&squash).unwrap(),
+            Some(ImageKind::SquashFs)
+        );
+        let erofs = dir.join("erofs");
+        let mut content = vec![0u8; 2048];
+        content[1024..1028].copy_from_slice(&EROFS_MAGIC.to_le_bytes());
+        std::fs::write(&erofs, content).unwrap();
+        assert_eq!(ImageKind::detect(&erofs).unwrap(), Some(ImageKind::Erofs));
+        let other = dir.join("other");
+        std::fs::write(&other, b"nothing").unwrap();
+        assert_eq!(ImageKind::detect(&other).unwrap(), None);
+        assert!(ImageMount::new(&other, &dir).is_err());
+    }
+}
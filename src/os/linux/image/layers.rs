@@ -0,0 +1,147 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use std::{
+    fs::create_dir_all,
+    io::Result,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::ImageMount;
+#[cfg(feature = "archive")]
+use crate::os::linux::{ArchiveMount, mountinfo::MountInfo};
+use crate::{Filesystem, StateRecovery};
+
+/// Prefix of the directories holding mounted lower images
+const IMAGE_STAGING: &str = "damascus-image-";
+
+/// Image files and archives given as layers of a stack, mounted in a staging directory so
+/// they can be used in place of directories
+#[derive(Debug, Default)]
+pub(crate) struct ImageLayers {
+    staging: Option<PathBuf>,
+    mounts: Vec<ImageMount>,
+    #[cfg(feature = "archive")]
+    archives: Vec<ArchiveMount>,
+    next: usize,
+}
+
+impl ImageLayers {
+    /// Mount every image found in layers, return the directories to be used instead
+    pub(crate) fn resolve(&mut self, layers: &[PathBuf]) -> Result<Vec<PathBuf>> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let mut resolved = vec![];
+        for layer in layers {
+            if !layer.is_file() {
+                resolved.push(layer.clone());
+                continue;
+            }
+            // reuse images still mounted by a previous attempt
+            if let Some(handle) = self
+                .mounts
+                .iter()
+                .find(|x| x.image() == layer && x.id().is_some())
+            {
+                resolved.push(handle.target());
+                continue;
+            }
+            #[cfg(feature = "archive")]
+            if let Some(handle) = self
+                .archives
+                .iter()
+                .find(|x| x.archive() == layer && x.id().is_some())
+            {
+                resolved.push(handle.target());
+                continue;
+            }
+            let staging = match &self.staging {
+                Some(x) => x.clone(),
+                None => {
+                    let staging = std::env::temp_dir().join(format!(
+                        "{}{}-{}",
+                        IMAGE_STAGING,
+                        std::process::id(),
+                        COUNT.fetch_add(1, Ordering::Relaxed)
+                    ));
+                    create_dir_all(&staging)?;
+                    self.staging = Some(staging.clone());
+                    staging
+                }
+            };
+            let dst = staging.join(format!("layer{}", self.next));
+            self.next += 1;
+            create_dir_all(&dst)?;
+            #[cfg(feature = "archive")]
+            if ArchiveMount::is_archive(layer)? {
+                let mut handle = ArchiveMount::new(layer, &dst);
+                handle.mount()?;
+                self.archives.push(handle);
+                resolved.push(dst);
+                continue;
+            }
+            let mut handle = ImageMount::new(layer, &dst)?;
+            handle.mount()?;
+            self.mounts.push(handle);
+            resolved.push(dst);
+        }
+        Ok(resolved)
+    }
+
+    /// Replace layers mounted from an image by the image itself
+    pub(crate) fn recover(&mut self, layers: &mut [PathBuf]) -> Result<()> {
+        for layer in layers.iter_mut() {
+            let Some(staging) = layer.parent().filter(|x| {
+                x.file_name()
+                    .is_some_and(|x| x.to_string_lossy().starts_with(IMAGE_STAGING))
+            }) else {
+                continue;
+            };
+            self.staging = Some(staging.to_path_buf());
+            // archives are served by the process which mounted them and cannot be taken over
+            #[cfg(feature = "archive")]
+            if let Some(info) = MountInfo::find(layer)?
+                && info.fs_type.starts_with("fuse")
+                && ArchiveMount::is_archive(&info.source).unwrap_or(false)
+            {
+                *layer = PathBuf::from(info.source);
+                continue;
+            }
+            let handle = ImageMount::recover(&layer)?;
+            *layer = handle.image().to_path_buf();
+            self.mounts.push(handle);
+        }
+        Ok(())
+    }
+
+    /// Unmount every image then remove the staging directory
+    pub(crate) fn release(&mut self) -> Result<()> {
+        while let Some(mut handle) = self.mounts.pop() {
+            handle.unmount()?;
+            std::fs::remove_dir(handle.target())?;
+        }
+        #[cfg(feature = "archive")]
+        while let Some(mut handle) = self.archives.pop() {
+            handle.unmount()?;
+            std::fs::remove_dir(handle.target())?;
+        }
+        if let Some(staging) = self.staging.take() {
+            std::fs::remove_dir(&staging)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn set_unmount_on_drop(&mut self, drop: bool) {
+        for handle in self.mounts.iter_mut() {
+            handle.set_unmount_on_drop(drop);
+        }
+        #[cfg(feature = "archive")]
+        for handle in self.archives.iter_mut() {
+            handle.set_unmount_on_drop(drop);
+        }
+    }
+}
@@ -0,0 +1,420 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use std::{
+    collections::HashMap,
+    fs::{self, File, Metadata},
+    io::{self, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileTypeExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use nix::sys::stat::{major, minor};
+use tracing::debug;
+
+use super::tmpfs::read_xattrs;
+
+const EROFS_MAGIC: u32 = 0xE0F5E1E2;
+const EROFS_SUPER_OFFSET: u64 = 1024;
+const BLOCK_BITS: u8 = 12;
+const BLOCK_SIZE: u64 = 1 << BLOCK_BITS;
+/// Metadata area start on the block following the superblock
+const META_BLKADDR: u32 = 1;
+/// Inodes are addressed by 32 bytes slots from the start of the metadata area
+const INODE_SLOT: u64 = 32;
+const INODE_EXTENDED_SIZE: u64 = 64;
+/// Extended inode version bit, data is laid out in plain consecutive blocks
+const INODE_FORMAT: u16 = 1;
+const DIRENT_SIZE: usize = 12;
+const XATTR_HEADER_SIZE: usize = 12;
+
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_CHRDEV: u8 = 3;
+const FT_BLKDEV: u8 = 4;
+const FT_FIFO: u8 = 5;
+const FT_SOCK: u8 = 6;
+const FT_SYMLINK: u8 = 7;
+
+/// Extended attribute namespaces known by the kernel driver and their index
+const XATTR_PREFIXES: &[(&[u8], u8)] = &[
+    (b"user.", 1),
+    (b"system.posix_acl_access", 2),
+    (b"system.posix_acl_default", 3),
+    (b"trusted.", 4),
+    (b"security.", 6),
+];
+
+#[derive(Debug, Clone)]
+/// Compact a directory into an uncompressed EROFS image usable as a lower layer,
+/// permissions, ownership, extended attributes, symlinks and hardlinks are preserved
+pub struct ImageWriter {
+    source: PathBuf,
+}
+
+impl ImageWriter {
+    #[must_use = "initialised ImageWriter should be used"]
+    #[inline]
+    pub fn new(source: impl AsRef<Path>) -> Self {
+        Self {
+            source: source.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Directory compacted in the image
+    #[inline]
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    /// Write the image to dest, replacing any existing file
+    pub fn write(&self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        if !fs::symlink_metadata(&self.source)?.is_dir() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "image source must be a directory",
+            ));
+        }
+        let mut tree = Tree::default();
+        tree.scan(&self.source, 0)?;
+        let blocks = tree.layout()?;
+        debug!(
+            "Damascus: writing EROFS image {:?} with {} inodes in {} blocks",
+            dest,
+            tree.nodes.len(),
+            blocks
+        );
+        let res = tree.write(dest, blocks);
+        if res.is_err() {
+            let _ = fs::remove_file(dest);
+        }
+        res
+    }
+}
+
+#[derive(Debug)]
+enum Content {
+    /// Entries name and node index, parent index for ".."
+    Dir(Vec<(Vec<u8>, usize)>, usize),
+    File(PathBuf),
+    Symlink,
+    Special,
+}
+
+#[derive(Debug)]
+struct Node {
+    meta: Metadata,
+    content: Content,
+    xattrs: Vec<u8>,
+    nlink: u32,
+    nid: u64,
+    blkaddr: u32,
+    data: Vec<u8>,
+}
+
+impl Node {
+    fn file_type(&self) -> u8 {
+        let ty = self.meta.file_type();
+        if ty.is_dir() {
+            FT_DIR
+        } else if ty.is_symlink() {
+            FT_SYMLINK
+        } else if ty.is_char_device() {
+            FT_CHRDEV
+        } else if ty.is_block_device() {
+            FT_BLKDEV
+        } else if ty.is_fifo() {
+            FT_FIFO
+        } else if ty.is_socket() {
+            FT_SOCK
+        } else {
+            FT_REG_FILE
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match &self.content {
+            Content::File(_) => self.meta.len(),
+            Content::Dir(..) | Content::Symlink => self.data.len() as u64,
+            Content::Special => 0,
+        }
+    }
+
+    fn encode(&self, ino: u32) -> [u8; INODE_EXTENDED_SIZE as usize] {
+        let mut raw = [0u8; INODE_EXTENDED_SIZE as usize];
+        let icount = if self.xattrs.is_empty() {
+            0
+        } else {
+            ((self.xattrs.len() - XATTR_HEADER_SIZE) / 4 + 1) as u16
+        };
+        let addr = match self.content {
+            Content::Special => {
+                let rdev = self.meta.rdev();
+                let (major, minor) = (major(rdev) as u32, minor(rdev) as u32);
+                (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+            }
+            _ => self.blkaddr,
+        };
+        raw[0..2].copy_from_slice(&INODE_FORMAT.to_le_bytes());
+        raw[2..4].copy_from_slice(&icount.to_le_bytes());
+        raw[4..6].copy_from_slice(&(self.meta.mode() as u16).to_le_bytes());
+        raw[8..16].copy_from_slice(&self.size().to_le_bytes());
+        raw[16..20].copy_from_slice(&addr.to_le_bytes());
+        raw[20..24].copy_from_slice(&ino.to_le_bytes());
+        raw[24..28].copy_from_slice(&self.meta.uid().to_le_bytes());
+        raw[28..32].copy_from_slice(&self.meta.gid().to_le_bytes());
+        raw[32..40].copy_from_slice(&(self.meta.mtime() as u64).to_le_bytes());
+        raw[40..44].copy_from_slice(&(self.meta.mtime_nsec() as u32).to_le_bytes());
+        raw[44..48].copy_from_slice(&self.nlink.to_le_bytes());
+        raw
+    }
+}
+
+#[derive(Debug, Default)]
+struct Tree {
+    nodes: Vec<Node>,
+    /// Already visited hardlinked inodes
+    links: HashMap<(u64, u64), usize>,
+}
+
+impl Tree {
+    /// Collect the node at path and its descendants, return its index
+    fn scan(&mut self, path: &Path, parent: usize) -> Result<usize> {
+        let meta = fs::symlink_metadata(path)?;
+        let ty = meta.file_type();
+        if !ty.is_dir()
+            && meta.nlink() > 1
+            && let Some(idx) = self.links.get(&(meta.dev(), meta.ino()))
+        {
+            self.nodes[*idx].nlink += 1;
+            return Ok(*idx);
+        }
+        let xattrs = encode_xattrs(path)?;
+        if xattrs.len() as u64 + INODE_EXTENDED_SIZE > BLOCK_SIZE {
+            return Err(Error::other(format!(
+                "extended attributes of {:?} do not fit in an image block",
+                path
+            )));
+        }
+        let idx = self.nodes.len();
+        let (content, data) = if ty.is_dir() {
+            (Content::Dir(vec![], parent), vec![])
+        } else if ty.is_symlink() {
+            (
+                Content::Symlink,
+                fs::read_link(path)?.as_os_str().as_bytes().to_vec(),
+            )
+        } else if ty.is_file() {
+            (Content::File(path.to_path_buf()), vec![])
+        } else {
+            (Content::Special, vec![])
+        };
+        self.nodes.push(Node {
+            meta,
+            content,
+            xattrs,
+            nlink: 1,
+            nid: 0,
+            blkaddr: 0,
+            data,
+        });
+        if ty.is_dir() {
+            let mut entries = vec![];
+            let mut subdirs = 0;
+            for entry in fs::read_dir(path)? {
+                let entry = entry?;
+                let child = self.scan(&entry.path(), idx)?;
+                if matches!(self.nodes[child].content, Content::Dir(..)) {
+                    subdirs += 1;
+                }
+                entries.push((entry.file_name().as_bytes().to_vec(), child));
+            }
+            self.nodes[idx].nlink = 2 + subdirs;
+            self.nodes[idx].content = Content::Dir(entries, parent);
+        } else if self.nodes[idx].meta.nlink() > 1 {
+            let meta = &self.nodes[idx].meta;
+            self.links.insert((meta.dev(), meta.ino()), idx);
+        }
+        Ok(idx)
+    }
+
+    /// Place inodes then data, return the number of blocks of the image
+    fn layout(&mut self) -> Result<u64> {
+        let mut pos = 0u64;
+        for node in self.nodes.iter_mut() {
+            let size = INODE_EXTENDED_SIZE + node.xattrs.len() as u64;
+            if pos % BLOCK_SIZE + size > BLOCK_SIZE {
+                pos = pos.next_multiple_of(BLOCK_SIZE);
+            }
+            node.nid = pos / INODE_SLOT;
+            pos = (pos + size).next_multiple_of(INODE_SLOT);
+        }
+        for idx in 0..self.nodes.len() {
+            if let Content::Dir(entries, parent) = &self.nodes[idx].content {
+                let mut dirents = vec![
+                    (b".".to_vec(), self.nodes[idx].nid, FT_DIR),
+                    (b"..".to_vec(), self.nodes[*parent].nid, FT_DIR),
+                ];
+                dirents.extend(entries.iter().map(|(name, child)| {
+                    let child = &self.nodes[*child];
+                    (name.clone(), child.nid, child.file_type())
+                }));
+                dirents.sort_by(|a, b| a.0.cmp(&b.0));
+                self.nodes[idx].data = encode_dir(&dirents);
+            }
+        }
+        let mut blkaddr = META_BLKADDR as u64 + pos.div_ceil(BLOCK_SIZE).max(1);
+        for node in self.nodes.iter_mut() {
+            let size = node.size();
+            if size == 0 || matches!(node.content, Content::Special) {
+                continue;
+            }
+            node.blkaddr = u32::try_from(blkaddr)
+                .map_err(|_| Error::other("image exceed the maximum number of blocks"))?;
+            blkaddr += size.div_ceil(BLOCK_SIZE);
+        }
+        Ok(blkaddr)
+    }
+
+    fn write(&self, dest: &Path, blocks: u64) -> Result<()> {
+        let mut out = File::create(dest)?;
+        let mut sb = [0u8; 128];
+        sb[0..4].copy_from_slice(&EROFS_MAGIC.to_le_bytes());
+        sb[12] = BLOCK_BITS;
+        sb[14..16].copy_from_slice(&(self.nodes[0].nid as u16).to_le_bytes());
+        sb[16..24].copy_from_slice(&(self.nodes.len() as u64).to_le_bytes());
+        sb[36..40].copy_from_slice(&(blocks as u32).to_le_bytes());
+        sb[40..44].copy_from_slice(&META_BLKADDR.to_le_bytes());
+        out.seek(SeekFrom::Start(EROFS_SUPER_OFFSET))?;
+        out.write_all(&sb)?;
+
+        let meta_start = META_BLKADDR as u64 * BLOCK_SIZE;
+        for (ino, node) in self.nodes.iter().enumerate() {
+            out.seek(SeekFrom::Start(meta_start + node.nid * INODE_SLOT))?;
+            out.write_all(&node.encode(ino as u32 + 1))?;
+            out.write_all(&node.xattrs)?;
+        }
+        for node in self.nodes.iter() {
+            if node.size() == 0 || matches!(node.content, Content::Special) {
+                continue;
+            }
+            out.seek(SeekFrom::Start(node.blkaddr as u64 * BLOCK_SIZE))?;
+            if let Content::File(path) = &node.content {
+                let size = node.size();
+                let copied = io::copy(&mut File::open(path)?.take(size), &mut out)?;
+                if copied != size {
+                    return Err(Error::other(format!(
+                        "{:?} changed while writing the image",
+                        path
+                    )));
+                }
+            } else {
+                out.write_all(&node.data)?;
+            }
+        }
+        out.set_len(blocks * BLOCK_SIZE)?;
+        out.sync_all()
+    }
+}
+
+/// Encode the inline extended attributes area, unknown namespaces are skipped
+fn encode_xattrs(path: &Path) -> Result<Vec<u8>> {
+    let mut body = vec![];
+    for (name, value) in read_xattrs(path)? {
+        let name = name.as_bytes();
+        let Some((suffix, index)) = XATTR_PREFIXES
+            .iter()
+            .find_map(|(prefix, index)| name.strip_prefix(*prefix).map(|x| (x, *index)))
+        else {
+            debug!(
+                "Damascus: skipping unsupported extended attribute {} of {:?}",
+                String::from_utf8_lossy(name),
+                path
+            );
+            continue;
+        };
+        let (Ok(name_len), Ok(value_len)) =
+            (u8::try_from(suffix.len()), u16::try_from(value.len()))
+        else {
+            return Err(Error::other(format!(
+                "extended attribute of {:?} is too large",
+                path
+            )));
+        };
+        body.push(name_len);
+        body.push(index);
+        body.extend_from_slice(&value_len.to_le_bytes());
+        body.extend_from_slice(suffix);
+        body.extend_from_slice(&value);
+        body.resize(body.len().next_multiple_of(4), 0);
+    }
+    if body.is_empty() {
+        return Ok(body);
+    }
+    let mut area = vec![0u8; XATTR_HEADER_SIZE];
+    area.extend(body);
+    Ok(area)
+}
+
+/// Pack sorted directory entries in blocks, dirents first then their names
+fn encode_dir(entries: &[(Vec<u8>, u64, u8)]) -> Vec<u8> {
+    let mut data = vec![];
+    let mut rest = entries;
+    while !rest.is_empty() {
+        let mut used = 0;
+        let count = rest
+            .iter()
+            .take_while(|(name, ..)| {
+                used += DIRENT_SIZE + name.len();
+                used <= BLOCK_SIZE as usize
+            })
+            .count();
+        let (block, next) = rest.split_at(count);
+        let start = data.len();
+        let mut nameoff = count * DIRENT_SIZE;
+        for (name, nid, ty) in block {
+            data.extend_from_slice(&nid.to_le_bytes());
+            data.extend_from_slice(&(nameoff as u16).to_le_bytes());
+            data.push(*ty);
+            data.push(0);
+            nameoff += name.len();
+        }
+        for (name, ..) in block {
+            data.extend_from_slice(name);
+        }
+        if !next.is_empty() {
+            data.resize(start + BLOCK_SIZE as usize, 0);
+        }
+        rest = next;
+    }
+    data
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_directory_blocks() {
+        let entries: Vec<_> = (0..400)
+            .map(|x| (format!("entry{:04}", x).into_bytes(), x as u64, FT_REG_FILE))
+            .collect();
+        let data = encode_dir(&entries);
+        // 195 entries of 21 bytes fit in a block
+        assert_eq!(data.len(), 2 * BLOCK_SIZE as usize + 10 * 21);
+        let block = &data[BLOCK_SIZE as usize..];
+        assert_eq!(u64::from_le_bytes(block[0..8].try_into().unwrap()), 195);
+        let nameoff = u16::from_le_bytes(block[8..10].try_into().unwrap()) as usize;
+        assert_eq!(nameoff, 195 * DIRENT_SIZE);
+        assert_eq!(&block[nameoff..nameoff + 9], b"entry0195");
+    }
+}
@@ -0,0 +1,380 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fmt::{Display, Write as _},
+    fs::{self, File},
+    io::{Error, ErrorKind, Result},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use tracing::debug;
+
+/// First line of a serialized manifest
+const MANIFEST_HEADER: &str = "damascus-manifest v1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// Recorded state of a single layer entry
+pub struct ManifestEntry {
+    /// Size in bytes, length of the target for symlinks
+    pub size: u64,
+    /// File type and permission bits
+    pub mode: u32,
+    /// BLAKE3 hash of the content or of the symlink target, none for other file types
+    pub hash: Option<[u8; 32]>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// Content of a layer, keyed by path relative to the layer root
+pub struct Manifest {
+    entries: BTreeMap<PathBuf, ManifestEntry>,
+}
+
+impl Manifest {
+    /// Walk the layer and hash every regular file and symlink
+    pub fn compute(layer: impl AsRef<Path>) -> Result<Self> {
+        let layer = layer.as_ref();
+        if !fs::metadata(layer)?.is_dir() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "manifest can only be computed for directory layers",
+            ));
+        }
+        let mut manifest = Manifest::default();
+        manifest.walk(layer, Path::new(""))?;
+        debug!(
+            "Damascus: computed manifest of {:?} with {} entries",
+            layer,
+            manifest.entries.len()
+        );
+        Ok(manifest)
+    }
+
+    #[inline]
+    pub fn entries(&self) -> &BTreeMap<PathBuf, ManifestEntry> {
+        &self.entries
+    }
+
+    /// Compare the current content of the layer against this manifest
+    pub fn verify(&self, layer: impl AsRef<Path>) -> Result<IntegrityReport> {
+        Ok(self.diff(&Manifest::compute(layer)?))
+    }
+
+    /// List what changed from this manifest to other
+    pub fn diff(&self, other: &Manifest) -> IntegrityReport {
+        let mut report = IntegrityReport::default();
+        for (path, entry) in self.entries.iter() {
+            match other.entries.get(path) {
+                None => report.missing.push(path.clone()),
+                Some(x) if x != entry => report.modified.push(path.clone()),
+                _ => {}
+            }
+        }
+        report.extra = other
+            .entries
+            .keys()
+            .filter(|x| !self.entries.contains_key(*x))
+            .cloned()
+            .collect();
+        report
+    }
+
+    /// Read a manifest previously written by [`Manifest::save`]
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let raw = fs::read_to_string(path)?;
+        let mut lines = raw.lines();
+        if lines.next() != Some(MANIFEST_HEADER) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "not a damascus manifest",
+            ));
+        }
+        let mut manifest = Manifest::default();
+        for line in lines.filter(|x| !x.is_empty()) {
+            let invalid = || Error::new(ErrorKind::InvalidData, format!("invalid line {}", line));
+            let mut fields = line.splitn(4, ' ');
+            let (Some(hash), Some(size), Some(mode), Some(path)) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid());
+            };
+            let hash = match hash {
+                "-" => None,
+                x => Some(*blake3::Hash::from_hex(x).map_err(|_| invalid())?.as_bytes()),
+            };
+            manifest.entries.insert(
+                unescape(path),
+                ManifestEntry {
+                    size: size.parse().map_err(|_| invalid())?,
+                    mode: u32::from_str_radix(mode, 8).map_err(|_| invalid())?,
+                    hash,
+                },
+            );
+        }
+        Ok(manifest)
+    }
+
+    /// Write the manifest as text, one entry per line
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    fn walk(&mut self, root: &Path, rel: &Path) -> Result<()> {
+        for entry in fs::read_dir(root.join(rel))? {
+            let entry = entry?;
+            let rel = rel.join(entry.file_name());
+            let path = entry.path();
+            let meta = fs::symlink_metadata(&path)?;
+            let ty = meta.file_type();
+            let (size, hash) = if ty.is_file() {
+                let mut hasher = blake3::Hasher::new();
+                hasher.update_reader(File::open(&path)?)?;
+                (meta.len(), Some(*hasher.finalize().as_bytes()))
+            } else if ty.is_symlink() {
+                let target = fs::read_link(&path)?;
+                let target = target.as_os_str().as_bytes();
+                (target.len() as u64, Some(*blake3::hash(target).as_bytes()))
+            } else {
+                (0, None)
+            };
+            self.entries.insert(
+                rel.clone(),
+                ManifestEntry {
+                    size,
+                    mode: meta.mode(),
+                    hash,
+                },
+            );
+            if ty.is_dir() {
+                self.walk(root, &rel)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for Manifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", MANIFEST_HEADER)?;
+        for (path, entry) in self.entries.iter() {
+            let hash = entry.hash.map_or("-".to_owned(), |x| {
+                blake3::Hash::from(x).to_hex().to_string()
+            });
+            writeln!(
+                f,
+                "{} {} {:o} {}",
+                hash,
+                entry.size,
+                entry.mode,
+                escape(path)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// Difference between a manifest and the current content of a layer
+pub struct IntegrityReport {
+    /// Entries of the manifest no longer present
+    pub missing: Vec<PathBuf>,
+    /// Entries present but not in the manifest
+    pub extra: Vec<PathBuf>,
+    /// Entries whose size, mode or content changed
+    pub modified: Vec<PathBuf>,
+}
+
+impl IntegrityReport {
+    /// Layer match its manifest
+    #[inline]
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.modified.is_empty()
+    }
+}
+
+impl Display for IntegrityReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let entries = [
+            ("missing", &self.missing),
+            ("extra", &self.extra),
+            ("modified", &self.modified),
+        ];
+        let mut first = true;
+        for (kind, paths) in entries {
+            for path in paths {
+                if !first {
+                    writeln!(f)?;
+                }
+                first = false;
+                write!(f, "{} {:?}", kind, path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Set or remove the manifest checked for layer, which must be a directory of layers
+#[cfg(any(feature = "overlayfs", feature = "fuse-overlayfs"))]
+pub(crate) fn set_manifest<'a>(
+    manifests: &mut Vec<(PathBuf, Manifest)>,
+    mut layers: impl Iterator<Item = &'a PathBuf>,
+    layer: &Path,
+    manifest: Option<Manifest>,
+) -> Result<()> {
+    if !layers.any(|x| x == layer) {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            "layer is not part of the stack",
+        ));
+    }
+    if manifest.is_some() && !fs::metadata(layer)?.is_dir() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "manifest can only be checked for directory layers",
+        ));
+    }
+    manifests.retain(|(x, _)| x != layer);
+    if let Some(manifest) = manifest {
+        manifests.push((layer.to_path_buf(), manifest));
+    }
+    Ok(())
+}
+
+/// Forget manifests of layers no longer part of the stack
+#[cfg(any(feature = "overlayfs", feature = "fuse-overlayfs"))]
+pub(crate) fn prune_manifests<'a>(
+    manifests: &mut Vec<(PathBuf, Manifest)>,
+    layers: impl Iterator<Item = &'a PathBuf> + Clone,
+) {
+    manifests.retain(|(x, _)| layers.clone().any(|y| x == y));
+}
+
+/// Refuse layers which drifted from their manifest
+#[cfg(any(feature = "overlayfs", feature = "fuse-overlayfs"))]
+pub(crate) fn check_layers(manifests: &[(PathBuf, Manifest)]) -> Result<()> {
+    for (layer, manifest) in manifests {
+        let report = manifest.verify(layer)?;
+        if !report.is_clean() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("layer {:?} drifted from its manifest :\n{}", layer, report),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Octal escape whitespace, control characters and backslash so a path fit on a line
+fn escape(path: &Path) -> String {
+    let mut res = String::new();
+    for b in path.as_os_str().as_bytes() {
+        if *b <= b' ' || *b == b'\\' || *b >= 0x7f {
+            let _ = write!(res, "\\{:03o}", b);
+        } else {
+            res.push(*b as char);
+        }
+    }
+    res
+}
+
+fn unescape(path: &str) -> PathBuf {
+    let mut res = vec![];
+    let bytes = path.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\'
+            && let Some(c) = path
+                .get(i + 1..i + 4)
+                .and_then(|x| u8::from_str_radix(x, 8).ok())
+        {
+            res.push(c);
+            i += 4;
+        } else {
+            res.push(bytes[i]);
+            i += 1;
+        }
+    }
+    PathBuf::from(OsStr::from_bytes(&res))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+    use temp_testdir::TempDir;
+
+    #[test]
+    fn manifest_roundtrip_and_drift() {
+        let dir = TempDir::default();
+        let root = dir.join("layer");
+        fs::create_dir_all(root.join("dir with space")).unwrap();
+        fs::write(root.join("dir with space/a\\b"), b"content").unwrap();
+        fs::write(root.join("kept"), b"kept").unwrap();
+        fs::write(root.join("removed"), b"removed").unwrap();
+        symlink("kept", root.join("link")).unwrap();
+
+        let manifest = Manifest::compute(&root).unwrap();
+        assert_eq!(manifest.entries().len(), 5);
+        let saved = root.with_extension("manifest");
+        manifest.save(&saved).unwrap();
+        assert_eq!(Manifest::load(&saved).unwrap(), manifest);
+        assert!(manifest.verify(&root).unwrap().is_clean());
+
+        fs::write(root.join("kept"), b"kepT").unwrap();
+        fs::remove_file(root.join("removed")).unwrap();
+        fs::write(root.join("added"), b"added").unwrap();
+        let report = manifest.verify(&root).unwrap();
+        assert_eq!(report.missing, vec![PathBuf::from("removed")]);
+        assert_eq!(report.extra, vec![PathBuf::from("added")]);
+        assert_eq!(report.modified, vec![PathBuf::from("kept")]);
+        #[cfg(any(feature = "overlayfs", feature = "fuse-overlayfs"))]
+        assert!(check_layers(&[(root.clone(), manifest)]).is_err());
+    }
+
+    #[cfg(any(feature = "overlayfs", feature = "fuse-overlayfs"))]
+    #[test]
+    fn manifest_layers() {
+        let dir = TempDir::default();
+        let layer = dir.join("layer");
+        let image = dir.join("layer.img");
+        fs::create_dir_all(&layer).unwrap();
+        fs::write(&image, b"").unwrap();
+        let layers = [layer.clone(), image.clone()];
+        let mut manifests = vec![];
+        let manifest = Manifest::compute(&layer).unwrap();
+        assert!(
+            set_manifest(
+                &mut manifests,
+                layers.iter(),
+                &dir.join("other"),
+                Some(manifest.clone())
+            )
+            .is_err()
+        );
+        assert!(
+            set_manifest(
+                &mut manifests,
+                layers.iter(),
+                &image,
+                Some(manifest.clone())
+            )
+            .is_err()
+        );
+        set_manifest(&mut manifests, layers.iter(), &layer, Some(manifest)).unwrap();
+        assert_eq!(manifests.len(), 1);
+        prune_manifests(&mut manifests, layers[1..].iter());
+        assert!(manifests.is_empty());
+    }
+}
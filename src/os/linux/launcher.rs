@@ -0,0 +1,334 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use std::{
+    collections::BTreeMap,
+    ffi::{CString, OsString},
+    io::{Error, ErrorKind, Read, Result, Write},
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        process::ExitStatusExt,
+    },
+    path::PathBuf,
+    process::{Command, ExitStatus},
+    ptr,
+};
+
+use nix::{
+    errno::Errno,
+    libc,
+    mount::{MsFlags, mount},
+    sched::{CloneFlags, clone},
+    sys::wait::{WaitPidFlag, WaitStatus, waitpid},
+    unistd::{Pid, chdir, getegid, geteuid},
+};
+use tracing::error;
+
+use super::namespace::Maps;
+use crate::{Filesystem, IdRange, OverlayFs, Stack, UserNamespace};
+
+/// Stack reserved for the cloned child until it exec the command
+const STACK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Launch a process that is the only one to see an overlay mounted
+///
+/// The process is started inside fresh user, mount and pid namespaces where the overlay is
+/// mounted, the command then run as the current user. Once it exit every process left in the
+/// namespace is killed by the kernel and the mount vanish with it, even after a crash.
+///
+/// Only kernel overlay is supported, everything the mount need is rendered before cloning
+/// since the child cannot safely allocate, log or spawn a FUSE daemon
+///
+/// NOTE : the command is the init process of its pid namespace, signals it doesn't handle are
+/// ignored except SIGKILL
+pub struct Launcher {
+    fs: OverlayFs,
+    namespace: UserNamespace,
+    over: Option<PathBuf>,
+    env_clear: bool,
+    env: BTreeMap<OsString, OsString>,
+}
+
+impl Launcher {
+    /// Initialise a Launcher for a stack, FUSE backends are refused since their daemon
+    /// cannot be started from the namespace
+    pub fn new(fs: impl Into<Stack>) -> Result<Self> {
+        match fs.into() {
+            Stack::OverlayFs(fs) => Ok(Self {
+                fs,
+                namespace: UserNamespace::new(),
+                over: None,
+                env_clear: false,
+                env: BTreeMap::new(),
+            }),
+            #[cfg(any(feature = "fuse-overlayfs", feature = "unionfs-fuse"))]
+            fs => Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("{} cannot be mounted by a Launcher", fs.backend()),
+            )),
+        }
+    }
+
+    /// Use custom id mappings while mounting
+    #[inline]
+    pub fn namespace(mut self, namespace: UserNamespace) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    /// Mount the overlay on top of a directory instead of its own target,
+    /// usually the one the command expect its files in
+    #[inline]
+    pub fn over(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.over = Some(dir.into());
+        self
+    }
+
+    /// Start commands with an empty environment, variables set on the command or through
+    /// env are still passed
+    ///
+    /// NOTE : Command::env_clear cannot be seen from a Launcher, use this instead
+    #[inline]
+    pub fn env_clear(mut self) -> Self {
+        self.env_clear = true;
+        self.env.clear();
+        self
+    }
+
+    /// Set an environment variable for every command
+    #[inline]
+    pub fn env(mut self, key: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+        self.env.insert(key.into(), value.into());
+        self
+    }
+
+    #[inline]
+    pub fn filesystem(&self) -> &OverlayFs {
+        &self.fs
+    }
+
+    /// Spawn command with its arguments, environment and working directory
+    pub fn spawn(&mut self, command: &Command) -> Result<Launched> {
+        if let Some(over) = &self.over {
+            self.fs.set_target(over)?;
+        }
+        let setup = self.namespace.prepare(&mut self.fs)?;
+        let (uid, gid) = (geteuid().as_raw(), getegid().as_raw());
+        let back = match (
+            self.namespace.uid_map().inside(uid),
+            self.namespace.gid_map().inside(gid),
+        ) {
+            (Some(u), Some(g)) => Maps::single(IdRange::new(uid, u, 1), IdRange::new(gid, g, 1)),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "user namespace mapping doesn't include the current user",
+                ));
+            }
+        };
+        let exec = Exec::new(command, self.env_clear, &self.env)?;
+        let (mut reader, mut writer) = std::io::pipe()?;
+        let mut stack = vec![0u8; STACK_SIZE];
+        // NOTE : only raw system calls from here, the child run on a copy of the caller memory
+        // where locks may be held by threads which don't exist in it
+        let child = Box::new(|| {
+            let res = (|| -> Result<()> {
+                setup.maps.write()?;
+                setup.mount()?;
+                // show the processes of the new pid namespace instead of the caller ones
+                mount(
+                    Some(c"proc"),
+                    c"/proc",
+                    Some(c"proc"),
+                    MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+                    None::<&std::ffi::CStr>,
+                )?;
+                // leave root privilege and go back to the original uid
+                back.unshare(CloneFlags::empty())?;
+                exec.exec()
+            })();
+            let errno = res
+                .err()
+                .and_then(|e| e.raw_os_error())
+                .unwrap_or(libc::EINVAL);
+            let _ = writer.write_all(&errno.to_ne_bytes());
+            unsafe { libc::_exit(127) }
+        });
+        let pid = unsafe {
+            clone(
+                child,
+                &mut stack,
+                CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWPID,
+                Some(libc::SIGCHLD),
+            )
+        }?;
+        drop(writer);
+        let mut errno = [0u8; 4];
+        if reader.read_exact(&mut errno).is_ok() {
+            let _ = waitpid(pid, None);
+            return Err(Error::from_raw_os_error(i32::from_ne_bytes(errno)));
+        }
+        Ok(Launched { pid, status: None })
+    }
+}
+
+/// Process started by a Launcher
+///
+/// Dropping the handle of a running process kills it then reap it, wait for it first to let
+/// it run to completion
+#[derive(Debug)]
+pub struct Launched {
+    pid: Pid,
+    status: Option<ExitStatus>,
+}
+impl Launched {
+    /// Pid of the process as seen from the caller
+    #[inline]
+    pub fn id(&self) -> u32 {
+        self.pid.as_raw() as u32
+    }
+
+    /// Wait for the process to exit
+    pub fn wait(&mut self) -> Result<ExitStatus> {
+        if let Some(status) = self.status {
+            return Ok(status);
+        }
+        self.reap(None)?
+            .ok_or(Error::other("child process ended unexpectedly"))
+    }
+
+    /// Check if the process exited without blocking
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        if self.status.is_some() {
+            return Ok(self.status);
+        }
+        self.reap(Some(WaitPidFlag::WNOHANG))
+    }
+
+    /// Forcefully kill the process along with everything left in its namespace
+    pub fn kill(&mut self) -> Result<()> {
+        if self.status.is_some() {
+            return Ok(());
+        }
+        if unsafe { libc::kill(self.pid.as_raw(), libc::SIGKILL) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn reap(&mut self, flags: Option<WaitPidFlag>) -> Result<Option<ExitStatus>> {
+        let status = loop {
+            match waitpid(self.pid, flags) {
+                Err(Errno::EINTR) => continue,
+                res => break res?,
+            }
+        };
+        self.status = match status {
+            WaitStatus::Exited(_, code) => Some(ExitStatus::from_raw((code & 0xff) << 8)),
+            WaitStatus::Signaled(_, signal, _) => Some(ExitStatus::from_raw(signal as i32)),
+            _ => None,
+        };
+        Ok(self.status)
+    }
+}
+
+impl Drop for Launched {
+    fn drop(&mut self) {
+        if let Err(err) = self
+            .try_wait()
+            .and_then(|x| match x {
+                Some(_) => Ok(()),
+                None => self.kill(),
+            })
+            .and_then(|_| self.wait().map(|_| ()))
+        {
+            error!(
+                "Damascus: unable to reap launched process {} because : {}",
+                self.pid, err
+            )
+        }
+    }
+}
+
+/// Command rendered ahead of time so nothing is allocated once cloned
+struct Exec {
+    program: CString,
+    dir: Option<CString>,
+    /// Own the strings argv and envp point to
+    _args: Vec<CString>,
+    _env: Vec<CString>,
+    /// Null terminated pointer arrays as expected by execvpe
+    argv: Vec<*const libc::c_char>,
+    envp: Vec<*const libc::c_char>,
+}
+
+impl Exec {
+    fn exec(&self) -> Result<()> {
+        if let Some(dir) = &self.dir {
+            chdir(dir.as_c_str())?;
+        }
+        unsafe {
+            libc::execvpe(
+                self.program.as_ptr(),
+                self.argv.as_ptr(),
+                self.envp.as_ptr(),
+            )
+        };
+        Err(Error::last_os_error())
+    }
+}
+
+impl Exec {
+    /// Render command, its environment start empty when clear is set then env is applied
+    fn new(value: &Command, clear: bool, env: &BTreeMap<OsString, OsString>) -> Result<Self> {
+        let cstr = |x: &[u8]| {
+            CString::new(x).map_err(|_| Error::new(ErrorKind::InvalidInput, "nul byte in command"))
+        };
+        let program = cstr(value.get_program().as_bytes())?;
+        let mut args = vec![program.clone()];
+        for arg in value.get_args() {
+            args.push(cstr(arg.as_bytes())?);
+        }
+        let mut vars: BTreeMap<OsString, OsString> = if clear {
+            BTreeMap::new()
+        } else {
+            std::env::vars_os().collect()
+        };
+        vars.extend(env.iter().map(|(k, v)| (k.clone(), v.clone())));
+        for (k, v) in value.get_envs() {
+            match v {
+                Some(v) => vars.insert(k.to_os_string(), v.to_os_string()),
+                None => vars.remove(k),
+            };
+        }
+        let mut env = vec![];
+        for (k, v) in vars {
+            let mut var = k.into_vec();
+            var.push(b'=');
+            var.extend(v.into_vec());
+            env.push(cstr(&var)?);
+        }
+        let dir = value
+            .get_current_dir()
+            .map(|x| cstr(x.as_os_str().as_bytes()))
+            .transpose()?;
+        let pointers = |x: &[CString]| {
+            x.iter()
+                .map(|x| x.as_ptr())
+                .chain([ptr::null()])
+                .collect::<Vec<_>>()
+        };
+        Ok(Self {
+            argv: pointers(&args),
+            envp: pointers(&env),
+            program,
+            dir,
+            _args: args,
+            _env: env,
+        })
+    }
+}
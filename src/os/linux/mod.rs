@@ -5,6 +5,64 @@
 //     http://www.apache.org/licenses/LICENSE-2.0
 //
 // Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+#[cfg(feature = "archive")]
+mod archive;
+mod bind;
+#[cfg(any(
+    feature = "overlayfs",
+    feature = "fuse-overlayfs",
+    feature = "unionfs-fuse"
+))]
+pub mod builder;
+mod busy;
+mod capability;
+#[cfg(feature = "dedup")]
+mod dedup;
+mod description;
+mod idmap;
+mod image;
+mod image_writer;
+#[cfg(feature = "integrity")]
+mod integrity;
+#[cfg(feature = "overlayfs")]
+mod launcher;
+mod mount_api;
+mod mountinfo;
+mod namespace;
+mod propagation;
+#[cfg(any(
+    feature = "overlayfs",
+    feature = "fuse-overlayfs",
+    feature = "unionfs-fuse"
+))]
+mod swap;
+mod tmpfs;
+mod version;
+#[cfg(feature = "archive")]
+pub use archive::ArchiveMount;
+pub use bind::{BindMount, DetachedMount};
+#[cfg(any(
+    feature = "overlayfs",
+    feature = "fuse-overlayfs",
+    feature = "unionfs-fuse"
+))]
+pub use builder::{Stack, StackBuilder};
+pub use busy::{HoldKind, Holder, UnmountMode, holders};
+pub use capability::OverlayCapabilities;
+#[cfg(feature = "dedup")]
+pub use dedup::{Dedup, DedupMethod, DedupReport, DuplicateGroup};
+pub use description::StackDescription;
+pub use idmap::{IdMap, IdRange};
+pub use image::{ImageBackend, ImageKind, ImageMount};
+pub use image_writer::ImageWriter;
+#[cfg(feature = "integrity")]
+pub use integrity::{IntegrityReport, Manifest, ManifestEntry};
+#[cfg(feature = "overlayfs")]
+pub use launcher::{Launched, Launcher};
+pub use namespace::UserNamespace;
+pub use propagation::Propagation;
+pub use tmpfs::Tmpfs;
+pub use version::Version;
 #[cfg(feature = "unionfs-fuse")]
 pub mod unionfs_fuse;
 #[cfg(feature = "unionfs-fuse")]
@@ -78,7 +136,13 @@ mod recover_state {
 }
 
 mod option {
-    use std::{fmt::Display, io::Result, str::FromStr};
+    use std::{
+        fmt::Display,
+        io::{Error, ErrorKind, Result},
+        str::FromStr,
+    };
+
+    use super::{Propagation, UnmountMode};
 
     pub trait LinuxFilesystem<O>
     where
@@ -93,6 +157,26 @@ mod option {
 
         /// List currently active option
         fn options(&self) -> &[MountOption<O>];
+
+        /// Propagation type applied to the mount point once mounted,
+        /// inherited from the parent mount when unset
+        fn propagation(&self) -> Option<Propagation> {
+            None
+        }
+
+        /// Set propagation type, applied right away when already mounted
+        fn set_propagation(&mut self, _propagation: Option<Propagation>) -> Result<()> {
+            Err(Error::new(
+                ErrorKind::Unsupported,
+                "propagation type cannot be set on this filesystem",
+            ))
+        }
+
+        /// Behavior of unmount when processes still use the mount point
+        fn unmount_mode(&self) -> UnmountMode;
+
+        /// Set the behavior of unmount, lazy by default
+        fn set_unmount_mode(&mut self, mode: UnmountMode);
     }
 
     #[allow(dead_code)]
@@ -135,6 +219,7 @@ mod option {
     }
 
     #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub enum MountOption<O: FsOption> {
         /// Mount the filesystem read-write.
         RW,
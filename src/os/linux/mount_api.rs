@@ -0,0 +1,142 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+/*
+* Thin wrappers around the new mount API (kernel 5.2+) which is not exposed by nix
+*/
+
+use std::{
+    io::{Error, Result},
+    os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    path::Path,
+};
+
+use nix::libc::{self, c_uint};
+
+use crate::AsCString;
+
+const OPEN_TREE_CLONE: c_uint = 1;
+const OPEN_TREE_CLOEXEC: c_uint = libc::O_CLOEXEC as c_uint;
+const MOVE_MOUNT_F_EMPTY_PATH: c_uint = 0x00000004;
+/// Attach beneath the top mount of the target, kernel 6.5+
+#[cfg(any(
+    feature = "overlayfs",
+    feature = "fuse-overlayfs",
+    feature = "unionfs-fuse"
+))]
+const MOVE_MOUNT_BENEATH: c_uint = 0x00000200;
+const MOUNT_ATTR_RDONLY: u64 = 0x00000001;
+#[cfg(feature = "overlayfs")]
+const MOUNT_ATTR_IDMAP: u64 = 0x00100000;
+
+#[repr(C)]
+/// struct mount_attr from linux/mount.h
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+/// Check the return value of a raw syscall
+fn check(res: libc::c_long) -> Result<libc::c_long> {
+    if res < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(res)
+    }
+}
+
+/// Create a detached copy of the mount tree at path, like a bind mount not attached anywhere yet
+pub(crate) fn open_tree(path: &Path, recursive: bool) -> Result<OwnedFd> {
+    let path = path.as_cstring();
+    let mut flags = OPEN_TREE_CLONE | OPEN_TREE_CLOEXEC;
+    if recursive {
+        flags |= libc::AT_RECURSIVE as c_uint;
+    }
+    let fd =
+        check(unsafe { libc::syscall(libc::SYS_open_tree, libc::AT_FDCWD, path.as_ptr(), flags) })?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+/// Change attributes of a detached mount tree
+fn set_attr(tree: BorrowedFd, attr: MountAttr, recursive: bool) -> Result<()> {
+    let mut flags = libc::AT_EMPTY_PATH;
+    if recursive {
+        flags |= libc::AT_RECURSIVE;
+    }
+    check(unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            tree.as_raw_fd(),
+            c"".as_ptr(),
+            flags,
+            &attr as *const MountAttr,
+            size_of::<MountAttr>(),
+        )
+    })?;
+    Ok(())
+}
+
+#[cfg(feature = "overlayfs")]
+/// Make a detached mount tree idmapped using the mappings of a user namespace
+pub(crate) fn set_idmap(tree: BorrowedFd, userns: BorrowedFd) -> Result<()> {
+    let attr = MountAttr {
+        attr_set: MOUNT_ATTR_IDMAP,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: userns.as_raw_fd() as u64,
+    };
+    set_attr(tree, attr, false)
+}
+
+/// Make a detached mount tree read-only or read-write
+pub(crate) fn set_readonly(tree: BorrowedFd, readonly: bool, recursive: bool) -> Result<()> {
+    let (attr_set, attr_clr) = if readonly {
+        (MOUNT_ATTR_RDONLY, 0)
+    } else {
+        (0, MOUNT_ATTR_RDONLY)
+    };
+    let attr = MountAttr {
+        attr_set,
+        attr_clr,
+        propagation: 0,
+        userns_fd: 0,
+    };
+    set_attr(tree, attr, recursive)
+}
+
+/// Attach a detached mount tree on target
+pub(crate) fn move_mount(tree: BorrowedFd, target: &Path) -> Result<()> {
+    attach(tree, target, MOVE_MOUNT_F_EMPTY_PATH)
+}
+
+/// Attach a detached mount tree under the mount currently on top of target, so that
+/// unmounting the top one reveal it without exposing the bare target
+#[cfg(any(
+    feature = "overlayfs",
+    feature = "fuse-overlayfs",
+    feature = "unionfs-fuse"
+))]
+pub(crate) fn move_mount_beneath(tree: BorrowedFd, target: &Path) -> Result<()> {
+    attach(tree, target, MOVE_MOUNT_F_EMPTY_PATH | MOVE_MOUNT_BENEATH)
+}
+
+fn attach(tree: BorrowedFd, target: &Path, flags: c_uint) -> Result<()> {
+    let target = target.as_cstring();
+    check(unsafe {
+        libc::syscall(
+            libc::SYS_move_mount,
+            tree.as_raw_fd(),
+            c"".as_ptr(),
+            libc::AT_FDCWD,
+            target.as_ptr(),
+            flags,
+        )
+    })?;
+    Ok(())
+}
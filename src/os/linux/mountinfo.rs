@@ -0,0 +1,170 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use std::{
+    io::Result,
+    path::{Path, PathBuf},
+};
+#[cfg(any(feature = "fuse-overlayfs", feature = "unionfs-fuse"))]
+use std::{
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+/// Default time given to a FUSE daemon to attach or release its mount point
+#[cfg(any(feature = "fuse-overlayfs", feature = "unionfs-fuse"))]
+pub(crate) const FUSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Entry of /proc/self/mountinfo, see proc_pid_mountinfo(5)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MountInfo {
+    pub id: u32,
+    pub parent: u32,
+    /// major:minor of the device
+    pub dev: String,
+    /// Directory of the filesystem which forms the root of this mount
+    pub root: PathBuf,
+    pub mount_point: PathBuf,
+    /// Per mount options
+    pub options: Vec<String>,
+    /// Optional fields, including propagation
+    pub optional: Vec<String>,
+    pub fs_type: String,
+    pub source: String,
+}
+
+impl MountInfo {
+    /// Retrieve every mount of the current mount namespace
+    pub(crate) fn all() -> Result<Vec<Self>> {
+        Ok(Self::parse(&std::fs::read_to_string(
+            "/proc/self/mountinfo",
+        )?))
+    }
+
+    /// Retrieve the topmost mount on target
+    pub(crate) fn find(target: &Path) -> Result<Option<Self>> {
+        Ok(Self::all()?.into_iter().rfind(|x| x.mount_point == target))
+    }
+
+    /// Poll until a FUSE mount other than previous is on top of target, false on timeout
+    #[cfg(any(feature = "fuse-overlayfs", feature = "unionfs-fuse"))]
+    pub(crate) fn wait_fuse(
+        target: &Path,
+        previous: Option<u32>,
+        timeout: Duration,
+    ) -> Result<bool> {
+        Self::wait(timeout, |mounts| {
+            mounts
+                .iter()
+                .rfind(|x| x.mount_point == target)
+                .is_some_and(|x| Some(x.id) != previous && x.fs_type.starts_with("fuse"))
+        })
+    }
+
+    /// Poll until the mount with id is gone, false on timeout
+    #[cfg(any(feature = "fuse-overlayfs", feature = "unionfs-fuse"))]
+    pub(crate) fn wait_gone(id: u32, timeout: Duration) -> Result<bool> {
+        Self::wait(timeout, |mounts| mounts.iter().all(|x| x.id != id))
+    }
+
+    #[cfg(any(feature = "fuse-overlayfs", feature = "unionfs-fuse"))]
+    fn wait(timeout: Duration, done: impl Fn(&[Self]) -> bool) -> Result<bool> {
+        let start = Instant::now();
+        loop {
+            if done(&Self::all()?) {
+                return Ok(true);
+            }
+            if start.elapsed() >= timeout {
+                return Ok(false);
+            }
+            sleep(Duration::from_millis(10));
+        }
+    }
+
+    pub(crate) fn parse(content: &str) -> Vec<Self> {
+        content.lines().filter_map(Self::parse_line).collect()
+    }
+
+    fn parse_line(line: &str) -> Option<Self> {
+        let mut fields = line.split(' ');
+        let id = fields.next()?.parse().ok()?;
+        let parent = fields.next()?.parse().ok()?;
+        let dev = fields.next()?.to_string();
+        let root = unescape(fields.next()?);
+        let mount_point = unescape(fields.next()?);
+        let options = fields.next()?.split(',').map(|x| x.to_string()).collect();
+        // optional fields are placed between mount options and the separator
+        let optional = fields
+            .by_ref()
+            .take_while(|x| *x != "-")
+            .map(|x| x.to_string())
+            .collect();
+        let fs_type = fields.next()?.to_string();
+        let source = unescape(fields.next()?).to_string_lossy().to_string();
+        Some(Self {
+            id,
+            parent,
+            dev,
+            root,
+            mount_point,
+            options,
+            optional,
+            fs_type,
+            source,
+        })
+    }
+}
+
+/// Revert octal escape of whitespace and backslash used by mountinfo
+fn unescape(path: &str) -> PathBuf {
+    let mut res = vec![];
+    let bytes = path.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\'
+            && let Some(c) = path
+                .get(i + 1..i + 4)
+                .and_then(|x| u8::from_str_radix(x, 8).ok())
+        {
+            res.push(c);
+            i += 4;
+        } else {
+            res.push(bytes[i]);
+            i += 1;
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&res).to_string())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let content = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+40 22 0:35 / /mnt/my\\040game ro,relatime shared:20 master:1 - overlay overlay rw,lowerdir=/a:/b";
+        let info = MountInfo::parse(content);
+        assert_eq!(info.len(), 2);
+        assert_eq!(info[1].mount_point, Path::new("/mnt/my game"));
+        assert_eq!(info[1].parent, 22);
+        assert_eq!(info[1].optional, vec!["shared:20", "master:1"]);
+        assert_eq!(info[1].fs_type, "overlay");
+        assert!(info[1].options.contains(&"ro".to_string()));
+    }
+
+    #[cfg(any(feature = "fuse-overlayfs", feature = "unionfs-fuse"))]
+    #[test]
+    fn wait_timeout() {
+        let root = MountInfo::find(Path::new("/")).unwrap().unwrap();
+        assert!(!MountInfo::wait_gone(root.id, Duration::from_millis(30)).unwrap());
+        assert!(MountInfo::wait_gone(u32::MAX, Duration::ZERO).unwrap());
+        assert!(!MountInfo::wait_fuse(Path::new("/"), None, Duration::ZERO).unwrap());
+    }
+}
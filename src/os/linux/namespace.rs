@@ -0,0 +1,343 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+#[cfg(feature = "overlayfs")]
+use std::{
+    ffi::CString,
+    fs::File,
+    io::{Error, ErrorKind, Read, Result, Write},
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::process::CommandExt,
+    },
+    path::PathBuf,
+    process::{Child, Command},
+};
+
+use nix::unistd::{getegid, geteuid};
+#[cfg(feature = "overlayfs")]
+use nix::{
+    libc,
+    mount::{MsFlags, mount},
+    sched::{CloneFlags, clone, unshare},
+    sys::wait::{WaitStatus, waitpid},
+    unistd::{ForkResult, fork},
+};
+
+use super::capability::userns_allowed;
+#[cfg(feature = "overlayfs")]
+use crate::{
+    AsCString, Filesystem, LinuxFilesystem, MountOption, OverlayCapabilities, OverlayFs,
+    StackableFilesystem, overlay::OverlayFsOption,
+};
+use crate::{IdMap, IdRange};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// User and mount namespace in which an unprivileged user can mount filesystems
+///
+/// Namespaces are only ever entered by a child process, mount made inside of them
+/// are invisible from the host and vanish with the last process using them
+pub struct UserNamespace {
+    uid_map: IdMap,
+    gid_map: IdMap,
+}
+
+impl Default for UserNamespace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UserNamespace {
+    /// Map root inside the namespace to the current user
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            uid_map: IdRange::new(0, geteuid().as_raw(), 1).into(),
+            gid_map: IdRange::new(0, getegid().as_raw(), 1).into(),
+        }
+    }
+
+    /// Use custom id mappings
+    ///
+    /// NOTE : without privilege the kernel only accept a single range mapping the current user
+    #[inline]
+    pub fn with_maps(uid_map: IdMap, gid_map: IdMap) -> Self {
+        Self { uid_map, gid_map }
+    }
+
+    #[inline]
+    pub fn uid_map(&self) -> &IdMap {
+        &self.uid_map
+    }
+
+    #[inline]
+    pub fn gid_map(&self) -> &IdMap {
+        &self.gid_map
+    }
+
+    /// Check that user namespaces can be created on this system
+    pub fn is_available() -> bool {
+        userns_allowed()
+    }
+}
+
+#[cfg(feature = "overlayfs")]
+impl UserNamespace {
+    /// Render mappings to be written once forked
+    fn maps(&self) -> Result<Maps> {
+        if !Self::is_available() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "user namespaces are disabled on this system",
+            ));
+        }
+        if self.uid_map.is_empty() || self.gid_map.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "user namespace need both an uid and a gid mapping",
+            ));
+        }
+        Ok(Maps {
+            uid_map: proc_map(&self.uid_map),
+            gid_map: proc_map(&self.gid_map),
+        })
+    }
+
+    /// Open a user namespace holding these mappings, as required by idmapped mounts
+    ///
+    /// A short lived child is created in the namespace so the mappings can be written from
+    /// the caller namespace, arbitrary mappings require privilege
+    pub(crate) fn open(&self) -> Result<OwnedFd> {
+        if self.uid_map.is_empty() || self.gid_map.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "user namespace need both an uid and a gid mapping",
+            ));
+        }
+        let (mut reader, writer) = std::io::pipe()?;
+        let hold = writer.as_raw_fd();
+        let mut stack = vec![0u8; 64 * 1024];
+        // block until the caller is done with the namespace
+        let child = Box::new(|| {
+            unsafe { libc::close(hold) };
+            let _ = reader.read(&mut [0u8]);
+            0
+        });
+        let pid = unsafe {
+            clone(
+                child,
+                &mut stack,
+                CloneFlags::CLONE_NEWUSER,
+                Some(libc::SIGCHLD),
+            )
+        }?;
+        let proc = PathBuf::from(format!("/proc/{}", pid));
+        let res = std::fs::write(proc.join("uid_map"), proc_map(&self.uid_map))
+            .and_then(|_| std::fs::write(proc.join("gid_map"), proc_map(&self.gid_map)))
+            .and_then(|_| File::open(proc.join("ns/user")))
+            .map(OwnedFd::from);
+        drop(writer);
+        waitpid(pid, None)?;
+        res
+    }
+
+    /// Mount overlay inside a new namespace then run f in it
+    ///
+    /// f is run by a forked child process, its return value is used as exit code
+    pub fn run<F>(&self, overlay: &mut OverlayFs, f: F) -> Result<i32>
+    where
+        F: FnOnce() -> i32,
+    {
+        let setup = self.prepare(overlay)?;
+        let (mut reader, mut writer) = std::io::pipe()?;
+        match unsafe { fork() }? {
+            ForkResult::Child => {
+                drop(reader);
+                let code = match setup.enter() {
+                    Ok(()) => {
+                        drop(writer);
+                        f()
+                    }
+                    Err(e) => {
+                        let errno = e.raw_os_error().unwrap_or(libc::EINVAL);
+                        let _ = writer.write_all(&errno.to_ne_bytes());
+                        libc::EXIT_FAILURE
+                    }
+                };
+                unsafe { libc::_exit(code) }
+            }
+            ForkResult::Parent { child } => {
+                drop(writer);
+                let mut errno = [0u8; 4];
+                let failed = reader.read_exact(&mut errno).is_ok();
+                let status = waitpid(child, None)?;
+                if failed {
+                    return Err(Error::from_raw_os_error(i32::from_ne_bytes(errno)));
+                }
+                match status {
+                    WaitStatus::Exited(_, code) => Ok(code),
+                    WaitStatus::Signaled(_, signal, _) => Err(Error::other(format!(
+                        "child process was killed by {:?}",
+                        signal
+                    ))),
+                    _ => Err(Error::other("child process ended unexpectedly")),
+                }
+            }
+        }
+    }
+
+    /// Mount overlay inside a new namespace then spawn command in it
+    pub fn spawn(&self, overlay: &mut OverlayFs, command: &mut Command) -> Result<Child> {
+        let setup = self.prepare(overlay)?;
+        unsafe { command.pre_exec(move || setup.enter()) };
+        command.spawn()
+    }
+
+    /// Render everything needed once forked, with user extended attributes enabled
+    pub(crate) fn prepare(&self, overlay: &mut OverlayFs) -> Result<Setup> {
+        if !OverlayCapabilities::get().unprivileged {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "overlay cannot be mounted inside a user namespace on this system",
+            ));
+        }
+        if overlay.idmap().is_some() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "idmapped layers cannot be created inside a user namespace",
+            ));
+        }
+        if overlay.lower().iter().any(|x| x.is_file()) {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "image layers cannot be mounted inside a user namespace",
+            ));
+        }
+        let maps = self.maps()?;
+        // trusted.* xattr are not available to unprivileged user, the option is only added
+        // while rendering so the caller handle is left untouched
+        let user_xattr = MountOption::FsSpecific(OverlayFsOption::UserXattr);
+        let added = !overlay.options().contains(&user_xattr);
+        overlay.set_option(user_xattr.clone())?;
+        let data = overlay.mount_data();
+        if added {
+            overlay.remove_option(user_xattr)?;
+        }
+        Ok(Setup {
+            maps,
+            target: overlay.target().as_cstring(),
+            data: data?,
+        })
+    }
+}
+
+#[cfg(feature = "overlayfs")]
+/// Id mappings rendered in the format expected by /proc/PID/[ug]id_map
+pub(crate) struct Maps {
+    uid_map: String,
+    gid_map: String,
+}
+
+#[cfg(feature = "overlayfs")]
+impl Maps {
+    /// Map a single uid and gid
+    pub(crate) fn single(uid: IdRange, gid: IdRange) -> Self {
+        Self {
+            uid_map: proc_map(&uid.into()),
+            gid_map: proc_map(&gid.into()),
+        }
+    }
+
+    /// Unshare a new user namespace along with the other flags then write mappings
+    pub(crate) fn unshare(&self, flags: CloneFlags) -> Result<()> {
+        unshare(CloneFlags::CLONE_NEWUSER | flags)?;
+        self.write()
+    }
+
+    /// Write mappings of the current process
+    ///
+    /// NOTE : to be called right after the process entered a new user namespace
+    pub(crate) fn write(&self) -> Result<()> {
+        std::fs::write("/proc/self/uid_map", &self.uid_map)?;
+        // setgroups must be denied before an unprivileged process can write gid_map
+        std::fs::write("/proc/self/setgroups", "deny")?;
+        std::fs::write("/proc/self/gid_map", &self.gid_map)
+    }
+}
+
+#[cfg(feature = "overlayfs")]
+/// Stop mount events from propagating back to the host
+fn make_private() -> Result<()> {
+    mount(
+        None::<&str>,
+        "/",
+        None::<&str>,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        None::<&str>,
+    )?;
+    Ok(())
+}
+
+#[cfg(feature = "overlayfs")]
+/// Everything required to enter the namespace and mount overlay from a forked child
+pub(crate) struct Setup {
+    pub(crate) maps: Maps,
+    target: CString,
+    data: CString,
+}
+
+#[cfg(feature = "overlayfs")]
+impl Setup {
+    /// Enter the namespace then mount overlay
+    ///
+    /// NOTE : run between fork and exec, nothing should be logged from here
+    fn enter(&self) -> Result<()> {
+        self.maps.unshare(CloneFlags::CLONE_NEWNS)?;
+        self.mount()
+    }
+
+    /// Stop mount events from propagating to the host then mount overlay
+    ///
+    /// NOTE : to be called once inside the new user and mount namespaces
+    pub(crate) fn mount(&self) -> Result<()> {
+        make_private()?;
+        mount(
+            Some(c"overlay"),
+            self.target.as_c_str(),
+            Some(c"overlay"),
+            MsFlags::empty(),
+            Some(self.data.as_c_str()),
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "overlayfs")]
+/// Render an id mapping in the format expected by /proc/PID/[ug]id_map
+fn proc_map(map: &IdMap) -> String {
+    map.ranges()
+        .iter()
+        .map(|x| format!("{} {} {}\n", x.inside, x.outside, x.count))
+        .collect()
+}
+
+#[cfg(all(test, feature = "overlayfs"))]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_proc_map() {
+        let map = IdMap::from(vec![
+            IdRange::new(0, 1000, 1),
+            IdRange::new(1, 100000, 65536),
+        ]);
+        assert_eq!(proc_map(&map), "0 1000 1\n1 100000 65536\n");
+    }
+}
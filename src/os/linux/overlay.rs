@@ -12,29 +12,64 @@
 */
 
 mod opt;
+mod verity;
 pub use opt::*;
+use verity::{
+    OVL_XATTR_TRUSTED, OVL_XATTR_USER, metacopy_digest_in, redirect, set_metacopy_digest_in,
+    walk_files,
+};
+pub use verity::{
+    VerityDigest, enable_verity, enable_verity_layer, measure_verity, metacopy_digest,
+    set_metacopy_digest,
+};
 
 use nix::mount::{MntFlags, MsFlags, mount, umount2};
 use std::{
     ffi::CString,
+    fs::create_dir_all,
     io::{Error, ErrorKind, Result},
+    os::fd::AsFd,
     path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 use tracing::{debug, error};
 
 use crate::{
-    AsCString, AsPath, Filesystem, FsData, LinuxFilesystem, MountOption, PartitionID,
-    StackableFilesystem, StateRecovery, restore_fsdata, set_option_helper,
+    AsCString, AsPath, Filesystem, FsData, LinuxFilesystem, MountOption, OverlayCapabilities,
+    PartitionID, Propagation, StackDescription, StackableFilesystem, StateRecovery, Tmpfs,
+    UnmountMode, UserNamespace, restore_fsdata, set_option_helper,
+};
+
+#[cfg(feature = "integrity")]
+use super::integrity::{Manifest, check_layers, prune_manifests, set_manifest};
+use super::{
+    busy::unmount_with,
+    image::ImageLayers,
+    mount_api::{move_mount, open_tree, set_idmap},
+    swap,
+    tmpfs::{copy_tree, scratch_dirs},
 };
 
+/// Prefix of the directories holding idmapped layers
+const IDMAP_STAGING: &str = "damascus-idmap-";
+
 #[derive(Debug)]
 /// Kernel overlay filesystem handle
 pub struct OverlayFs {
     lower: Vec<PathBuf>,
+    data: Vec<PathBuf>,
     upper: Option<PathBuf>,
     work: Option<PathBuf>,
     target: CString,
     options: Vec<MountOption<OverlayFsOption>>,
+    propagation: Option<Propagation>,
+    unmount_mode: UnmountMode,
+    scratch: Option<Tmpfs>,
+    images: ImageLayers,
+    #[cfg(feature = "integrity")]
+    manifests: Vec<(PathBuf, Manifest)>,
+    idmap: Option<UserNamespace>,
+    staging: Option<PathBuf>,
     id: Option<PartitionID>,
     drop: bool,
 }
@@ -57,10 +92,19 @@ impl OverlayFs {
     {
         Ok(Self {
             lower: lower.map(|x| x.to_path_buf()).collect(),
+            data: vec![],
             upper: upper.map(|x| x.into()),
             work: work.map(|x| x.into()),
             target: target.as_ref().as_cstring(),
             options: MountOption::defaults(),
+            propagation: None,
+            unmount_mode: UnmountMode::default(),
+            scratch: None,
+            images: ImageLayers::default(),
+            #[cfg(feature = "integrity")]
+            manifests: vec![],
+            idmap: None,
+            staging: None,
             id: None,
             drop,
         })
@@ -82,10 +126,19 @@ impl OverlayFs {
         }
         Ok(OverlayFs {
             lower,
+            data: vec![],
             upper: None,
             work: None,
             target: target.as_ref().as_cstring(),
             options: MountOption::defaults(),
+            propagation: None,
+            unmount_mode: UnmountMode::default(),
+            scratch: None,
+            images: ImageLayers::default(),
+            #[cfg(feature = "integrity")]
+            manifests: vec![],
+            idmap: None,
+            staging: None,
             id: None,
             drop: true,
         })
@@ -108,15 +161,82 @@ impl OverlayFs {
         }
         Ok(OverlayFs {
             lower: lower.map(|x| x.as_ref().to_path_buf()).collect(),
+            data: vec![],
             upper: Some(upper.as_ref().to_path_buf()),
             work: Some(work.as_ref().to_path_buf()),
             target: target.as_ref().as_cstring(),
             options: MountOption::defaults(),
+            propagation: None,
+            unmount_mode: UnmountMode::default(),
+            scratch: None,
+            images: ImageLayers::default(),
+            #[cfg(feature = "integrity")]
+            manifests: vec![],
+            idmap: None,
+            staging: None,
             id: None,
             drop: true,
         })
     }
 
+    /// Initialise a writable handle whose upper and work directories live on a private tmpfs
+    ///
+    /// scratch is mounted right away and unmounted along with the overlay, changes can be kept
+    /// with export_changes before teardown
+    pub fn ephemeral<I, A, T>(lower: I, mut scratch: Tmpfs, target: T) -> Result<OverlayFs>
+    where
+        I: Iterator<Item = A>,
+        A: AsRef<Path>,
+        T: AsRef<Path>,
+    {
+        let (upper, work) = scratch_dirs(&mut scratch)?;
+        let mut handle = Self::writable(lower, upper, work, target)?;
+        handle.scratch = Some(scratch);
+        Ok(handle)
+    }
+
+    /// Tmpfs holding upper and work directories of an ephemeral handle
+    #[inline]
+    pub fn scratch(&self) -> Option<&Tmpfs> {
+        self.scratch.as_ref()
+    }
+
+    /// Manifests verified before each mount
+    #[cfg(feature = "integrity")]
+    #[inline]
+    pub fn manifests(&self) -> &[(PathBuf, Manifest)] {
+        &self.manifests
+    }
+
+    /// Refuse to mount while layer has drifted from manifest, None remove the check
+    #[cfg(feature = "integrity")]
+    pub fn set_manifest(
+        &mut self,
+        layer: impl AsRef<Path>,
+        manifest: Option<Manifest>,
+    ) -> Result<()> {
+        if self.id.is_some() {
+            return Err(Error::other(
+                "manifest cannot be change when the FileSystem is mounted",
+            ));
+        }
+        set_manifest(
+            &mut self.manifests,
+            self.lower.iter().chain(self.data.iter()),
+            layer.as_ref(),
+            manifest,
+        )
+    }
+
+    /// Copy the upper directory, which hold every change made through the overlay, to dest
+    pub fn export_changes(&self, dest: impl AsRef<Path>) -> Result<()> {
+        let upper = self
+            .upper
+            .as_ref()
+            .ok_or(Error::new(ErrorKind::NotFound, "upper directory not set"))?;
+        copy_tree(upper, dest.as_ref())
+    }
+
     #[inline]
     pub fn work(&self) -> Option<&PathBuf> {
         self.work.as_ref()
@@ -139,31 +259,278 @@ impl OverlayFs {
         self.work = Some(work);
         Ok(())
     }
-}
 
-impl Filesystem for OverlayFs {
+    /// Retrieve the list of data-only lower layer
     #[inline]
-    fn mount(&mut self) -> Result<PathBuf> {
-        if !Self::is_available() {
+    pub fn data_lower(&self) -> Vec<&Path> {
+        self.data.iter().map(|x| x.as_path()).collect()
+    }
+
+    /// Set data-only lower layer
+    ///
+    /// Data-only layers are placed below every regular lower layer, their content is never
+    /// listed in the merged directory and can only be reached through a metacopy file holding
+    /// an absolute redirect, which require kernel 6.5 or later
+    #[inline]
+    pub fn set_data_lower(&mut self, data: impl Into<Vec<PathBuf>>) -> Result<()> {
+        if self.id.is_some() {
+            return Err(Error::other(
+                "data-only layer cannot be change when the FileSystem is mounted",
+            ));
+        }
+        self.data = data.into();
+        #[cfg(feature = "integrity")]
+        prune_manifests(
+            &mut self.manifests,
+            self.lower.iter().chain(self.data.iter()),
+        );
+        Ok(())
+    }
+
+    /// Retrieve the id mapping applied to every layer
+    #[inline]
+    pub fn idmap(&self) -> Option<&UserNamespace> {
+        self.idmap.as_ref()
+    }
+
+    /// Set the id mapping applied to every layer
+    ///
+    /// Each lower and the upper are exposed to overlay through an idmapped bind mount, so files
+    /// owned by another user are seen and copied up as if they were owned by the mapped one.
+    /// Require privilege and kernel 5.19 or later, intermediate mounts are removed on unmount.
+    #[inline]
+    pub fn set_idmap(&mut self, idmap: Option<UserNamespace>) -> Result<()> {
+        if self.id.is_some() {
+            return Err(Error::other(
+                "id mapping cannot be change when the FileSystem is mounted",
+            ));
+        }
+        self.idmap = idmap;
+        Ok(())
+    }
+
+    /// Namespace of the overlay xattrs read by the kernel with the current options
+    fn xattr_prefix(&self) -> &'static str {
+        if self
+            .options
+            .contains(&MountOption::FsSpecific(OverlayFsOption::UserXattr))
+        {
+            OVL_XATTR_USER
+        } else {
+            OVL_XATTR_TRUSTED
+        }
+    }
+
+    /// Digest stored in a metacopy file, read from the xattr namespace used by this overlay
+    ///
+    /// Return none when the file isn't a metacopy file, or Some(None) when it hold no digest
+    pub fn metacopy_digest(&self, file: impl AsRef<Path>) -> Result<Option<Option<VerityDigest>>> {
+        metacopy_digest_in(file.as_ref(), self.xattr_prefix())
+    }
+
+    /// Store the digest of the data file in a metacopy file, using the xattr namespace of this
+    /// overlay
+    pub fn set_metacopy_digest(&self, file: impl AsRef<Path>, digest: &VerityDigest) -> Result<()> {
+        set_metacopy_digest_in(file.as_ref(), self.xattr_prefix(), digest)
+    }
+
+    /// Check that a configuration with verity=require won't answer EIO on open
+    ///
+    /// Every metacopy file in the upper and lower layers must hold a digest matching the
+    /// fs-verity digest of the data file it redirect to, see enable_verity_layer and
+    /// set_metacopy_digest to fix them
+    pub fn check_verity(&self) -> Result<()> {
+        let mut mode = FsVerity::Off;
+        let mut metacopy = false;
+        let prefix = self.xattr_prefix();
+        for opt in &self.options {
+            match opt {
+                MountOption::FsSpecific(OverlayFsOption::FsVerity(v)) => mode = v.clone(),
+                MountOption::FsSpecific(OverlayFsOption::Metacopy(b)) => metacopy = *b,
+                _ => {}
+            }
+        }
+        if mode == FsVerity::Off {
+            return Ok(());
+        }
+        if !metacopy {
             return Err(Error::new(
-                ErrorKind::NotFound,
-                "overlayfs is not available",
+                ErrorKind::InvalidInput,
+                "overlay FileSystem need metacopy=on to use verity",
             ));
         }
-        if matches!(self.id,Some(x) if x == PartitionID::try_from(self.target.as_path())?) {
-            debug!("Damascus: partition already mounted");
-            return Ok(self.target.as_path().to_path_buf());
+        if !OverlayCapabilities::get().verity {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "overlay FileSystem on this kernel doesn't support verity",
+            ));
+        }
+        if mode != FsVerity::Require {
+            return Ok(());
         }
-        let flags = MsFlags::empty();
+        // image layers can only be inspected once mounted
+        let layers: Vec<&Path> = self
+            .upper
+            .iter()
+            .chain(self.lower.iter())
+            .chain(self.data.iter())
+            .map(|x| x.as_path())
+            .filter(|x| x.is_dir())
+            .collect();
+        let metacopy_layers = layers.len() - self.data.iter().filter(|x| x.is_dir()).count();
+        for (i, layer) in layers[..metacopy_layers].iter().enumerate() {
+            walk_files(layer, &mut |path, meta| {
+                if !meta.is_file() {
+                    return Ok(());
+                }
+                let digest = match metacopy_digest_in(path, prefix)? {
+                    None => return Ok(()),
+                    Some(None) => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("metacopy file {:?} has no verity digest", path),
+                        ));
+                    }
+                    Some(Some(digest)) => digest,
+                };
+                let relative = path.strip_prefix(layer).map_err(Error::other)?;
+                let origin = match redirect(path, prefix)? {
+                    Some(x) if x.is_absolute() => x.strip_prefix("/").map_err(Error::other)?.into(),
+                    Some(x) => relative.with_file_name(x),
+                    None => relative.to_path_buf(),
+                };
+                let mut data = None;
+                for lower in &layers[i + 1..] {
+                    let candidate = lower.join(&origin);
+                    if candidate.symlink_metadata().is_ok_and(|x| x.is_file())
+                        && metacopy_digest_in(&candidate, prefix)?.is_none()
+                    {
+                        data = Some(candidate);
+                        break;
+                    }
+                }
+                let Some(data) = data else {
+                    return Err(Error::new(
+                        ErrorKind::NotFound,
+                        format!("data file of metacopy file {:?} cannot be found", path),
+                    ));
+                };
+                match measure_verity(&data)? {
+                    None => Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("data file {:?} doesn't have fs-verity enabled", data),
+                    )),
+                    Some(x) if x != digest => Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "verity digest of metacopy file {:?} doesn't match data file {:?}",
+                            path, data
+                        ),
+                    )),
+                    Some(_) => Ok(()),
+                }
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Replace the lower layers of the mounted overlay without unmounting it
+    ///
+    /// The new stack is mounted aside then atomically put in place of the current one, which
+    /// is lazily detached, processes keep their view until they release it while new lookups
+    /// see the new layers. Only read-only overlays can be swapped, require kernel 6.5 or later
+    pub fn hot_swap(&mut self, lower: impl Into<Vec<PathBuf>>) -> Result<()> {
+        if self.id.is_none() {
+            return Err(Error::other(
+                "hot swap require the FileSystem to be mounted",
+            ));
+        }
+        let lower = lower.into();
+        let target = self.target.clone();
+        let previous = std::mem::replace(&mut self.lower, lower.clone());
+        let id = self.id.take();
+        // manifests of the dropped layers must not be checked by the replacement
+        #[cfg(feature = "integrity")]
+        let manifests = self.manifests.clone();
+        #[cfg(feature = "integrity")]
+        prune_manifests(
+            &mut self.manifests,
+            self.lower.iter().chain(self.data.iter()),
+        );
+        let idmap = self.staging.take();
+        // the replacement get its own images so a failure only release the ones it mounted
+        let mut images = std::mem::take(&mut self.images);
+        let res = swap::hot_swap(
+            target.as_path(),
+            &lower,
+            self.upper.is_some(),
+            |staging| {
+                self.target = staging.as_cstring();
+                self.mount().map(|_| ())
+            },
+            |target, flags| Ok(umount2(target, flags)?),
+        );
+        self.target = target;
+        if let Err(err) = res {
+            self.lower = previous;
+            self.id = id;
+            #[cfg(feature = "integrity")]
+            {
+                self.manifests = manifests;
+            }
+            let staging = std::mem::replace(&mut self.staging, idmap);
+            let mut staged = std::mem::replace(&mut self.images, images);
+            if let Err(err) = Self::release_staging(staging).and(staged.release()) {
+                error!(
+                    "Damascus: unable to release layers of the replacing stack because : {}",
+                    err
+                )
+            }
+            return Err(err);
+        }
+        self.id = Some(PartitionID::try_from(self.target.as_path())?);
+        // idmapped layers and images of the previous stack are lazily detached, its own
+        // detached mount keep them alive until it is released
+        if let Err(err) = Self::release_staging(idmap).and(images.release()) {
+            error!(
+                "Damascus: unable to release layers of the replaced stack because : {}",
+                err
+            )
+        }
+        if let Some(p) = self.propagation {
+            p.apply(self.target.as_path())?;
+        }
+        Ok(())
+    }
+
+    /// Check the configuration then build the data passed to mount(2)
+    pub(crate) fn mount_data(&self) -> Result<CString> {
+        let upper = self.upper.as_deref().zip(self.work.as_deref());
+        self.render_data(&self.lower, &self.data, upper)
+    }
+
+    /// Build the data passed to mount(2) for the given layers
+    fn render_data(
+        &self,
+        lower: &[PathBuf],
+        data: &[PathBuf],
+        upper: Option<(&Path, &Path)>,
+    ) -> Result<CString> {
+        self.check_capabilities()?;
+        self.check_data_lower()?;
         let mut options = String::new();
         options.push_str("lowerdir=");
-        for (i, p) in self.lower.iter().enumerate() {
+        for (i, p) in lower.iter().enumerate() {
             if i != 0 {
                 options.push(':')
             }
             options.push_str(p.to_string_lossy().as_ref());
         }
-        if let (Some(u), Some(w)) = (self.upper.as_ref(), self.work.as_ref()) {
+        for p in data {
+            options.push_str("::");
+            options.push_str(p.to_string_lossy().as_ref());
+        }
+        if let Some((u, w)) = upper {
             options.push_str(",upperdir=");
             options.push_str(u.to_string_lossy().as_ref());
             options.push_str(",workdir=");
@@ -174,31 +541,240 @@ impl Filesystem for OverlayFs {
         }
         let mut args = options.as_bytes().to_vec();
         args.push(b'\0');
-        let data = unsafe { CString::from_vec_with_nul_unchecked(args) };
-        mount(
-            Some(c"overlay"),
-            &*self.target,
-            Some(c"overlay"),
-            flags,
-            Some(data.as_bytes()),
+        Ok(unsafe { CString::from_vec_with_nul_unchecked(args) })
+    }
+
+    /// Expose every layer through an idmapped bind mount then build the data passed to mount(2)
+    fn idmap_layers(
+        &mut self,
+        idmap: &UserNamespace,
+        lower: &[PathBuf],
+        data: &[PathBuf],
+    ) -> Result<CString> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        if !OverlayCapabilities::get().idmapped_lower {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "overlay FileSystem doesn't support idmapped layers on this kernel",
+            ));
+        }
+        let staging = std::env::temp_dir().join(format!(
+            "{}{}-{}",
+            IDMAP_STAGING,
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        create_dir_all(&staging)?;
+        self.staging = Some(staging.clone());
+        let userns = idmap.open()?;
+        let clone = |src: &Path, name: String| -> Result<PathBuf> {
+            let dst = staging.join(name);
+            create_dir_all(&dst)?;
+            let tree = open_tree(src, false)?;
+            set_idmap(tree.as_fd(), userns.as_fd())?;
+            move_mount(tree.as_fd(), &dst)?;
+            Ok(dst)
+        };
+        let lower = lower
+            .iter()
+            .enumerate()
+            .map(|(i, l)| clone(l, format!("lower{}", i)))
+            .collect::<Result<Vec<_>>>()?;
+        let data = data
+            .iter()
+            .enumerate()
+            .map(|(i, l)| clone(l, format!("data{}", i)))
+            .collect::<Result<Vec<_>>>()?;
+        let upper = match (self.upper.as_ref(), self.work.as_ref()) {
+            (Some(u), Some(w)) => {
+                // upper and work must stay on the same mount, map their common ancestor
+                let base: PathBuf = u
+                    .components()
+                    .zip(w.components())
+                    .take_while(|(a, b)| a == b)
+                    .map(|(a, _)| a)
+                    .collect();
+                let root = clone(&base, "upper".to_string())?;
+                let rebase = |x: &Path| x.strip_prefix(&base).map(|x| root.join(x));
+                Some((
+                    rebase(u).map_err(Error::other)?,
+                    rebase(w).map_err(Error::other)?,
+                ))
+            }
+            _ => None,
+        };
+        self.render_data(
+            &lower,
+            &data,
+            upper.as_ref().map(|(u, w)| (u.as_path(), w.as_path())),
         )
-        .inspect_err(|_x| {
-            dbg!(&self);
-        })?;
+    }
+
+    /// Remove idmapped bind mounts created for the layers
+    fn release_idmap(&mut self) -> Result<()> {
+        Self::release_staging(self.staging.take())
+    }
+
+    /// Remove idmapped bind mounts found in staging
+    fn release_staging(staging: Option<PathBuf>) -> Result<()> {
+        if let Some(staging) = staging {
+            for entry in std::fs::read_dir(&staging)? {
+                let path = entry?.path();
+                let _ = umount2(&path, MntFlags::MNT_DETACH);
+                std::fs::remove_dir(&path)?;
+            }
+            std::fs::remove_dir(&staging)?;
+        }
+        Ok(())
+    }
+
+    /// Check that the running kernel support the current configuration
+    fn check_capabilities(&self) -> Result<()> {
+        let caps = OverlayCapabilities::get();
+        let unsupported = |feature: &str| {
+            Err(Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "overlay FileSystem on kernel {} doesn't support {}",
+                    caps.kernel, feature
+                ),
+            ))
+        };
+        if self.lower.len() + self.data.len() > caps.max_lower {
+            return unsupported("that many lower layers");
+        }
+        if !self.data.is_empty() && !caps.data_only_lower {
+            return unsupported("data-only lower layers");
+        }
+        for opt in &self.options {
+            match opt {
+                MountOption::FsSpecific(OverlayFsOption::UserXattr) if !caps.userxattr => {
+                    return unsupported("userxattr");
+                }
+                MountOption::FsSpecific(OverlayFsOption::Volatile) if !caps.volatile => {
+                    return unsupported("volatile");
+                }
+                MountOption::FsSpecific(OverlayFsOption::Metacopy(true)) if !caps.metacopy => {
+                    return unsupported("metacopy");
+                }
+                MountOption::FsSpecific(OverlayFsOption::RedirectDir(_)) if !caps.redirect_dir => {
+                    return unsupported("redirect_dir");
+                }
+                MountOption::FsSpecific(OverlayFsOption::FsVerity(
+                    FsVerity::On | FsVerity::Require,
+                )) if !caps.verity => {
+                    return unsupported("verity");
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the current configuration allow data-only lower layer to be used
+    fn check_data_lower(&self) -> Result<()> {
+        if self.data.is_empty() {
+            return Ok(());
+        }
+        if self.lower.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "overlay FileSystem need a least 1 regular lower directory to use data-only layers",
+            ));
+        }
+        let mut metacopy = false;
+        // metacopy=on imply redirect_dir=on unless explicitly overridden
+        let mut redirect = true;
+        for opt in &self.options {
+            match opt {
+                MountOption::FsSpecific(OverlayFsOption::Metacopy(b)) => metacopy = *b,
+                MountOption::FsSpecific(OverlayFsOption::RedirectDir(r)) => {
+                    redirect = matches!(r, RedirectDir::On | RedirectDir::Follow)
+                }
+                _ => {}
+            }
+        }
+        if !metacopy {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "overlay FileSystem need metacopy=on to use data-only layers",
+            ));
+        }
+        if !redirect {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "overlay FileSystem cannot follow redirect to data-only layers with redirect_dir=off or redirect_dir=nofollow",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Filesystem for OverlayFs {
+    #[inline]
+    fn mount(&mut self) -> Result<PathBuf> {
+        if !Self::is_available() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "overlayfs is not available",
+            ));
+        }
+        if matches!(self.id,Some(x) if x == PartitionID::try_from(self.target.as_path())?) {
+            debug!("Damascus: partition already mounted");
+            return Ok(self.target.as_path().to_path_buf());
+        }
+        #[cfg(feature = "integrity")]
+        check_layers(&self.manifests)?;
+        // images given as layers are mounted first then used in place of directories
+        let layers = self
+            .images
+            .resolve(&self.lower)
+            .and_then(|lower| Ok((lower, self.images.resolve(&self.data)?)));
+        let data = layers.and_then(|(lower, data)| match self.idmap.clone() {
+            Some(idmap) => self.idmap_layers(&idmap, &lower, &data),
+            None => {
+                let upper = self.upper.as_deref().zip(self.work.as_deref());
+                self.render_data(&lower, &data, upper)
+            }
+        });
+        if let Err(err) = data
+            .and_then(|data| {
+                Ok(mount(
+                    Some(c"overlay"),
+                    &*self.target,
+                    Some(c"overlay"),
+                    MsFlags::empty(),
+                    Some(data.as_bytes()),
+                )?)
+            })
+            .inspect_err(|_x| {
+                dbg!(&self);
+            })
+        {
+            self.release_idmap()?;
+            self.images.release()?;
+            return Err(err);
+        }
         self.id = Some(
             PartitionID::try_from(self.target.as_path())
                 .map_err(|_| Error::other("unable to get PartitionID"))?,
         );
+        if let Some(p) = self.propagation {
+            p.apply(self.target.as_path())?;
+        }
         Ok(self.target.as_path().to_path_buf())
     }
 
     #[inline]
     fn unmount(&mut self) -> Result<()> {
         if matches!(self.id,Some(x) if x == PartitionID::try_from(self.target.as_path())?) {
-            umount2(self.target.as_c_str(), MntFlags::MNT_DETACH)?;
+            unmount_with(self.target.as_path(), self.unmount_mode, |flags| {
+                Ok(umount2(self.target.as_c_str(), flags)?)
+            })?;
             self.id = None;
         }
-        Ok(())
+        self.release_idmap()?;
+        self.images.release()
     }
 
     #[inline]
@@ -209,6 +785,10 @@ impl Filesystem for OverlayFs {
     #[inline]
     fn set_unmount_on_drop(&mut self, drop: bool) {
         self.drop = drop;
+        if let Some(scratch) = self.scratch.as_mut() {
+            scratch.set_unmount_on_drop(drop);
+        }
+        self.images.set_unmount_on_drop(drop);
     }
 
     #[inline]
@@ -258,6 +838,28 @@ impl LinuxFilesystem<OverlayFsOption> for OverlayFs {
     fn options(&self) -> &[MountOption<OverlayFsOption>] {
         &self.options
     }
+
+    fn propagation(&self) -> Option<Propagation> {
+        self.propagation
+    }
+
+    fn set_propagation(&mut self, propagation: Option<Propagation>) -> Result<()> {
+        if let Some(p) = propagation
+            && self.id.is_some()
+        {
+            p.apply(self.target.as_path())?;
+        }
+        self.propagation = propagation;
+        Ok(())
+    }
+
+    fn unmount_mode(&self) -> UnmountMode {
+        self.unmount_mode
+    }
+
+    fn set_unmount_mode(&mut self, mode: UnmountMode) {
+        self.unmount_mode = mode;
+    }
 }
 
 impl StackableFilesystem for OverlayFs {
@@ -274,6 +876,11 @@ impl StackableFilesystem for OverlayFs {
             ));
         }
         self.lower = lower.into();
+        #[cfg(feature = "integrity")]
+        prune_manifests(
+            &mut self.manifests,
+            self.lower.iter().chain(self.data.iter()),
+        );
         Ok(())
     }
 
@@ -314,6 +921,7 @@ impl StateRecovery for OverlayFs {
             "OverlayFs not found at mount point : ".to_string() + &path.to_string_lossy(),
         ))?;
         let mut lower = vec![];
+        let mut data_lower = vec![];
         let mut upper = None;
         let mut work = None;
         let target = path.as_cstring();
@@ -329,8 +937,14 @@ impl StateRecovery for OverlayFs {
                     };
                     match o {
                         "lowerdir" => {
-                            for path in va.split(':') {
-                                lower.push(PathBuf::from(path))
+                            let mut layers = va.split("::");
+                            if let Some(regular) = layers.next() {
+                                for path in regular.split(':') {
+                                    lower.push(PathBuf::from(path))
+                                }
+                            }
+                            for path in layers {
+                                data_lower.push(PathBuf::from(path))
                             }
                             return None;
                         }
@@ -348,12 +962,33 @@ impl StateRecovery for OverlayFs {
                 Some(x.to_owned())
             })
             .collect();
+        let mut images = ImageLayers::default();
+        images.recover(&mut lower)?;
+        images.recover(&mut data_lower)?;
+        // layers exposed through idmapped mounts live in a staging directory
+        let staging = lower
+            .first()
+            .and_then(|x| x.parent())
+            .filter(|x| {
+                x.file_name()
+                    .is_some_and(|x| x.to_string_lossy().starts_with(IDMAP_STAGING))
+            })
+            .map(|x| x.to_path_buf());
         Ok(Self {
             lower,
+            data: data_lower,
             upper,
             work,
             target,
             options,
+            propagation: Propagation::of(path).ok(),
+            unmount_mode: UnmountMode::default(),
+            scratch: None,
+            images,
+            #[cfg(feature = "integrity")]
+            manifests: vec![],
+            idmap: None,
+            staging,
             id: Some(
                 PartitionID::try_from(path)
                     .map_err(|_| Error::other("unable to get PartitionID"))?,
@@ -363,6 +998,50 @@ impl StateRecovery for OverlayFs {
     }
 }
 
+impl TryFrom<StackDescription<OverlayFsOption>> for OverlayFs {
+    type Error = Error;
+
+    fn try_from(value: StackDescription<OverlayFsOption>) -> Result<Self> {
+        value.check_supported("overlay FileSystem", false, true, true)?;
+        let options = value.checked_options()?;
+        let mut handle = match (&value.upper, &value.work) {
+            (Some(upper), Some(work)) => {
+                Self::writable(value.lower.iter(), upper, work, &value.target)?
+            }
+            (None, None) => Self::readonly(value.lower.iter(), &value.target)?,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "overlay FileSystem need both upper and work directory to be writable",
+                ));
+            }
+        };
+        handle.data = value.data;
+        handle.options = options;
+        handle.idmap = value.idmap;
+        handle.propagation = value.propagation;
+        handle.drop = value.drop;
+        Ok(handle)
+    }
+}
+
+impl From<&OverlayFs> for StackDescription<OverlayFsOption> {
+    fn from(value: &OverlayFs) -> Self {
+        Self {
+            lower: value.lower.clone(),
+            writable_lower: vec![],
+            data: value.data.clone(),
+            upper: value.upper.clone(),
+            work: value.work.clone(),
+            target: value.target.as_path().to_path_buf(),
+            options: value.options.clone(),
+            idmap: value.idmap.clone(),
+            propagation: value.propagation,
+            drop: value.drop,
+        }
+    }
+}
+
 impl Drop for OverlayFs {
     #[inline]
     fn drop(&mut self) {
@@ -9,6 +9,7 @@ use crate::{FsOption, MountOption};
 use std::{fmt::Display, str::FromStr};
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum RedirectDir {
     On,
     Follow,
@@ -23,6 +24,7 @@ impl From<RedirectDir> for MountOption<OverlayFsOption> {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum FsVerity {
     On,
     Require,
@@ -36,6 +38,7 @@ impl From<FsVerity> for MountOption<OverlayFsOption> {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Xino {
     On,
     Auto,
@@ -43,6 +46,7 @@ pub enum Xino {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Uuid {
     On,
     Null,
@@ -56,6 +60,7 @@ impl From<Xino> for MountOption<OverlayFsOption> {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum OverlayFsOption {
     /// ### On
     /// Redirects are enabled.
@@ -158,9 +163,9 @@ impl FromStr for OverlayFsOption {
                     "off" => return Ok(Self::Metacopy(false)),
                     _ => {}
                 },
-                "fs_verify" => match va {
+                "verity" => match va {
                     "on" => return Ok(OverlayFsOption::FsVerity(FsVerity::On)),
-                    "required" => return Ok(Self::FsVerity(FsVerity::Require)),
+                    "require" | "required" => return Ok(Self::FsVerity(FsVerity::Require)),
                     "off" => return Ok(Self::FsVerity(FsVerity::Off)),
                     _ => {}
                 },
@@ -216,7 +221,7 @@ impl Display for OverlayFsOption {
                 },
                 OverlayFsOption::FsVerity(o) => match o {
                     FsVerity::On => "verity=on",
-                    FsVerity::Require => "verity=required",
+                    FsVerity::Require => "verity=require",
                     FsVerity::Off => "verity=off",
                 },
                 OverlayFsOption::Index(o) => match o {
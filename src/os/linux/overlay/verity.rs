@@ -0,0 +1,293 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use std::{
+    ffi::{CString, OsString},
+    fs::{self, File, Metadata},
+    io::{Error, ErrorKind, Result},
+    os::{fd::AsRawFd, unix::ffi::OsStringExt},
+    path::{Path, PathBuf},
+};
+
+use nix::libc::{self, c_ulong};
+use tracing::debug;
+
+use crate::{AsCString, os::linux::tmpfs::read_xattrs};
+
+const FS_IOC_ENABLE_VERITY: c_ulong = 0x40806685;
+const FS_IOC_MEASURE_VERITY: c_ulong = 0xC0046686;
+const FS_VERITY_HASH_ALG_SHA256: u16 = 1;
+const FS_VERITY_MAX_DIGEST_SIZE: usize = 64;
+const FS_VERITY_BLOCK_SIZE: u32 = 4096;
+
+/// Namespace of the overlay xattrs
+pub(super) const OVL_XATTR_TRUSTED: &str = "trusted.overlay.";
+/// Namespace of the overlay xattrs when mounted with userxattr
+pub(super) const OVL_XATTR_USER: &str = "user.overlay.";
+/// Overlay xattr marking a metacopy file, optionally holding the digest of its data file
+const OVL_XATTR_METACOPY: &str = "metacopy";
+/// version, length, flags and digest algorithm
+const OVL_METACOPY_HEADER: usize = 4;
+
+#[repr(C)]
+struct FsVerityEnableArg {
+    version: u32,
+    hash_algorithm: u32,
+    block_size: u32,
+    salt_size: u32,
+    salt_ptr: u64,
+    sig_size: u32,
+    reserved1: u32,
+    sig_ptr: u64,
+    reserved2: [u64; 11],
+}
+
+#[repr(C)]
+struct FsVerityDigest {
+    algorithm: u16,
+    size: u16,
+    digest: [u8; FS_VERITY_MAX_DIGEST_SIZE],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// fs-verity file digest
+pub struct VerityDigest {
+    /// Hash algorithm, 1 for SHA-256 and 2 for SHA-512
+    pub algorithm: u16,
+    pub digest: Vec<u8>,
+}
+
+/// Enable fs-verity on a regular file, return false when it was already enabled
+///
+/// The file become read-only, the underlying filesystem must support fs-verity
+pub fn enable_verity(file: impl AsRef<Path>) -> Result<bool> {
+    let file = File::open(file)?;
+    let arg = FsVerityEnableArg {
+        version: 1,
+        hash_algorithm: FS_VERITY_HASH_ALG_SHA256 as u32,
+        block_size: FS_VERITY_BLOCK_SIZE,
+        salt_size: 0,
+        salt_ptr: 0,
+        sig_size: 0,
+        reserved1: 0,
+        sig_ptr: 0,
+        reserved2: [0; 11],
+    };
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_ENABLE_VERITY, &arg) } < 0 {
+        let err = Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::EEXIST) => Ok(false),
+            Some(libc::ENOTTY | libc::EOPNOTSUPP) => Err(Error::new(
+                ErrorKind::Unsupported,
+                "filesystem doesn't support fs-verity",
+            )),
+            _ => Err(err),
+        };
+    }
+    Ok(true)
+}
+
+/// Measure the fs-verity digest of a file, none when fs-verity isn't enabled on it
+pub fn measure_verity(file: impl AsRef<Path>) -> Result<Option<VerityDigest>> {
+    let file = File::open(file)?;
+    let mut arg = FsVerityDigest {
+        algorithm: 0,
+        size: FS_VERITY_MAX_DIGEST_SIZE as u16,
+        digest: [0; FS_VERITY_MAX_DIGEST_SIZE],
+    };
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_MEASURE_VERITY, &mut arg) } < 0 {
+        let err = Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENODATA) => Ok(None),
+            Some(libc::ENOTTY | libc::EOPNOTSUPP) => Err(Error::new(
+                ErrorKind::Unsupported,
+                "filesystem doesn't support fs-verity",
+            )),
+            _ => Err(err),
+        };
+    }
+    Ok(Some(VerityDigest {
+        algorithm: arg.algorithm,
+        digest: arg.digest[..arg.size as usize].to_vec(),
+    }))
+}
+
+/// Enable fs-verity on every regular file of a layer, return how many were newly enabled
+pub fn enable_verity_layer(layer: impl AsRef<Path>) -> Result<usize> {
+    let mut count = 0;
+    walk_files(layer.as_ref(), &mut |path, meta| {
+        if meta.is_file() && enable_verity(path)? {
+            count += 1;
+        }
+        Ok(())
+    })?;
+    debug!(
+        "Damascus: enabled fs-verity on {} files of {:?}",
+        count,
+        layer.as_ref()
+    );
+    Ok(count)
+}
+
+/// Digest stored in an overlay metacopy file
+///
+/// Return none when the file isn't a metacopy file, or Some(None) when it hold no digest.
+/// Only trusted xattrs are read, use OverlayFs::metacopy_digest for layers of an overlay
+/// mounted with userxattr
+pub fn metacopy_digest(file: impl AsRef<Path>) -> Result<Option<Option<VerityDigest>>> {
+    metacopy_digest_in(file.as_ref(), OVL_XATTR_TRUSTED)
+}
+
+/// Digest stored in an overlay metacopy file using the given xattr namespace
+pub(super) fn metacopy_digest_in(
+    file: &Path,
+    prefix: &str,
+) -> Result<Option<Option<VerityDigest>>> {
+    Ok(overlay_xattr(file, prefix, OVL_XATTR_METACOPY)?.map(|x| decode_metacopy(&x)))
+}
+
+/// Path of the file holding the data of a metacopy or renamed file, absolute ones start
+/// from the layer root while relative ones are in the same directory
+pub(super) fn redirect(file: &Path, prefix: &str) -> Result<Option<PathBuf>> {
+    Ok(overlay_xattr(file, prefix, "redirect")?.map(|x| PathBuf::from(OsString::from_vec(x))))
+}
+
+fn overlay_xattr(file: &Path, prefix: &str, name: &str) -> Result<Option<Vec<u8>>> {
+    let name = format!("{}{}", prefix, name);
+    Ok(read_xattrs(file)?
+        .into_iter()
+        .find(|(x, _)| x.as_bytes() == name.as_bytes())
+        .map(|(_, value)| value))
+}
+
+/// Store the digest of the data file in an overlay metacopy file, as required by verity=require
+///
+/// Only trusted xattrs are written, use OverlayFs::set_metacopy_digest for layers of an
+/// overlay mounted with userxattr
+pub fn set_metacopy_digest(file: impl AsRef<Path>, digest: &VerityDigest) -> Result<()> {
+    set_metacopy_digest_in(file.as_ref(), OVL_XATTR_TRUSTED, digest)
+}
+
+/// Store the digest of the data file in an overlay metacopy file using the given xattr namespace
+pub(super) fn set_metacopy_digest_in(
+    file: &Path,
+    prefix: &str,
+    digest: &VerityDigest,
+) -> Result<()> {
+    let value = encode_metacopy(digest)?;
+    let file = file.as_cstring();
+    let name = CString::new(format!("{}{}", prefix, OVL_XATTR_METACOPY))?;
+    if unsafe {
+        libc::lsetxattr(
+            file.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    } < 0
+    {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn encode_metacopy(digest: &VerityDigest) -> Result<Vec<u8>> {
+    let (Ok(algorithm), true) = (
+        u8::try_from(digest.algorithm),
+        digest.digest.len() <= FS_VERITY_MAX_DIGEST_SIZE,
+    ) else {
+        return Err(Error::new(ErrorKind::InvalidInput, "invalid verity digest"));
+    };
+    let mut value = vec![
+        0,
+        (OVL_METACOPY_HEADER + digest.digest.len()) as u8,
+        0,
+        algorithm,
+    ];
+    value.extend_from_slice(&digest.digest);
+    Ok(value)
+}
+
+fn decode_metacopy(value: &[u8]) -> Option<VerityDigest> {
+    let len = *value.get(1)? as usize;
+    if value.len() < len || len <= OVL_METACOPY_HEADER {
+        return None;
+    }
+    Some(VerityDigest {
+        algorithm: value[3] as u16,
+        digest: value[OVL_METACOPY_HEADER..len].to_vec(),
+    })
+}
+
+/// Call f on every entry below dir without following symlinks
+pub(super) fn walk_files(
+    dir: &Path,
+    f: &mut impl FnMut(&Path, &Metadata) -> Result<()>,
+) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let meta = fs::symlink_metadata(&path)?;
+        f(&path, &meta)?;
+        if meta.is_dir() {
+            walk_files(&path, f)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::overlay::{FsVerity, OverlayFsOption};
+
+    #[test]
+    fn metacopy_digest_roundtrip() {
+        let digest = VerityDigest {
+            algorithm: FS_VERITY_HASH_ALG_SHA256,
+            digest: vec![0xAB; 32],
+        };
+        let value = encode_metacopy(&digest).unwrap();
+        assert_eq!(&value[..4], &[0, 36, 0, 1]);
+        assert_eq!(decode_metacopy(&value), Some(digest));
+        // metacopy files created without verity hold an empty value
+        assert_eq!(decode_metacopy(&[]), None);
+    }
+
+    #[test]
+    fn metacopy_digest_namespace() {
+        use temp_testdir::TempDir;
+        let dir = TempDir::default();
+        let file = dir.join("meta");
+        File::create(&file).unwrap();
+        let digest = VerityDigest {
+            algorithm: FS_VERITY_HASH_ALG_SHA256,
+            digest: vec![0xCD; 32],
+        };
+        if let Err(e) = set_metacopy_digest_in(&file, OVL_XATTR_USER, &digest) {
+            assert_eq!(e.raw_os_error(), Some(libc::EOPNOTSUPP));
+            return;
+        }
+        assert_eq!(
+            metacopy_digest_in(&file, OVL_XATTR_USER).unwrap(),
+            Some(Some(digest))
+        );
+        assert_eq!(metacopy_digest_in(&file, OVL_XATTR_TRUSTED).unwrap(), None);
+    }
+
+    #[test]
+    fn parse_verity_option() {
+        let opt = OverlayFsOption::from_str("verity=require").unwrap();
+        assert_eq!(opt, OverlayFsOption::FsVerity(FsVerity::Require));
+        assert_eq!(opt.to_string(), "verity=require");
+        assert!(OverlayFsOption::from_str("fs_verify=on").is_err());
+    }
+}
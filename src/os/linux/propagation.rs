@@ -0,0 +1,156 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use std::{
+    fmt::Display,
+    io::{Error, ErrorKind, Result},
+    path::Path,
+    str::FromStr,
+};
+
+use nix::mount::{MsFlags, mount};
+
+use super::mountinfo::MountInfo;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// Propagation type of a mount point, see mount_namespaces(7)
+pub enum Propagation {
+    /// Mount events are neither received nor forwarded
+    Private,
+    /// Mount events are received from the master peer group but never forwarded
+    Slave,
+    /// Mount events are forwarded to and received from the peer group
+    Shared,
+    /// Mount events are received from the master peer group and forwarded to the peer group
+    SharedSlave,
+    /// Private and cannot be bind mounted
+    Unbindable,
+}
+
+impl Propagation {
+    #[inline]
+    fn flags(&self) -> MsFlags {
+        match self {
+            Propagation::Private => MsFlags::MS_PRIVATE,
+            Propagation::Slave => MsFlags::MS_SLAVE,
+            Propagation::Shared | Propagation::SharedSlave => MsFlags::MS_SHARED,
+            Propagation::Unbindable => MsFlags::MS_UNBINDABLE,
+        }
+    }
+
+    /// Change the propagation type of an existing mount point
+    pub(crate) fn apply(&self, target: &Path) -> Result<()> {
+        // a mount has to become a slave before joining a new peer group
+        if *self == Propagation::SharedSlave {
+            Propagation::Slave.apply(target)?;
+        }
+        mount(
+            None::<&str>,
+            target,
+            None::<&str>,
+            self.flags(),
+            None::<&str>,
+        )?;
+        Ok(())
+    }
+
+    /// Deduce propagation type from the optional fields of a mountinfo entry
+    pub(crate) fn from_optional(optional: &[String]) -> Self {
+        let master = optional.iter().any(|x| x.starts_with("master:"));
+        let shared = optional.iter().any(|x| x.starts_with("shared:"));
+        if master && shared {
+            Propagation::SharedSlave
+        } else if master {
+            Propagation::Slave
+        } else if shared {
+            Propagation::Shared
+        } else if optional.iter().any(|x| x == "unbindable") {
+            Propagation::Unbindable
+        } else {
+            Propagation::Private
+        }
+    }
+
+    /// Retrieve the propagation type of a mount point from the system information
+    pub fn of(target: impl AsRef<Path>) -> Result<Self> {
+        let target = target.as_ref();
+        MountInfo::find(target)?
+            .map(|x| Self::from_optional(&x.optional))
+            .ok_or(Error::new(
+                ErrorKind::NotFound,
+                "no mount point at : ".to_string() + &target.to_string_lossy(),
+            ))
+    }
+}
+
+impl FromStr for Propagation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "private" => Propagation::Private,
+            "slave" => Propagation::Slave,
+            "shared" => Propagation::Shared,
+            "shared-slave" => Propagation::SharedSlave,
+            "unbindable" => Propagation::Unbindable,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Invalid mount propagation",
+                ));
+            }
+        })
+    }
+}
+
+impl Display for Propagation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            Propagation::Private => "private",
+            Propagation::Slave => "slave",
+            Propagation::Shared => "shared",
+            Propagation::SharedSlave => "shared-slave",
+            Propagation::Unbindable => "unbindable",
+        };
+        write!(f, "{}", str)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mountinfo() {
+        let content = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+40 22 0:35 / /mnt/my\\040game rw,relatime shared:20 master:1 - overlay overlay rw,lowerdir=/a:/b
+41 22 0:36 / /mnt/other rw,relatime - tmpfs tmpfs rw
+42 41 0:37 / /mnt/other rw,relatime unbindable - tmpfs tmpfs rw
+43 22 0:38 / /srv rw,relatime shared:21 - tmpfs tmpfs rw
+44 22 0:39 / /opt rw,relatime master:21 - tmpfs tmpfs rw";
+        let info = MountInfo::parse(content);
+        let find = |x: &str| {
+            info.iter()
+                .rfind(|m| m.mount_point == Path::new(x))
+                .map(|m| Propagation::from_optional(&m.optional))
+        };
+        assert_eq!(find("/mnt/my game"), Some(Propagation::SharedSlave));
+        assert_eq!(find("/opt"), Some(Propagation::Slave));
+        assert_eq!(find("/mnt/other"), Some(Propagation::Unbindable));
+        assert_eq!(find("/srv"), Some(Propagation::Shared));
+        assert_eq!(find("/nowhere"), None);
+        for propagation in [Propagation::Slave, Propagation::SharedSlave] {
+            assert_eq!(
+                Propagation::from_str(&propagation.to_string()).unwrap(),
+                propagation
+            );
+        }
+    }
+}
@@ -0,0 +1,95 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use std::{
+    fs::{create_dir_all, remove_dir},
+    io::{Error, ErrorKind, Result},
+    os::fd::AsFd,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use nix::{libc, mount::MntFlags};
+use tracing::{debug, error};
+
+use super::mount_api::{move_mount_beneath, open_tree};
+use crate::Version;
+
+/// Prefix of the directories the replacing stack is mounted on
+const SWAP_STAGING: &str = "damascus-swap-";
+
+/// Mount a replacement stack through mount then swap it with the stack mounted at target
+///
+/// mount is called with the staging directory the replacement must be mounted on, unmount
+/// with the mountpoints to detach and the flags to use
+pub(crate) fn hot_swap(
+    target: &Path,
+    lower: &[PathBuf],
+    writable: bool,
+    mount: impl FnOnce(&Path) -> Result<()>,
+    unmount: impl Fn(&Path, MntFlags) -> Result<()>,
+) -> Result<()> {
+    if writable {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "hot swap is only supported by read-only stacks, upper and work directories cannot be shared by two mounts",
+        ));
+    }
+    // the bare directory is hidden by the current stack and cannot be reached again
+    if lower.iter().any(|x| x == target) {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "hot swap is not supported when mounted on top of a lower directory",
+        ));
+    }
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let staging = std::env::temp_dir().join(format!(
+        "{}{}-{}",
+        SWAP_STAGING,
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    create_dir_all(&staging)?;
+    let res = mount(&staging).and_then(|_| {
+        swap_mount(&staging, target, &unmount).inspect_err(|_| {
+            if let Err(err) = unmount(&staging, MntFlags::MNT_DETACH) {
+                error!(
+                    "Damascus: unable to unmount staged stack at {:?} because : {}",
+                    staging, err
+                )
+            }
+        })
+    });
+    let _ = remove_dir(&staging);
+    res
+}
+
+/// Attach a clone of the mount at staging beneath the one at target then lazily detach the
+/// latter, processes using it keep their view while new lookups reach the replacement
+fn swap_mount(
+    staging: &Path,
+    target: &Path,
+    unmount: &impl Fn(&Path, MntFlags) -> Result<()>,
+) -> Result<()> {
+    let tree = open_tree(staging, false)?;
+    move_mount_beneath(tree.as_fd(), target).map_err(|err| {
+        if err.raw_os_error() == Some(libc::EINVAL)
+            && Version::kernel().is_ok_and(|x| x < Version::new(6, 5, 0))
+        {
+            Error::new(
+                ErrorKind::Unsupported,
+                "hot swap require kernel 6.5 or later",
+            )
+        } else {
+            err
+        }
+    })?;
+    unmount(target, MntFlags::MNT_DETACH)?;
+    unmount(staging, MntFlags::MNT_DETACH)?;
+    debug!("Damascus: swapped stack at {:?}", target);
+    Ok(())
+}
@@ -0,0 +1,436 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    ffi::CString,
+    fs::{self, Permissions},
+    io::{Error, Result},
+    os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt, lchown, symlink},
+    path::{Path, PathBuf},
+};
+
+use nix::{
+    libc,
+    mount::{MsFlags, mount, umount2},
+    sys::stat::{Mode, SFlag, mknod},
+};
+use tracing::{debug, error};
+
+use super::busy::unmount_with;
+use crate::{AsCString, AsPath, Filesystem, PartitionID, UnmountMode};
+
+#[derive(Debug)]
+/// Memory backed filesystem handle, content is lost once unmounted
+pub struct Tmpfs {
+    target: CString,
+    size: Option<u64>,
+    inodes: Option<u64>,
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    unmount_mode: UnmountMode,
+    id: Option<PartitionID>,
+    drop: bool,
+}
+
+impl Tmpfs {
+    #[must_use = "initialised Tmpfs handle should be used"]
+    #[inline]
+    pub fn new<T: AsRef<Path>>(target: T) -> Self {
+        Self {
+            target: target.as_ref().as_cstring(),
+            size: None,
+            inodes: None,
+            mode: None,
+            uid: None,
+            gid: None,
+            unmount_mode: UnmountMode::default(),
+            id: None,
+            drop: true,
+        }
+    }
+
+    /// Maximum size in bytes, half of the RAM when unset
+    #[inline]
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    #[inline]
+    pub fn set_size(&mut self, size: Option<u64>) -> Result<()> {
+        self.ensure_unmounted()?;
+        self.size = size;
+        Ok(())
+    }
+
+    /// Maximum number of inodes
+    #[inline]
+    pub fn inodes(&self) -> Option<u64> {
+        self.inodes
+    }
+
+    #[inline]
+    pub fn set_inodes(&mut self, inodes: Option<u64>) -> Result<()> {
+        self.ensure_unmounted()?;
+        self.inodes = inodes;
+        Ok(())
+    }
+
+    /// Permissions of the root directory
+    #[inline]
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    #[inline]
+    pub fn set_mode(&mut self, mode: Option<u32>) -> Result<()> {
+        self.ensure_unmounted()?;
+        self.mode = mode;
+        Ok(())
+    }
+
+    /// Owner of the root directory
+    #[inline]
+    pub fn uid(&self) -> Option<u32> {
+        self.uid
+    }
+
+    #[inline]
+    pub fn set_uid(&mut self, uid: Option<u32>) -> Result<()> {
+        self.ensure_unmounted()?;
+        self.uid = uid;
+        Ok(())
+    }
+
+    /// Group of the root directory
+    #[inline]
+    pub fn gid(&self) -> Option<u32> {
+        self.gid
+    }
+
+    #[inline]
+    pub fn set_gid(&mut self, gid: Option<u32>) -> Result<()> {
+        self.ensure_unmounted()?;
+        self.gid = gid;
+        Ok(())
+    }
+
+    /// Behavior of unmount when processes still use the mount point
+    #[inline]
+    pub fn unmount_mode(&self) -> UnmountMode {
+        self.unmount_mode
+    }
+
+    /// Set the behavior of unmount, lazy by default
+    #[inline]
+    pub fn set_unmount_mode(&mut self, mode: UnmountMode) {
+        self.unmount_mode = mode;
+    }
+
+    /// Copy the whole content to dest, to be called before teardown to keep it
+    pub fn export(&self, dest: impl AsRef<Path>) -> Result<()> {
+        if self.id.is_none() {
+            return Err(Error::other("tmpfs is not mounted"));
+        }
+        copy_tree(self.target.as_path(), dest.as_ref())
+    }
+
+    #[inline]
+    fn ensure_unmounted(&self) -> Result<()> {
+        if self.id.is_some() {
+            return Err(Error::other(
+                "tmpfs options cannot be change when the FileSystem is mounted",
+            ));
+        }
+        Ok(())
+    }
+
+    fn mount_data(&self) -> String {
+        let mut data = vec![];
+        if let Some(size) = self.size {
+            data.push(format!("size={}", size));
+        }
+        if let Some(inodes) = self.inodes {
+            data.push(format!("nr_inodes={}", inodes));
+        }
+        if let Some(mode) = self.mode {
+            data.push(format!("mode={:o}", mode));
+        }
+        if let Some(uid) = self.uid {
+            data.push(format!("uid={}", uid));
+        }
+        if let Some(gid) = self.gid {
+            data.push(format!("gid={}", gid));
+        }
+        data.join(",")
+    }
+}
+
+impl Filesystem for Tmpfs {
+    fn mount(&mut self) -> Result<PathBuf> {
+        if matches!(self.id,Some(x) if x == PartitionID::try_from(self.target.as_path())?) {
+            debug!("Damascus: partition already mounted");
+            return Ok(self.target.as_path().to_path_buf());
+        }
+        let data = self.mount_data();
+        debug!("Damascus: mount tmpfs with options : {}", data);
+        mount(
+            Some(c"tmpfs"),
+            self.target.as_c_str(),
+            Some(c"tmpfs"),
+            MsFlags::empty(),
+            Some(data.as_str()),
+        )?;
+        self.id = Some(
+            PartitionID::try_from(self.target.as_path())
+                .map_err(|_| Error::other("unable to get PartitionID"))?,
+        );
+        Ok(self.target.as_path().to_path_buf())
+    }
+
+    fn unmount(&mut self) -> Result<()> {
+        if matches!(self.id,Some(x) if x == PartitionID::try_from(self.target.as_path())?) {
+            unmount_with(self.target.as_path(), self.unmount_mode, |flags| {
+                Ok(umount2(self.target.as_c_str(), flags)?)
+            })?;
+            self.id = None;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn unmount_on_drop(&self) -> bool {
+        self.drop
+    }
+
+    #[inline]
+    fn set_unmount_on_drop(&mut self, drop: bool) {
+        self.drop = drop;
+    }
+
+    #[inline]
+    fn id(&self) -> Option<&PartitionID> {
+        self.id.as_ref()
+    }
+
+    #[inline]
+    fn target(&self) -> PathBuf {
+        self.target.as_path().to_path_buf()
+    }
+
+    #[inline]
+    fn set_target(&mut self, target: impl AsRef<Path>) -> Result<()> {
+        self.ensure_unmounted()?;
+        self.target = target.as_ref().as_cstring();
+        Ok(())
+    }
+
+    #[inline]
+    fn is_available() -> bool {
+        fs::read_to_string("/proc/filesystems")
+            .map(|x| x.lines().any(|l| l.ends_with("\ttmpfs")))
+            .unwrap_or(false)
+    }
+}
+
+impl Drop for Tmpfs {
+    #[inline]
+    fn drop(&mut self) {
+        if self.drop
+            && let Err(err) = self.unmount()
+        {
+            error!(
+                "Damascus: unable to unmount tmpfs at {:?} because : {}",
+                self.target, err
+            )
+        }
+    }
+}
+
+/// Mount scratch then create upper and work directories on it
+#[cfg(any(feature = "overlayfs", feature = "fuse-overlayfs"))]
+pub(crate) fn scratch_dirs(scratch: &mut Tmpfs) -> Result<(PathBuf, PathBuf)> {
+    let root = scratch.mount()?;
+    let upper = root.join("upper");
+    let work = root.join("work");
+    fs::create_dir_all(&upper)?;
+    fs::create_dir_all(&work)?;
+    Ok((upper, work))
+}
+
+/// Recursively copy a directory, preserving symlinks, whiteouts, hardlinks, ownership,
+/// permissions and extended attributes so an overlay upper directory stay meaningful
+///
+/// Ownership is only restored when the process is allowed to, otherwise copies belong to
+/// the current user
+pub(crate) fn copy_tree(src: &Path, dest: &Path) -> Result<()> {
+    copy_entry(src, dest, &mut HashMap::new())
+}
+
+/// Copy a single entry, links map inodes with several names to their first copy
+fn copy_entry(src: &Path, dest: &Path, links: &mut HashMap<(u64, u64), PathBuf>) -> Result<()> {
+    let meta = fs::symlink_metadata(src)?;
+    let ty = meta.file_type();
+    if !ty.is_dir() && meta.nlink() > 1 {
+        match links.entry((meta.dev(), meta.ino())) {
+            Entry::Occupied(first) => return fs::hard_link(first.get(), dest),
+            Entry::Vacant(x) => {
+                x.insert(dest.to_path_buf());
+            }
+        }
+    }
+    if ty.is_dir() {
+        fs::create_dir_all(dest)?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            copy_entry(&entry.path(), &dest.join(entry.file_name()), links)?;
+        }
+    } else if ty.is_symlink() {
+        symlink(fs::read_link(src)?, dest)?;
+        return copy_owner(&meta, dest);
+    } else if ty.is_char_device() || ty.is_block_device() || ty.is_fifo() {
+        let kind = if ty.is_char_device() {
+            SFlag::S_IFCHR
+        } else if ty.is_block_device() {
+            SFlag::S_IFBLK
+        } else {
+            SFlag::S_IFIFO
+        };
+        mknod(
+            dest,
+            kind,
+            Mode::from_bits_truncate(meta.mode()),
+            meta.rdev(),
+        )?;
+    } else {
+        fs::copy(src, dest)?;
+    }
+    // changing owner clear setuid and setgid bits, permissions must come after
+    copy_owner(&meta, dest)?;
+    fs::set_permissions(dest, Permissions::from_mode(meta.mode()))?;
+    copy_xattrs(src, dest)
+}
+
+/// Give dest the owner of the source, skipped when not permitted
+fn copy_owner(meta: &fs::Metadata, dest: &Path) -> Result<()> {
+    match lchown(dest, Some(meta.uid()), Some(meta.gid())) {
+        Err(err) if err.raw_os_error() == Some(libc::EPERM) => Ok(()),
+        res => res,
+    }
+}
+
+/// Copy extended attributes, overlay keep opaque directories and redirects in them
+///
+/// Attributes outside of the user namespace which cannot be set, because of missing
+/// privilege or support from the destination filesystem, are skipped
+fn copy_xattrs(src: &Path, dest: &Path) -> Result<()> {
+    let dest_c = dest.as_cstring();
+    for (name, value) in read_xattrs(src)? {
+        if unsafe {
+            libc::lsetxattr(
+                dest_c.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        } < 0
+        {
+            let err = Error::last_os_error();
+            if !name.as_bytes().starts_with(b"user.")
+                && matches!(err.raw_os_error(), Some(libc::EPERM | libc::ENOTSUP))
+            {
+                debug!(
+                    "Damascus: skipped extended attribute {:?} of {:?} because : {}",
+                    name, dest, err
+                );
+                continue;
+            }
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// List extended attributes of a path without following symlinks
+pub(crate) fn read_xattrs(path: &Path) -> Result<Vec<(CString, Vec<u8>)>> {
+    let path = path.as_cstring();
+    let len = unsafe { libc::llistxattr(path.as_ptr(), std::ptr::null_mut(), 0) };
+    if len <= 0 {
+        return Ok(vec![]);
+    }
+    let mut names = vec![0u8; len as usize];
+    let len = unsafe { libc::llistxattr(path.as_ptr(), names.as_mut_ptr().cast(), names.len()) };
+    if len < 0 {
+        return Err(Error::last_os_error());
+    }
+    let mut xattrs = vec![];
+    for name in names[..len as usize]
+        .split(|x| *x == 0)
+        .filter(|x| !x.is_empty())
+    {
+        let name = CString::new(name).map_err(Error::other)?;
+        let len = unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if len < 0 {
+            return Err(Error::last_os_error());
+        }
+        let mut value = vec![0u8; len as usize];
+        let len = unsafe {
+            libc::lgetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            )
+        };
+        if len < 0 {
+            return Err(Error::last_os_error());
+        }
+        value.truncate(len as usize);
+        xattrs.push((name, value));
+    }
+    Ok(xattrs)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use temp_testdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn render_mount_data() {
+        let mut tmp = Tmpfs::new("/tmp/nowhere");
+        assert_eq!(tmp.mount_data(), "");
+        tmp.set_size(Some(64 * 1024 * 1024)).unwrap();
+        tmp.set_inodes(Some(4096)).unwrap();
+        tmp.set_mode(Some(0o755)).unwrap();
+        tmp.set_uid(Some(1000)).unwrap();
+        tmp.set_gid(Some(100)).unwrap();
+        assert_eq!(
+            tmp.mount_data(),
+            "size=67108864,nr_inodes=4096,mode=755,uid=1000,gid=100"
+        );
+    }
+
+    #[test]
+    fn copy_tree_keep_hardlinks() {
+        let base = TempDir::default();
+        let (src, dest) = (base.join("src"), base.join("dest"));
+        fs::create_dir_all(src.join("dir")).unwrap();
+        fs::write(src.join("file"), b"content").unwrap();
+        fs::hard_link(src.join("file"), src.join("dir").join("link")).unwrap();
+        copy_tree(&src, &dest).unwrap();
+        let file = fs::metadata(dest.join("file")).unwrap();
+        let link = fs::metadata(dest.join("dir").join("link")).unwrap();
+        assert_eq!(file.ino(), link.ino());
+        assert_eq!(file.nlink(), 2);
+        assert_eq!(file.uid(), fs::metadata(src.join("file")).unwrap().uid());
+    }
+}
@@ -10,29 +10,64 @@
 * https://github.com/tailhook/libmount/blob/master/src/overlay.rs
 */
 
+mod branch;
 mod opt;
+pub use branch::*;
 pub use opt::*;
 
+use nix::{
+    sys::resource::{Resource, getrlimit},
+    unistd::geteuid,
+};
 use std::{
     ffi::CString,
     io::{Error, ErrorKind, Result},
     path::{Path, PathBuf},
     process::Command,
+    str::FromStr,
+    sync::OnceLock,
+    time::Duration,
 };
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
+use super::{
+    busy::{fusermount, unmount_with},
+    mountinfo::{FUSE_TIMEOUT, MountInfo},
+    swap,
+};
 use crate::os::set_option_helper;
 use crate::{
-    AsCString, AsPath, Filesystem, LinuxFilesystem, MountOption, PartitionID, StackableFilesystem,
+    AsCString, AsPath, Filesystem, LinuxFilesystem, MountOption, PartitionID, Propagation,
+    StackDescription, StackableFilesystem, StateRecovery, UnmountMode, Version,
 };
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Features supported by a unionfs-fuse release
+pub struct UnionFsFuseFeatures {
+    /// Built against FUSE 3 which mount on non-empty directories, older releases need the
+    /// nonempty option
+    pub fuse3: bool,
+}
+
+impl From<Version> for UnionFsFuseFeatures {
+    fn from(value: Version) -> Self {
+        Self {
+            // unionfs-fuse moved to FUSE 3 with its 3.0 release
+            fuse3: value >= Version::new(3, 0, 0),
+        }
+    }
+}
+
 #[derive(Debug)]
 /// Unionfs fuse filesystem handle
 pub struct UnionFsFuse {
-    lower: Vec<PathBuf>,
-    upper: Option<PathBuf>,
+    /// Branches ordered from the highest to the lowest priority
+    branches: Vec<Branch>,
     target: CString,
     options: Vec<MountOption<UnionFsFuseOption>>,
+    propagation: Option<Propagation>,
+    unmount_mode: UnmountMode,
+    timeout: Duration,
     id: Option<PartitionID>,
     drop: bool,
 }
@@ -52,12 +87,13 @@ impl UnionFsFuse {
         B: Into<PathBuf>,
         D: AsRef<Path>,
     {
-        let lower: Vec<PathBuf> = lower.map(|x| x.to_path_buf()).collect();
         Ok(Self {
-            lower,
-            upper: upper.map(|x| x.into()),
+            branches: Self::stack(lower, upper.map(|x| x.into())),
             target: target.as_ref().as_cstring(),
             options: MountOption::defaults(),
+            propagation: None,
+            unmount_mode: UnmountMode::default(),
+            timeout: FUSE_TIMEOUT,
             id: None,
             drop,
         })
@@ -79,10 +115,12 @@ impl UnionFsFuse {
             ));
         }
         Ok(Self {
-            lower,
-            upper: None,
+            branches: Self::stack(lower.iter(), None),
             target: target.as_ref().as_cstring(),
             options: MountOption::defaults(),
+            propagation: None,
+            unmount_mode: UnmountMode::default(),
+            timeout: FUSE_TIMEOUT,
             id: None,
             drop: true,
         })
@@ -99,14 +137,202 @@ impl UnionFsFuse {
         D: AsRef<Path>,
     {
         Ok(Self {
-            lower: lower.map(|x| x.as_ref().to_path_buf()).collect(),
-            upper: Some(upper.as_ref().to_path_buf()),
+            branches: Self::stack(lower, Some(upper.as_ref().to_path_buf())),
             target: target.as_ref().as_cstring(),
             options: MountOption::defaults(),
+            propagation: None,
+            unmount_mode: UnmountMode::default(),
+            timeout: FUSE_TIMEOUT,
             id: None,
             drop: true,
         })
     }
+
+    #[must_use = "initialised UnionFsFuse handle should be used"]
+    #[inline]
+    /// Initialise a new UnionFsFuse handle with explicit branch mode
+    /// branches are ordered from the highest to the lowest priority
+    pub fn with_branches<I, T>(branches: I, target: T) -> Result<Self>
+    where
+        I: IntoIterator<Item = Branch>,
+        T: AsRef<Path>,
+    {
+        let branches: Vec<Branch> = branches.into_iter().collect();
+        if branches.is_empty() {
+            return Err(Error::other(
+                "unionfs-fuse FileSystem need a least 1 branch to work",
+            ));
+        }
+        Ok(Self {
+            branches,
+            target: target.as_ref().as_cstring(),
+            options: MountOption::defaults(),
+            propagation: None,
+            unmount_mode: UnmountMode::default(),
+            timeout: FUSE_TIMEOUT,
+            id: None,
+            drop: true,
+        })
+    }
+
+    /// Retrieve every branch ordered from the highest to the lowest priority
+    #[inline]
+    pub fn branches(&self) -> &[Branch] {
+        &self.branches
+    }
+
+    /// Set every branch ordered from the highest to the lowest priority
+    #[inline]
+    pub fn set_branches(&mut self, branches: impl Into<Vec<Branch>>) -> Result<()> {
+        if self.id.is_some() {
+            return Err(Error::other(
+                "branches cannot be change when the FileSystem is mounted",
+            ));
+        }
+        self.branches = branches.into();
+        Ok(())
+    }
+
+    /// Time given to the daemon to attach or release the mount point
+    #[inline]
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Set the time given to the daemon to attach or release the mount point
+    #[inline]
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Replace the lower branches of the mounted union without unmounting it
+    ///
+    /// The new stack is served by another unionfs process and atomically put in place of the
+    /// current one, which is lazily detached and exit once every process released it.
+    /// Only read-only unions can be swapped, require privilege and kernel 6.5 or later
+    pub fn hot_swap(&mut self, lower: impl Into<Vec<PathBuf>>) -> Result<()> {
+        if self.id.is_none() {
+            return Err(Error::other(
+                "hot swap require the FileSystem to be mounted",
+            ));
+        }
+        let lower = lower.into();
+        let target = self.target.clone();
+        let writable = self.upper_idx().is_some();
+        let previous = std::mem::replace(
+            &mut self.branches,
+            Self::stack(lower.iter(), None::<PathBuf>),
+        );
+        let id = self.id.take();
+        let res = swap::hot_swap(
+            target.as_path(),
+            &lower,
+            writable,
+            |staging| {
+                self.target = staging.as_cstring();
+                self.mount().map(|_| ())
+            },
+            fusermount,
+        );
+        self.target = target;
+        if let Err(err) = res {
+            self.branches = previous;
+            self.id = id;
+            return Err(err);
+        }
+        self.id = Some(PartitionID::try_from(self.target.as_path())?);
+        if let Some(p) = self.propagation {
+            p.apply(self.target.as_path())?;
+        }
+        Ok(())
+    }
+
+    /// Retrieve the version of the unionfs-fuse binary used to mount
+    pub fn version() -> Result<Version> {
+        static VERSION: OnceLock<Option<Version>> = OnceLock::new();
+        VERSION
+            .get_or_init(|| {
+                #[cfg(feature = "unionfs-fuse-vendored")]
+                let output = env!("UNIONFS-FUSE-VERSION").to_string();
+                #[cfg(not(feature = "unionfs-fuse-vendored"))]
+                let output = Command::new("unionfs")
+                    .arg("--version")
+                    .output()
+                    .map(|x| {
+                        String::from_utf8_lossy(&x.stdout).to_string()
+                            + &String::from_utf8_lossy(&x.stderr)
+                    })
+                    .ok()?;
+                Version::from_version_output(&output).ok()
+            })
+            .ok_or(Error::new(
+                ErrorKind::NotFound,
+                "unable to detect unionfs-fuse version",
+            ))
+    }
+
+    /// Retrieve the features supported by the unionfs-fuse binary used to mount
+    pub fn features() -> Result<UnionFsFuseFeatures> {
+        Self::version().map(UnionFsFuseFeatures::from)
+    }
+
+    /// Build branch list with upper as the writable top branch
+    fn stack<I, A>(lower: I, upper: Option<PathBuf>) -> Vec<Branch>
+    where
+        I: Iterator<Item = A>,
+        A: AsRef<Path>,
+    {
+        upper
+            .map(Branch::rw)
+            .into_iter()
+            .chain(lower.map(|x| Branch::ro(x.as_ref())))
+            .collect()
+    }
+
+    /// Index of the branch used as upper layer
+    #[inline]
+    fn upper_idx(&self) -> Option<usize> {
+        self.branches.iter().position(|x| x.is_writable())
+    }
+
+    /// Check that the current option set can work with the configured branches
+    fn check_options(&self) -> Result<()> {
+        // images and archives are only mounted as layers by OverlayFs and FuseOverlayFs
+        if let Some(branch) = self.branches.iter().find(|x| x.path.is_file()) {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "unionfs-fuse cannot use the file {:?} as a branch, only directories are supported",
+                    branch.path
+                ),
+            ));
+        }
+        let relative_branch = self.branches.iter().any(|x| x.path.is_relative());
+        for opt in &self.options {
+            match opt {
+                MountOption::FsSpecific(UnionFsFuseOption::Chroot(_)) if relative_branch => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "unionfs-fuse need absolute branch path when chroot is used",
+                    ));
+                }
+                MountOption::FsSpecific(UnionFsFuseOption::MaxFile(max)) => {
+                    let (_, hard) = getrlimit(Resource::RLIMIT_NOFILE)?;
+                    if !geteuid().is_root() && *max as u64 > hard {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!(
+                                "unionfs-fuse max_files={} exceed the RLIMIT_NOFILE hard limit of {}",
+                                max, hard
+                            ),
+                        ));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Filesystem for UnionFsFuse {
@@ -123,21 +349,43 @@ impl Filesystem for UnionFsFuse {
             debug!("Damascus: partition already mounted");
             return Ok(PathBuf::from(&self.target.as_path()));
         }
-        let mut layer_args: String = String::new();
-        for path in &self.lower {
-            layer_args.push_str(path.to_string_lossy().as_ref());
-            layer_args.push_str("=ro:");
-        }
-        if let Some(upper) = &self.upper {
-            layer_args.push_str(upper.to_string_lossy().as_ref());
-            layer_args.push_str("=rw");
+        self.check_options()?;
+        // WARN : mounting on top of a branch freeze the mount
+        if self
+            .branches
+            .iter()
+            .any(|x| x.path == self.target.as_path())
+        {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "unionfs-fuse cannot be mounted on top of a branch",
+            ));
         }
+        let layer_args = self
+            .branches
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+            .join(":");
 
         let mut options = String::new();
         for mo in &self.options {
             options.push_str(&(",".to_string() + &mo.to_string()))
         }
+        // FUSE 2 refuse to mount on a non-empty directory unless told otherwise
+        if std::fs::read_dir(self.target.as_path())?.next().is_some() {
+            match Self::features() {
+                Ok(features) if !features.fuse3 => options.push_str(",nonempty"),
+                Ok(_) => {}
+                Err(err) => warn!(
+                    "Damascus: mounting on a non-empty directory may fail because : {}",
+                    err
+                ),
+            }
+        }
 
+        let target = std::fs::canonicalize(self.target.as_path())?;
+        let previous = MountInfo::find(&target)?.map(|x| x.id);
         let args = &[
             CString::new("unionfs")?,
             CString::new("-o")?,
@@ -147,25 +395,47 @@ impl Filesystem for UnionFsFuse {
         ];
 
         #[cfg(feature = "unionfs-fuse-vendored")]
-        {
+        let stderr = {
             use nix::{
+                fcntl::OFlag,
+                libc,
                 sys::{
                     memfd::{MFdFlags, memfd_create},
-                    wait::waitpid,
+                    wait::{WaitStatus, waitpid},
                 },
-                unistd::{ForkResult, fexecve, fork, write},
+                unistd::{ForkResult, dup2_stderr, fexecve, fork, pipe2, write},
             };
+            use std::{fs::File, io::Read};
             // init embedded unionfs fuse since it's not always packaged by distribution
             let byte = include_bytes!(concat!("../../../", env!("UNIONFS-FUSE-BIN")));
             let mem = memfd_create(CString::new("unionfs")?.as_c_str(), MFdFlags::empty())?;
             write(&mem, byte)?;
             let env: Vec<CString> = vec![];
+            // the write end is only kept by the child, closed on exec unless it became its stderr
+            let (reader, writer) = pipe2(OFlag::O_CLOEXEC)?;
             match unsafe { fork() } {
                 Ok(ForkResult::Parent { child, .. }) => {
-                    waitpid(child, None)?;
+                    drop(writer);
+                    let mut output = Vec::new();
+                    let read = File::from(reader).read_to_end(&mut output);
+                    let status = waitpid(child, None)?;
+                    read?;
+                    let stderr = String::from_utf8_lossy(&output).to_string();
+                    if !matches!(status, WaitStatus::Exited(_, 0)) {
+                        error!("Damascus: unable to mount {:?}\n{}", &self, stderr);
+                        return Err(Error::new(
+                            ErrorKind::PermissionDenied,
+                            "Failed to mount vfs",
+                        ));
+                    }
+                    stderr
                 }
                 Ok(ForkResult::Child) => {
-                    fexecve(mem, args, &env)?;
+                    // only async-signal-safe calls until exec, exit without unwinding on failure
+                    if dup2_stderr(&writer).is_ok() {
+                        let _ = fexecve(mem, args, &env);
+                    }
+                    unsafe { libc::_exit(127) }
                 }
                 Err(_) => {
                     return Err(Error::new(
@@ -174,53 +444,62 @@ impl Filesystem for UnionFsFuse {
                     ));
                 }
             }
-        }
+        };
         #[cfg(not(feature = "unionfs-fuse-vendored"))]
-        {
-            let options: Vec<&str> = args.iter().skip(1).map(|x| x.to_str().unwrap()).collect();
+        let stderr = {
             let output = Command::new("unionfs")
-                .args(options)
-                .spawn()
-                .unwrap()
-                .wait_with_output()
-                .unwrap();
+                .args(args.iter().skip(1).map(|x| x.as_path()))
+                .stderr(std::process::Stdio::piped())
+                .spawn()?
+                .wait_with_output()?;
+            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
             if !output.status.success() {
-                error!(
-                    "Damascus: unable to mount {:?}\n{}",
-                    &self,
-                    String::from_utf8_lossy(&output.stderr)
-                );
+                error!("Damascus: unable to mount {:?}\n{}", &self, stderr);
                 return Err(Error::new(
                     ErrorKind::PermissionDenied,
                     "Failed to mount vfs",
                 ));
             }
+            stderr
         };
+        // the daemon may still be attaching the mount point once the launcher exited
+        if !MountInfo::wait_fuse(&target, previous, self.timeout)? {
+            error!("Damascus: {:?} wasn't mounted in time\n{}", &self, stderr);
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!(
+                    "unionfs didn't mount {:?} within {:?}\n{}",
+                    target, self.timeout, stderr
+                ),
+            ));
+        }
 
         self.id = Some(
             PartitionID::try_from(self.target.as_path())
                 .map_err(|_| Error::other("unable to get PartitionID"))?,
         );
+        if let Some(p) = self.propagation {
+            p.apply(self.target.as_path())?;
+        }
         Ok(self.target.as_path().to_path_buf())
     }
 
     #[inline]
     fn unmount(&mut self) -> Result<()> {
         if matches!(self.id,Some(x) if x == PartitionID::try_from(self.target.as_path())?) {
-            let child = Command::new("fusermount")
-                .args(["-z", "-u"])
-                .arg(self.target.as_path())
-                .spawn()?;
-            let output = child.wait_with_output()?;
-            if !output.status.success() {
-                error!(
-                    "Damascus: unable to unmount {:?}\n{}",
-                    &self,
-                    String::from_utf8_lossy(&output.stderr)
-                );
+            let mount = MountInfo::find(&std::path::absolute(self.target.as_path())?)?;
+            unmount_with(self.target.as_path(), self.unmount_mode, |flags| {
+                fusermount(self.target.as_path(), flags)
+            })?;
+            if let Some(mount) = mount
+                && !MountInfo::wait_gone(mount.id, self.timeout)?
+            {
                 return Err(Error::new(
-                    ErrorKind::PermissionDenied,
-                    "Failed to unmount vfs",
+                    ErrorKind::TimedOut,
+                    format!(
+                        "{:?} wasn't unmounted within {:?}",
+                        self.target, self.timeout
+                    ),
                 ));
             }
             self.id = None;
@@ -299,30 +578,104 @@ impl LinuxFilesystem<UnionFsFuseOption> for UnionFsFuse {
     fn options(&self) -> &[crate::MountOption<UnionFsFuseOption>] {
         &self.options
     }
+
+    fn propagation(&self) -> Option<Propagation> {
+        self.propagation
+    }
+
+    fn set_propagation(&mut self, propagation: Option<Propagation>) -> Result<()> {
+        if let Some(p) = propagation
+            && self.id.is_some()
+        {
+            p.apply(self.target.as_path())?;
+        }
+        self.propagation = propagation;
+        Ok(())
+    }
+
+    fn unmount_mode(&self) -> UnmountMode {
+        self.unmount_mode
+    }
+
+    fn set_unmount_mode(&mut self, mode: UnmountMode) {
+        self.unmount_mode = mode;
+    }
 }
 
 impl StackableFilesystem for UnionFsFuse {
+    /// Every branch except the one returned by upper
     #[inline]
     fn lower(&self) -> Vec<&Path> {
-        self.lower.iter().map(|x| x.as_path()).collect()
+        let upper = self.upper_idx();
+        self.branches
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| Some(*i) != upper)
+            .map(|(_, x)| x.path())
+            .collect()
     }
 
-    #[inline]
+    /// Replace every branch except the upper one, branches kept from the previous list keep
+    /// their mode while new ones are read-only
+    ///
+    /// Fail when a writable branch other than the upper one would be dropped, use
+    /// set_branches to remove it explicitly
     fn set_lower(&mut self, lower: impl Into<Vec<PathBuf>>) -> Result<()> {
         if self.id.is_some() {
             return Err(Error::other(
                 "upper layer cannot be change when the FileSystem is mounted",
             ));
         }
-        self.lower = lower.into();
+        let lower = lower.into();
+        let upper = self.upper_idx();
+        let previous: Vec<&Branch> = self
+            .branches
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| Some(*i) != upper)
+            .map(|(_, x)| x)
+            .collect();
+        if let Some(dropped) = previous
+            .iter()
+            .find(|x| x.is_writable() && !lower.contains(&x.path))
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "writable branch {:?} would be dropped, use set_branches to remove it",
+                    dropped.path
+                ),
+            ));
+        }
+        let mut branches: Vec<Branch> = lower
+            .into_iter()
+            .map(|path| {
+                previous
+                    .iter()
+                    .find(|x| x.path == path)
+                    .map_or(Branch::ro(&path), |x| (*x).clone())
+            })
+            .collect();
+        // keep read-only branches above the upper one, it must stay the first writable branch
+        if let Some(i) = upper {
+            let pos = branches
+                .iter()
+                .position(|x| x.is_writable())
+                .map_or(i, |x| x.min(i))
+                .min(branches.len());
+            branches.insert(pos, self.branches[i].clone());
+        }
+        self.branches = branches;
         Ok(())
     }
 
+    /// First writable branch
     #[inline]
     fn upper(&self) -> Option<&Path> {
-        self.upper.as_deref()
+        self.upper_idx().map(|i| self.branches[i].path())
     }
 
+    /// Replace the first writable branch or insert a new one on top
     #[inline]
     fn set_upper(&mut self, upper: impl Into<PathBuf>) -> Result<()> {
         if self.id.is_some() {
@@ -330,11 +683,146 @@ impl StackableFilesystem for UnionFsFuse {
                 "upper layer cannot be change when the FileSystem is mounted",
             ));
         }
-        self.upper = Some(upper.into());
+        match self.upper_idx() {
+            Some(i) => self.branches[i].path = upper.into(),
+            None => self.branches.insert(0, Branch::rw(upper)),
+        }
         Ok(())
     }
 }
 
+impl StateRecovery for UnionFsFuse {
+    fn recover<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        // arguments are NUL separated in cmdline so paths holding spaces are kept intact
+        for entry in std::fs::read_dir("/proc")? {
+            let entry = entry?;
+            if !entry
+                .file_name()
+                .to_str()
+                .is_some_and(|x| x.bytes().all(|c| c.is_ascii_digit()))
+            {
+                continue;
+            }
+            let Ok(cmdline) = std::fs::read(entry.path().join("cmdline")) else {
+                continue;
+            };
+            let mut args = cmdline
+                .split(|x| *x == 0)
+                .filter(|x| !x.is_empty())
+                .map(|x| String::from_utf8_lossy(x).to_string());
+            if args
+                .next()
+                .is_some_and(|x| Path::new(&x).file_name() == Some("unionfs".as_ref()))
+            {
+                let mut options = vec![];
+                let mut positional = vec![];
+                while let Some(elem) = args.next() {
+                    if elem == "-o" {
+                        if let Some(elem) = args.next() {
+                            options.extend(
+                                elem.split(',')
+                                    .filter(|x| !x.is_empty())
+                                    .filter_map(|x| MountOption::from_str(x).ok()),
+                            );
+                        }
+                    } else {
+                        positional.push(elem);
+                    }
+                }
+                if let [branches, target] = &positional[..]
+                    && Path::new(target) == path
+                {
+                    return Ok(Self {
+                        branches: branches
+                            .split(':')
+                            .map(Branch::from_str)
+                            .collect::<Result<_>>()?,
+                        target: path.as_cstring(),
+                        options,
+                        propagation: Propagation::of(path).ok(),
+                        unmount_mode: UnmountMode::default(),
+                        timeout: FUSE_TIMEOUT,
+                        id: Some(
+                            PartitionID::try_from(path)
+                                .map_err(|_| Error::other("unable to get PartitionID"))?,
+                        ),
+                        drop: true,
+                    });
+                }
+            }
+        }
+        error!(
+            "Damascus: unable to recover handle at {:?}\n{}",
+            path, "no filesystem of type unionfs-fuse is mounted"
+        );
+        Err(Error::new(ErrorKind::NotFound, "Failed to recover handle"))
+    }
+}
+
+impl TryFrom<StackDescription<UnionFsFuseOption>> for UnionFsFuse {
+    type Error = Error;
+
+    fn try_from(value: StackDescription<UnionFsFuseOption>) -> Result<Self> {
+        value.check_supported("unionfs-fuse FileSystem", true, false, false)?;
+        let options = value.checked_options()?;
+        if let Some(path) = value
+            .writable_lower
+            .iter()
+            .find(|x| !value.lower.contains(x))
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("writable lower layer {:?} is not a lower layer", path),
+            ));
+        }
+        let branches = value
+            .upper
+            .iter()
+            .map(Branch::rw)
+            .chain(value.lower.iter().map(|x| {
+                if value.writable_lower.contains(x) {
+                    Branch::rw(x)
+                } else {
+                    Branch::ro(x)
+                }
+            }));
+        let mut handle = Self::with_branches(branches, &value.target)?;
+        handle.options = options;
+        handle.propagation = value.propagation;
+        handle.drop = value.drop;
+        Ok(handle)
+    }
+}
+
+impl From<&UnionFsFuse> for StackDescription<UnionFsFuseOption> {
+    fn from(value: &UnionFsFuse) -> Self {
+        // read-only branches above the upper one cannot be expressed through upper, the
+        // whole branch list is then described as lower layers to keep their order
+        let upper = value.upper_idx().filter(|x| *x == 0);
+        let lower = value
+            .branches
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| Some(*i) != upper);
+        Self {
+            lower: lower.clone().map(|(_, x)| x.path.clone()).collect(),
+            writable_lower: lower
+                .filter(|(_, x)| x.is_writable())
+                .map(|(_, x)| x.path.clone())
+                .collect(),
+            data: vec![],
+            upper: upper.map(|x| value.branches[x].path.clone()),
+            work: None,
+            target: value.target.as_path().to_path_buf(),
+            options: value.options.clone(),
+            idmap: None,
+            propagation: value.propagation,
+            drop: value.drop,
+        }
+    }
+}
+
 impl Drop for UnionFsFuse {
     #[inline]
     fn drop(&mut self) {
@@ -350,6 +838,7 @@ impl Drop for UnionFsFuse {
 }
 
 #[cfg(test)]
+#[allow(clippy::unwrap_used)]
 mod tests {
     #[cfg(feature = "unionfs-fuse-vendored")]
     #[test]
@@ -357,4 +846,129 @@ mod tests {
         use super::{Filesystem, UnionFsFuse};
         assert!(UnionFsFuse::is_available())
     }
+
+    #[test]
+    fn chroot_need_absolute_branch() {
+        use super::{LinuxFilesystem, StackableFilesystem, UnionFsFuse, UnionFsFuseOption};
+        use std::path::PathBuf;
+        let mut o = UnionFsFuse::readonly(["lower1", "lower2"].iter(), "/mnt").unwrap();
+        o.set_option(UnionFsFuseOption::Chroot(PathBuf::from("/srv")))
+            .unwrap();
+        assert!(o.check_options().is_err());
+        o.set_lower(vec![PathBuf::from("/lower1"), PathBuf::from("/lower2")])
+            .unwrap();
+        assert!(o.check_options().is_ok());
+    }
+
+    #[test]
+    fn file_branch_rejected() {
+        use super::UnionFsFuse;
+        use temp_testdir::TempDir;
+        let dir = TempDir::default();
+        let image = dir.join("layer.img");
+        std::fs::write(&image, b"").unwrap();
+        let o = UnionFsFuse::readonly([dir.join("lower"), image].iter(), "/mnt").unwrap();
+        assert!(o.check_options().is_err());
+    }
+
+    #[test]
+    fn features() {
+        use super::{UnionFsFuseFeatures, Version};
+        assert!(!UnionFsFuseFeatures::from(Version::new(2, 2, 0)).fuse3);
+        assert!(UnionFsFuseFeatures::from(Version::new(3, 0, 0)).fuse3);
+    }
+
+    #[test]
+    fn branch_mode() {
+        use super::{Branch, StackableFilesystem, UnionFsFuse};
+        use std::path::{Path, PathBuf};
+        let mut o = UnionFsFuse::with_branches(
+            [
+                Branch::ro("/mods"),
+                Branch::rw("/saves"),
+                Branch::rw("/configs"),
+                Branch::ro("/game"),
+            ],
+            "/mnt",
+        )
+        .unwrap();
+        assert_eq!(o.upper(), Some(Path::new("/saves")));
+        assert_eq!(
+            o.lower(),
+            vec![
+                Path::new("/mods"),
+                Path::new("/configs"),
+                Path::new("/game")
+            ]
+        );
+        o.set_upper("/upper").unwrap();
+        assert_eq!(o.branches()[1], Branch::rw("/upper"));
+
+        // extra writable branches are kept or refused rather than silently dropped
+        assert!(o.set_lower(vec![PathBuf::from("/game")]).is_err());
+        o.set_lower(vec![PathBuf::from("/configs"), PathBuf::from("/data")])
+            .unwrap();
+        assert_eq!(
+            o.branches(),
+            &[
+                Branch::rw("/upper"),
+                Branch::rw("/configs"),
+                Branch::ro("/data")
+            ]
+        );
+        let mut o = UnionFsFuse::with_branches(
+            [
+                Branch::ro("/mods"),
+                Branch::rw("/saves"),
+                Branch::ro("/game"),
+            ],
+            "/mnt",
+        )
+        .unwrap();
+        o.set_lower(vec![PathBuf::from("/patch"), PathBuf::from("/game")])
+            .unwrap();
+        assert_eq!(
+            o.branches(),
+            &[
+                Branch::ro("/patch"),
+                Branch::rw("/saves"),
+                Branch::ro("/game")
+            ]
+        );
+
+        let o = UnionFsFuse::writable(["/lower1", "/lower2"].iter(), "/upper", "/mnt").unwrap();
+        assert_eq!(
+            o.branches(),
+            &[
+                Branch::rw("/upper"),
+                Branch::ro("/lower1"),
+                Branch::ro("/lower2")
+            ]
+        );
+        assert_eq!("/upper=rw".parse::<Branch>().unwrap(), Branch::rw("/upper"));
+        assert_eq!("/a=b".parse::<Branch>().unwrap(), Branch::ro("/a=b"));
+        assert_eq!("/a=b=RO".parse::<Branch>().unwrap(), Branch::ro("/a=b"));
+    }
+
+    #[test]
+    fn description_round_trip() {
+        use super::{Branch, StackDescription, UnionFsFuse, UnionFsFuseOption};
+        for branches in [
+            vec![
+                Branch::rw("/saves"),
+                Branch::rw("/configs"),
+                Branch::ro("/game"),
+            ],
+            vec![
+                Branch::ro("/mods"),
+                Branch::rw("/saves"),
+                Branch::ro("/game"),
+            ],
+        ] {
+            let o = UnionFsFuse::with_branches(branches, "/mnt").unwrap();
+            let description = StackDescription::<UnionFsFuseOption>::from(&o);
+            let restored = UnionFsFuse::try_from(description).unwrap();
+            assert_eq!(restored.branches(), o.branches());
+        }
+    }
 }
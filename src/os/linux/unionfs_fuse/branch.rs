@@ -0,0 +1,116 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use std::{
+    fmt::Display,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BranchMode {
+    /// Branch is read-only, modification are redirected to a writable branch when cow is enabled
+    RO,
+    /// Branch is writable, the first writable branch receive newly created files
+    RW,
+}
+
+impl FromStr for BranchMode {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("ro") {
+            Ok(Self::RO)
+        } else if s.eq_ignore_ascii_case("rw") {
+            Ok(Self::RW)
+        } else {
+            Err(Error::new(
+                ErrorKind::Unsupported,
+                "Unsupported branch mode",
+            ))
+        }
+    }
+}
+
+impl Display for BranchMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                BranchMode::RO => "RO",
+                BranchMode::RW => "RW",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+/// Union branch with its access mode
+pub struct Branch {
+    pub path: PathBuf,
+    pub mode: BranchMode,
+}
+
+impl Branch {
+    #[inline]
+    pub fn new(path: impl Into<PathBuf>, mode: BranchMode) -> Self {
+        Self {
+            path: path.into(),
+            mode,
+        }
+    }
+
+    /// Initialise a new read-only branch
+    #[inline]
+    pub fn ro(path: impl Into<PathBuf>) -> Self {
+        Self::new(path, BranchMode::RO)
+    }
+
+    /// Initialise a new writable branch
+    #[inline]
+    pub fn rw(path: impl Into<PathBuf>) -> Self {
+        Self::new(path, BranchMode::RW)
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    #[inline]
+    pub fn is_writable(&self) -> bool {
+        self.mode == BranchMode::RW
+    }
+}
+
+impl FromStr for Branch {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // '=' may be part of the path, only a known mode suffix is split off
+        Ok(
+            match s
+                .rsplit_once('=')
+                .and_then(|(path, mode)| Some((path, BranchMode::from_str(mode).ok()?)))
+            {
+                Some((path, mode)) => Self::new(path, mode),
+                // unionfs-fuse default to read-only when no mode is given
+                None => Self::ro(s),
+            },
+        )
+    }
+}
+
+impl Display for Branch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.path.to_string_lossy(), self.mode)
+    }
+}
@@ -9,7 +9,8 @@ use std::{fmt::Display, path::PathBuf, str::FromStr};
 
 use crate::{FsOption, MountOption};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum UnionFsFuseOption {
     /// Chroot into this path. Use this if you want to have a union of "/"
     Chroot(PathBuf),
@@ -27,6 +28,14 @@ pub enum UnionFsFuseOption {
     StatfsOmitRo,
     /// Enable direct-io flag for the fuse subsystem
     DirectIo,
+    /// Write debug information into this file
+    DebugFile(PathBuf),
+    /// Kept for compatibility, has no effect since unionfs-fuse 0.23
+    NoInitGroups,
+    /// Allow access by all users
+    AllowOther,
+    /// Let the filesystem set inode numbers instead of fuse
+    UseIno,
 }
 
 impl FsOption for UnionFsFuseOption {
@@ -34,8 +43,21 @@ impl FsOption for UnionFsFuseOption {
         vec![UnionFsFuseOption::Cow, UnionFsFuseOption::HideMetaFiles]
     }
 
-    fn incompatible(&self, _other: &MountOption<Self>) -> bool {
-        // TODO : find incompatible mount option and define compatibility matrix
+    fn incompatible(&self, other: &MountOption<Self>) -> bool {
+        let MountOption::FsSpecific(other) = other else {
+            return false;
+        };
+        let incompat_matrix = [|s: &UnionFsFuseOption, o: &UnionFsFuseOption| {
+            // every user would be able to bypass permission checks
+            matches!(s, UnionFsFuseOption::RelaxedPermission)
+                && matches!(o, UnionFsFuseOption::AllowOther)
+        }];
+
+        for incompat in incompat_matrix {
+            if incompat(self, other) || incompat(other, self) {
+                return true;
+            }
+        }
         false
     }
 }
@@ -54,6 +76,9 @@ impl FromStr for UnionFsFuseOption {
                 "chroot" => {
                     return Ok(Self::Chroot(PathBuf::from(va)));
                 }
+                "debug_file" => {
+                    return Ok(Self::DebugFile(PathBuf::from(va)));
+                }
                 _ => {}
             };
         }
@@ -65,6 +90,9 @@ impl FromStr for UnionFsFuseOption {
             "relaxed_permissions" => Self::RelaxedPermission,
             "statfs_omit_ro" => UnionFsFuseOption::StatfsOmitRo,
             "direct_io" => Self::DirectIo,
+            "noinitgroups" => Self::NoInitGroups,
+            "allow_other" => Self::AllowOther,
+            "use_ino" => Self::UseIno,
             _ => {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::Unsupported,
@@ -89,6 +117,10 @@ impl Display for UnionFsFuseOption {
                 UnionFsFuseOption::RelaxedPermission => "relaxed_permissions".to_owned(),
                 UnionFsFuseOption::StatfsOmitRo => "statfs_omit_ro".to_owned(),
                 UnionFsFuseOption::DirectIo => "direct_io".to_owned(),
+                UnionFsFuseOption::DebugFile(x) => "debug_file=".to_string() + &x.to_string_lossy(),
+                UnionFsFuseOption::NoInitGroups => "noinitgroups".to_owned(),
+                UnionFsFuseOption::AllowOther => "allow_other".to_owned(),
+                UnionFsFuseOption::UseIno => "use_ino".to_owned(),
             }
         )
     }
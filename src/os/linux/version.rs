@@ -0,0 +1,110 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use std::{
+    fmt::Display,
+    io::{Error, ErrorKind, Result},
+    str::FromStr,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// Version of the running kernel or of a filesystem binary
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    #[inline]
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Retrieve the version of the running kernel
+    pub fn kernel() -> Result<Self> {
+        Self::from_str(std::fs::read_to_string("/proc/sys/kernel/osrelease")?.trim())
+    }
+
+    /// Extract a version from the output of a `--version` flag
+    /// ex: "fuse-overlayfs: version 1.13-dev" or "unionfs-fuse version: 3.3"
+    #[cfg(any(feature = "fuse-overlayfs", feature = "unionfs-fuse"))]
+    pub(crate) fn from_version_output(output: &str) -> Result<Self> {
+        output
+            .lines()
+            .filter(|x| x.to_lowercase().contains("version"))
+            .flat_map(|x| x.split_whitespace())
+            .find(|x| x.starts_with(|c: char| c.is_ascii_digit()))
+            .ok_or(Error::new(
+                ErrorKind::InvalidData,
+                "version not found in output",
+            ))
+            .and_then(Self::from_str)
+    }
+}
+
+impl FromStr for Version {
+    type Err = std::io::Error;
+
+    /// Parse version string such as "6.8.0-45-generic" or "1.13-dev"
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut it = s.split(|c: char| !c.is_ascii_digit()).map(|x| x.parse());
+        let mut next = || {
+            it.next()
+                .and_then(|x| x.ok())
+                .ok_or(Error::new(ErrorKind::InvalidData, "Invalid version"))
+        };
+        let major = next()?;
+        let minor = next()?;
+        // some release only contain major and minor
+        let patch = next().unwrap_or(0);
+        Ok(Self::new(major, minor, patch))
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Version::from_str("6.8.0-45-generic").unwrap(),
+            Version::new(6, 8, 0)
+        );
+        assert_eq!(Version::from_str("6.18").unwrap(), Version::new(6, 18, 0));
+        assert!(Version::new(5, 11, 0) > Version::new(5, 10, 200));
+        assert!(Version::from_str("linux").is_err());
+    }
+
+    #[test]
+    #[cfg(any(feature = "fuse-overlayfs", feature = "unionfs-fuse"))]
+    fn parse_version_output() {
+        assert_eq!(
+            Version::from_version_output(
+                "fuse-overlayfs: version 1.13-dev\nFUSE library version 3.16.2\n"
+            )
+            .unwrap(),
+            Version::new(1, 13, 0)
+        );
+        assert_eq!(
+            Version::from_version_output("unionfs-fuse version: 3.3\n").unwrap(),
+            Version::new(3, 3, 0)
+        );
+    }
+}
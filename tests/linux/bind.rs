@@ -0,0 +1,69 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use crate::skip;
+
+use super::{read_only_test, read_test, write_test};
+use damascus::{BindMount, Filesystem, StateRecovery};
+use nix::unistd::geteuid;
+use std::fs::create_dir_all;
+use temp_testdir::TempDir;
+
+pub fn mount_bind_r() {
+    if !geteuid().is_root() {
+        skip!("bind mount can only be created as root");
+        return;
+    }
+    let tmp = TempDir::default().to_path_buf();
+    let source = tmp.join("source");
+    let target = tmp.join("mount");
+    create_dir_all(&source).unwrap();
+    create_dir_all(&target).unwrap();
+    write_test(&source.join("test"));
+
+    let mut b = BindMount::new(&source, &target);
+    b.set_readonly(true).unwrap();
+    b.mount().unwrap();
+    read_test(&target.join("test"));
+    read_only_test(&target.join("other"));
+    assert!(b.set_readonly(false).is_err());
+
+    let reco = BindMount::recover(&target).unwrap();
+    assert_eq!(reco.source(), source);
+    assert!(reco.readonly());
+    assert!(!reco.recursive());
+
+    b.unmount().unwrap();
+    assert!(!target.join("test").exists());
+}
+
+pub fn mount_bind_detached() {
+    if !geteuid().is_root() {
+        skip!("bind mount can only be created as root");
+        return;
+    }
+    let tmp = TempDir::default().to_path_buf();
+    let source = tmp.join("source");
+    let target = tmp.join("mount");
+    create_dir_all(&source).unwrap();
+    create_dir_all(&target).unwrap();
+    write_test(&source.join("test"));
+
+    let b = BindMount::new(&source, &target);
+    let tree = b.detached().unwrap();
+    // not visible until attached
+    assert!(!target.join("test").exists());
+    tree.attach(&target).unwrap();
+    read_test(&target.join("test"));
+    write_test(&target.join("other"));
+    assert!(source.join("other").exists());
+
+    let mut reco = BindMount::recover(&target).unwrap();
+    assert_eq!(reco.source(), source);
+    reco.unmount().unwrap();
+    assert!(!target.join("test").exists());
+}
@@ -0,0 +1,95 @@
+// Copyright 2025 Yato202010
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
+use crate::skip;
+
+use super::{execute_test, read_test, write_test};
+use damascus::{Filesystem, Stack, StackBuilder, StackableFilesystem};
+use std::fs::create_dir_all;
+use temp_testdir::TempDir;
+
+pub fn build_writable_stack() {
+    if !Stack::is_available() {
+        skip!("No stackable filesystem is available");
+        return;
+    }
+    let tmp = TempDir::default().to_path_buf();
+    let lower1 = tmp.join("lower1");
+    let lower2 = tmp.join("lower2");
+    let upper = tmp.join("upper");
+    let work = tmp.join("work");
+    let target = tmp.join("mount");
+    let test = target.join("test");
+    create_dir_all(&lower1).unwrap();
+    create_dir_all(&lower2).unwrap();
+    create_dir_all(&target).unwrap();
+    create_dir_all(&upper).unwrap();
+    create_dir_all(&work).unwrap();
+    let selected = match StackBuilder::new(&target)
+        .lower([&lower1, &lower2])
+        .upper(&upper)
+        .work(&work)
+        .writable(true)
+        .executable(true)
+        .build()
+    {
+        Ok(x) => x,
+        Err(e) => {
+            skip!(&e.to_string());
+            return;
+        }
+    };
+    if selected.namespace.is_some() {
+        skip!("Selected backend must be mounted inside a user namespace");
+        return;
+    }
+    let mut o = selected.handle;
+    assert_eq!(o.upper(), Some(upper.as_path()));
+    o.mount().unwrap();
+
+    write_test(&test);
+
+    read_test(&test);
+
+    execute_test(&test);
+}
+
+pub fn build_writable_stack_without_upper() {
+    let tmp = TempDir::default().to_path_buf();
+    let err = StackBuilder::new(tmp.join("mount"))
+        .lower([tmp.join("lower1"), tmp.join("lower2")])
+        .writable(true)
+        .build()
+        .unwrap_err();
+    let err = err.to_string();
+    assert!(err.contains("overlayfs"));
+    assert!(err.contains("fuse-overlayfs"));
+    assert!(err.contains("unionfs-fuse"));
+}
+
+pub fn build_case_insensitive_stack() {
+    let tmp = TempDir::default().to_path_buf();
+    let err = StackBuilder::new(tmp.join("mount"))
+        .lower([tmp.join("lower1"), tmp.join("lower2")])
+        .case_insensitive(true)
+        .build()
+        .unwrap_err();
+    let err = err.to_string();
+    assert!(err.contains("overlayfs"));
+    assert!(err.contains("fuse-overlayfs"));
+    assert!(err.contains("unionfs-fuse"));
+}
+
+pub fn build_readonly_stack_with_upper() {
+    let tmp = TempDir::default().to_path_buf();
+    let err = StackBuilder::new(tmp.join("mount"))
+        .lower([tmp.join("lower1"), tmp.join("lower2")])
+        .upper(tmp.join("upper"))
+        .build()
+        .unwrap_err();
+    assert!(err.to_string().contains("upper directory"));
+}
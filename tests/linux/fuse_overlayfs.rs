@@ -155,3 +155,38 @@ pub fn recover_fuse_overlay_rw_handle() {
     assert_eq!(reco.work(), o.work());
     assert_eq!(reco.target(), o.target());
 }
+
+pub fn mount_fuse_overlay_wait_ready() {
+    use std::time::Duration;
+
+    if !FuseOverlayFs::is_available() {
+        skip!("FuseOverlayFs is not available");
+        return;
+    }
+    if geteuid().is_root() {
+        skip!("fuse mount can't be tested as root");
+        return;
+    }
+    let fuse_on = |target: &std::path::Path| {
+        std::fs::read_to_string("/proc/self/mountinfo")
+            .unwrap()
+            .lines()
+            .any(|x| x.contains(&format!(" {} ", target.display())) && x.contains(" - fuse"))
+    };
+    let tmp = TempDir::default().to_path_buf();
+    let lower1 = tmp.join("lower1");
+    let lower2 = tmp.join("lower2");
+    let target = tmp.join("mount");
+    create_dir_all(&lower1).unwrap();
+    create_dir_all(&lower2).unwrap();
+    create_dir_all(&target).unwrap();
+    let target = target.canonicalize().unwrap();
+    let mut o = FuseOverlayFs::readonly([&lower1, &lower2].iter(), &target).unwrap();
+    o.set_timeout(Duration::from_secs(10));
+    o.mount().unwrap();
+    // the mount point is attached once mount return
+    assert!(fuse_on(&target));
+    assert!(o.mounted());
+    o.unmount().unwrap();
+    assert!(!fuse_on(&target));
+}
@@ -25,6 +25,13 @@ use nix::{
 #[allow(unused_imports)]
 use crate::register_tests;
 
+#[cfg(any(
+    feature = "overlayfs",
+    feature = "fuse-overlayfs",
+    feature = "unionfs-fuse"
+))]
+pub mod builder;
+
 #[cfg(feature = "unionfs-fuse")]
 pub mod unionfs_fuse;
 
@@ -34,6 +41,8 @@ pub mod fuse_overlayfs;
 #[cfg(feature = "overlayfs")]
 pub mod overlayfs;
 
+pub mod bind;
+
 pub fn register_test() {
     #[cfg(feature = "unionfs-fuse")]
     register_tests!(
@@ -41,6 +50,7 @@ pub fn register_test() {
         // Ernno::EROFS = 30 which is clearly wrong but why !?
         unionfs_fuse::mount_unionfs_fuse_r,
         unionfs_fuse::mount_unionfs_fuse_rw,
+        unionfs_fuse::recover_unionfs_fuse_branches_handle,
         // WARN : mounting on top of lower dir is not permitted for now it freeze
         //unionfs_fuse::mount_unionfs_fuse_rw_on_lower
     );
@@ -50,7 +60,8 @@ pub fn register_test() {
         fuse_overlayfs::mount_fuse_overlay_rw,
         fuse_overlayfs::mount_fuse_overlay_rw_on_lower,
         fuse_overlayfs::recover_fuse_overlay_ro_handle,
-        fuse_overlayfs::recover_fuse_overlay_rw_handle
+        fuse_overlayfs::recover_fuse_overlay_rw_handle,
+        fuse_overlayfs::mount_fuse_overlay_wait_ready
     );
     #[cfg(feature = "overlayfs")]
     register_tests!(
@@ -58,7 +69,36 @@ pub fn register_test() {
         overlayfs::mount_overlay_rw,
         overlayfs::mount_overlay_rw_on_lower,
         overlayfs::recover_overlay_ro_handle,
-        overlayfs::recover_overlay_rw_handle
+        overlayfs::recover_overlay_rw_handle,
+        overlayfs::mount_overlay_data_only_lower,
+        overlayfs::mount_overlay_dyn_collection,
+        overlayfs::mount_overlay_user_namespace,
+        overlayfs::launch_in_overlay_namespace,
+        overlayfs::mount_overlay_idmapped,
+        overlayfs::mount_overlay_propagation,
+        overlayfs::mount_overlay_ephemeral,
+        overlayfs::mount_overlay_image_lower,
+        overlayfs::mount_overlay_written_image,
+        overlayfs::mount_overlay_verity_require,
+        overlayfs::mount_overlay_profile,
+        overlayfs::mount_overlay_hot_swap,
+        overlayfs::mount_overlay_unmount_modes
+    );
+    register_tests!(bind::mount_bind_r, bind::mount_bind_detached);
+    #[cfg(all(feature = "overlayfs", feature = "archive"))]
+    register_tests!(overlayfs::mount_overlay_archive_lower);
+    #[cfg(all(feature = "overlayfs", feature = "integrity"))]
+    register_tests!(overlayfs::mount_overlay_manifest_drift);
+    #[cfg(any(
+        feature = "overlayfs",
+        feature = "fuse-overlayfs",
+        feature = "unionfs-fuse"
+    ))]
+    register_tests!(
+        builder::build_writable_stack,
+        builder::build_writable_stack_without_upper,
+        builder::build_case_insensitive_stack,
+        builder::build_readonly_stack_with_upper
     );
 }
 
@@ -7,13 +7,16 @@
 // Unless required by applicable law or agreed to in writing, software distributed under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the License for the specific language governing permissions and limitations under the License.
 use crate::skip;
 
-use super::{execute_test, read_only_test, read_test, setup_namespaces, write_test};
+use super::{
+    SCRIPT_CONTENTS, execute_test, read_only_test, read_test, setup_namespaces, write_test,
+};
 use damascus::{
-    Filesystem, LinuxFilesystem, MountOption, OverlayFs, StackableFilesystem, StateRecovery,
-    overlay::OverlayFsOption,
+    Filesystem, IdMap, IdRange, ImageMount, Launcher, LinuxFilesystem, MountOption,
+    OverlayCapabilities, OverlayFs, Propagation, Stack, StackableFilesystem, StateRecovery, Tmpfs,
+    UserNamespace, overlay::OverlayFsOption,
 };
 use nix::unistd::{geteuid, getuid};
-use std::fs::create_dir_all;
+use std::{fs::create_dir_all, os::unix::fs::MetadataExt, process::Command};
 use temp_testdir::TempDir;
 
 pub fn mount_overlay_r() {
@@ -193,3 +196,728 @@ pub fn recover_overlay_rw_handle() {
     assert_eq!(reco.work(), o.work());
     assert_eq!(reco.target(), o.target());
 }
+
+fn set_trusted_xattr(path: &std::path::Path, name: &std::ffi::CStr, value: &[u8]) {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+    let res = unsafe {
+        nix::libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const nix::libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    assert_eq!(res, 0, "setxattr failed: {}", std::io::Error::last_os_error());
+}
+
+pub fn mount_overlay_data_only_lower() {
+    if !OverlayFs::is_available() {
+        skip!("OverlayFs is not available");
+        return;
+    }
+    if !geteuid().is_root() {
+        skip!("data-only layers need trusted xattr which can only be set as root");
+        return;
+    }
+    let tmp = TempDir::default().to_path_buf();
+    let lower1 = tmp.join("lower1");
+    let lower2 = tmp.join("lower2");
+    let data = tmp.join("data");
+    let target = tmp.join("mount");
+    create_dir_all(&lower1).unwrap();
+    create_dir_all(&lower2).unwrap();
+    create_dir_all(&data).unwrap();
+    create_dir_all(&target).unwrap();
+    std::fs::write(data.join("blob"), SCRIPT_BLOB).unwrap();
+    let meta = lower1.join("texture");
+    std::fs::File::create(&meta).unwrap();
+    set_trusted_xattr(&meta, c"trusted.overlay.metacopy", b"");
+    set_trusted_xattr(&meta, c"trusted.overlay.redirect", b"/blob");
+
+    let mut o = OverlayFs::readonly([&lower1, &lower2].iter(), &target).unwrap();
+    o.set_data_lower(vec![data.clone()]).unwrap();
+    assert!(o.mount().is_err());
+    o.set_option(OverlayFsOption::Metacopy(true)).unwrap();
+    o.mount().unwrap();
+
+    assert_eq!(std::fs::read(target.join("texture")).unwrap(), SCRIPT_BLOB);
+    assert!(!target.join("blob").exists());
+
+    let reco = OverlayFs::recover(&target).unwrap();
+    assert_eq!(reco.lower(), o.lower());
+    assert_eq!(reco.data_lower(), o.data_lower());
+}
+
+static SCRIPT_BLOB: &[u8] = b"data only content";
+
+pub fn mount_overlay_verity_require() {
+    use damascus::overlay::{FsVerity, enable_verity_layer, measure_verity, metacopy_digest};
+    use nix::mount::{MntFlags, umount2};
+
+    if !OverlayFs::is_available() || !OverlayCapabilities::get().verity {
+        skip!("OverlayFs with verity is not available");
+        return;
+    }
+    if !geteuid().is_root() {
+        skip!("verity layers are built on a loop mounted ext4 as root");
+        return;
+    }
+    let tmp = TempDir::default().to_path_buf();
+    let image = tmp.join("verity.ext4");
+    let base = tmp.join("base");
+    create_dir_all(&base).unwrap();
+    std::fs::File::create(&image)
+        .and_then(|f| f.set_len(64 * 1024 * 1024))
+        .unwrap();
+    let ok = |cmd: &mut Command| cmd.output().is_ok_and(|x| x.status.success());
+    if !ok(Command::new("mkfs.ext4")
+        .args(["-q", "-O", "verity"])
+        .arg(&image))
+        || !ok(Command::new("mount")
+            .args(["-o", "loop"])
+            .arg(&image)
+            .arg(&base))
+    {
+        skip!("ext4 with verity support is required");
+        return;
+    }
+    let lower1 = base.join("lower1");
+    let lower2 = base.join("lower2");
+    let data = base.join("data");
+    let target = tmp.join("mount");
+    create_dir_all(&lower1).unwrap();
+    create_dir_all(&lower2).unwrap();
+    create_dir_all(&data).unwrap();
+    create_dir_all(&target).unwrap();
+    std::fs::write(data.join("blob"), SCRIPT_BLOB).unwrap();
+    let meta = lower1.join("texture");
+    std::fs::File::create(&meta).unwrap();
+    set_trusted_xattr(&meta, c"trusted.overlay.metacopy", b"");
+    set_trusted_xattr(&meta, c"trusted.overlay.redirect", b"/blob");
+
+    let mut o = OverlayFs::readonly([&lower1, &lower2].iter(), &target).unwrap();
+    o.set_data_lower(vec![data.clone()]).unwrap();
+    o.set_option(FsVerity::Require).unwrap();
+    assert!(o.check_verity().is_err());
+    o.set_option(OverlayFsOption::Metacopy(true)).unwrap();
+    // data file doesn't have fs-verity yet
+    assert!(o.check_verity().is_err());
+
+    match enable_verity_layer(&data) {
+        Ok(count) => assert_eq!(count, 1),
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+            umount2(&base, MntFlags::MNT_DETACH).unwrap();
+            skip!("kernel is built without fs-verity");
+            return;
+        }
+        Err(e) => panic!("enable verity failed: {}", e),
+    }
+    assert_eq!(enable_verity_layer(&data).unwrap(), 0);
+    let digest = measure_verity(data.join("blob")).unwrap().unwrap();
+    assert_eq!(digest.digest.len(), 32);
+    // metacopy file doesn't hold the digest yet
+    assert_eq!(metacopy_digest(&meta).unwrap(), Some(None));
+    assert!(o.check_verity().is_err());
+
+    o.set_metacopy_digest(&meta, &digest).unwrap();
+    assert_eq!(o.metacopy_digest(&meta).unwrap(), Some(Some(digest)));
+    o.check_verity().unwrap();
+    o.mount().unwrap();
+    assert_eq!(std::fs::read(target.join("texture")).unwrap(), SCRIPT_BLOB);
+    o.unmount().unwrap();
+    umount2(&base, MntFlags::MNT_DETACH).unwrap();
+}
+
+pub fn mount_overlay_dyn_collection() {
+    use damascus::DynStackableFilesystem;
+    if !OverlayFs::is_available() {
+        skip!("OverlayFs is not available");
+        return;
+    }
+    if !geteuid().is_root()
+        && let Err(_e) = setup_namespaces()
+    {
+        skip!("Cannot setup user namespaces this is not what we are testing");
+        return;
+    }
+    let tmp = TempDir::default().to_path_buf();
+    let lower1 = tmp.join("lower1");
+    let lower2 = tmp.join("lower2");
+    let target1 = tmp.join("mount1");
+    let target2 = tmp.join("mount2");
+    create_dir_all(&lower1).unwrap();
+    create_dir_all(&lower2).unwrap();
+    create_dir_all(&target1).unwrap();
+    create_dir_all(&target2).unwrap();
+    let mut handles: Vec<Box<dyn DynStackableFilesystem>> = vec![
+        Box::new(OverlayFs::readonly([&lower1, &lower2].iter(), &target1).unwrap()),
+        Box::new(Stack::OverlayFs(
+            OverlayFs::readonly([&lower2, &lower1].iter(), &target2).unwrap(),
+        )),
+    ];
+    for h in handles.iter_mut() {
+        assert!(h.dyn_is_available());
+        h.dyn_mount().unwrap();
+    }
+    for h in handles.iter_mut() {
+        assert!(h.dyn_mounted());
+        assert_eq!(h.dyn_lower().len(), 2);
+        h.dyn_unmount().unwrap();
+        assert!(!h.dyn_mounted());
+    }
+}
+
+pub fn mount_overlay_user_namespace() {
+    if !OverlayCapabilities::get().unprivileged {
+        skip!("OverlayFs cannot be mounted in user namespace");
+        return;
+    }
+    let tmp = TempDir::default().to_path_buf();
+    let lower1 = tmp.join("lower1");
+    let lower2 = tmp.join("lower2");
+    let upper = tmp.join("upper");
+    let work = tmp.join("work");
+    let target = tmp.join("mount");
+    let test = target.join("test");
+    create_dir_all(&lower1).unwrap();
+    create_dir_all(&lower2).unwrap();
+    create_dir_all(&target).unwrap();
+    create_dir_all(&upper).unwrap();
+    create_dir_all(&work).unwrap();
+    let mut o = OverlayFs::writable([lower1, lower2].iter(), &upper, &work, &target).unwrap();
+    let ns = UserNamespace::new();
+    let code = ns
+        .run(&mut o, || {
+            std::panic::catch_unwind(|| {
+                write_test(&test);
+                read_test(&test);
+            })
+            .map_or(1, |_| 0)
+        })
+        .unwrap();
+    assert_eq!(code, 0);
+    // options required by the namespace are not left on the handle
+    assert!(o.options().is_empty());
+    // mount happened in the namespace only
+    assert!(!test.exists());
+    assert!(upper.join("test").exists());
+
+    let status = ns
+        .spawn(&mut o, Command::new("test").arg("-f").arg(&test))
+        .unwrap()
+        .wait()
+        .unwrap();
+    assert!(status.success());
+}
+
+pub fn launch_in_overlay_namespace() {
+    if !OverlayCapabilities::get().unprivileged {
+        skip!("OverlayFs cannot be mounted in user namespace");
+        return;
+    }
+    let tmp = TempDir::default().to_path_buf();
+    let lower1 = tmp.join("lower1");
+    let lower2 = tmp.join("lower2");
+    let target = tmp.join("mount");
+    create_dir_all(&lower1).unwrap();
+    create_dir_all(&lower2).unwrap();
+    create_dir_all(&target).unwrap();
+    std::fs::write(lower2.join("test"), SCRIPT_CONTENTS).unwrap();
+    let o = OverlayFs::readonly([&lower1, &lower2].iter(), &tmp).unwrap();
+    let mut launcher = Launcher::new(o).unwrap().over(&target);
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(format!(
+        "test -f {} && test \"$(id -u)\" = {} && test \"$(cat /proc/1/comm)\" = sh",
+        target.join("test").display(),
+        geteuid()
+    ));
+    let status = launcher.spawn(&command).unwrap().wait().unwrap();
+    assert!(status.success());
+    // only the launched process can see the stack
+    assert!(!target.join("test").exists());
+
+    // environment set on the launcher and the command are both honoured
+    let mut launcher = launcher.env_clear().env("DAMASCUS_KEPT", "1");
+    let mut command = Command::new("sh");
+    command
+        .env("DAMASCUS_COMMAND", "1")
+        .arg("-c")
+        .arg("test \"$DAMASCUS_KEPT$DAMASCUS_COMMAND\" = 11 && test -z \"$HOME\"");
+    let status = launcher.spawn(&command).unwrap().wait().unwrap();
+    assert!(status.success());
+
+    let mut child = launcher.spawn(Command::new("sleep").arg("60")).unwrap();
+    child.kill().unwrap();
+    assert!(!child.wait().unwrap().success());
+}
+
+pub fn mount_overlay_idmapped() {
+    if !OverlayFs::is_available() || !OverlayCapabilities::get().idmapped_lower {
+        skip!("OverlayFs idmapped layers are not available");
+        return;
+    }
+    if !geteuid().is_root() {
+        skip!("idmapped mount can only be tested as root");
+        return;
+    }
+    let tmp = TempDir::default().to_path_buf();
+    let lower1 = tmp.join("lower1");
+    let lower2 = tmp.join("lower2");
+    let upper = tmp.join("upper");
+    let work = tmp.join("work");
+    let target = tmp.join("mount");
+    create_dir_all(&lower1).unwrap();
+    create_dir_all(&lower2).unwrap();
+    create_dir_all(&target).unwrap();
+    create_dir_all(&upper).unwrap();
+    create_dir_all(&work).unwrap();
+    std::fs::write(lower1.join("owned"), SCRIPT_CONTENTS).unwrap();
+    // swap root and uid 1000
+    let map = IdMap::from(vec![IdRange::new(0, 1000, 1), IdRange::new(1000, 0, 1)]);
+    let mut o = OverlayFs::writable([&lower1, &lower2].iter(), &upper, &work, &target).unwrap();
+    o.set_idmap(Some(UserNamespace::with_maps(map.clone(), map)))
+        .unwrap();
+    o.mount().unwrap();
+
+    assert_eq!(target.join("owned").metadata().unwrap().uid(), 1000);
+    write_test(&target.join("test"));
+    assert_eq!(upper.join("test").metadata().unwrap().uid(), 1000);
+
+    o.unmount().unwrap();
+    assert!(!target.join("owned").exists());
+}
+
+pub fn mount_overlay_propagation() {
+    if !OverlayFs::is_available() {
+        skip!("OverlayFs is not available");
+        return;
+    }
+    if !geteuid().is_root() {
+        skip!("propagation can only be changed as root");
+        return;
+    }
+    let tmp = TempDir::default().to_path_buf();
+    let lower1 = tmp.join("lower1");
+    let lower2 = tmp.join("lower2");
+    let target = tmp.join("mount");
+    create_dir_all(&lower1).unwrap();
+    create_dir_all(&lower2).unwrap();
+    create_dir_all(&target).unwrap();
+    let mut o = OverlayFs::readonly([&lower1, &lower2].iter(), &target).unwrap();
+    o.set_propagation(Some(Propagation::Private)).unwrap();
+    o.mount().unwrap();
+    assert_eq!(Propagation::of(&target).unwrap(), Propagation::Private);
+
+    o.set_propagation(Some(Propagation::Unbindable)).unwrap();
+    let reco = OverlayFs::recover(&target).unwrap();
+    assert_eq!(reco.propagation(), Some(Propagation::Unbindable));
+}
+
+pub fn mount_overlay_ephemeral() {
+    if !OverlayFs::is_available() || !Tmpfs::is_available() {
+        skip!("OverlayFs or Tmpfs is not available");
+        return;
+    }
+    if !geteuid().is_root() {
+        skip!("tmpfs can only be mounted as root");
+        return;
+    }
+    let tmp = TempDir::default().to_path_buf();
+    let lower1 = tmp.join("lower1");
+    let lower2 = tmp.join("lower2");
+    let scratch = tmp.join("scratch");
+    let target = tmp.join("mount");
+    let export = tmp.join("export");
+    create_dir_all(&lower1).unwrap();
+    create_dir_all(&lower2).unwrap();
+    create_dir_all(&scratch).unwrap();
+    create_dir_all(&target).unwrap();
+    let mut tmpfs = Tmpfs::new(&scratch);
+    tmpfs.set_size(Some(16 * 1024 * 1024)).unwrap();
+    tmpfs.set_mode(Some(0o700)).unwrap();
+    let mut o = OverlayFs::ephemeral([&lower1, &lower2].iter(), tmpfs, &target).unwrap();
+    assert!(o.scratch().is_some_and(|x| x.id().is_some()));
+    o.mount().unwrap();
+    write_test(&target.join("test"));
+    assert!(!lower1.join("test").exists());
+
+    o.export_changes(&export).unwrap();
+    read_test(&export.join("test"));
+    drop(o);
+    assert!(!target.join("test").exists());
+    assert!(!scratch.join("upper").exists());
+}
+
+pub fn mount_overlay_image_lower() {
+    if !OverlayFs::is_available() || !ImageMount::is_available() {
+        skip!("OverlayFs or ImageMount is not available");
+        return;
+    }
+    if !geteuid().is_root() {
+        skip!("image are mounted through loop devices as root");
+        return;
+    }
+    let tmp = TempDir::default().to_path_buf();
+    let content = tmp.join("content");
+    let image = tmp.join("lower1.squashfs");
+    let lower2 = tmp.join("lower2");
+    let target = tmp.join("mount");
+    create_dir_all(&content).unwrap();
+    create_dir_all(&lower2).unwrap();
+    create_dir_all(&target).unwrap();
+    write_test(&content.join("test"));
+    if !Command::new("mksquashfs")
+        .arg(&content)
+        .arg(&image)
+        .arg("-quiet")
+        .status()
+        .is_ok_and(|x| x.success())
+    {
+        skip!("mksquashfs is required to build the image");
+        return;
+    }
+    let mut o = OverlayFs::readonly([&image, &lower2].iter(), &target).unwrap();
+    o.mount().unwrap();
+    read_test(&target.join("test"));
+
+    let reco = OverlayFs::recover(&target).unwrap();
+    assert_eq!(reco.lower()[0], image);
+    o.unmount().unwrap();
+    assert!(!target.join("test").exists());
+}
+
+pub fn mount_overlay_written_image() {
+    use damascus::ImageWriter;
+    use nix::libc;
+    use std::{
+        fs::{self, Permissions, hard_link},
+        os::unix::fs::{PermissionsExt, symlink},
+    };
+
+    if !OverlayFs::is_available() || !ImageMount::is_available() {
+        skip!("OverlayFs or ImageMount is not available");
+        return;
+    }
+    if !geteuid().is_root() {
+        skip!("image are mounted through loop devices as root");
+        return;
+    }
+    let tmp = TempDir::default().to_path_buf();
+    let content = tmp.join("content");
+    let image = tmp.join("lower1.erofs");
+    let lower2 = tmp.join("lower2");
+    let target = tmp.join("mount");
+    create_dir_all(content.join("many")).unwrap();
+    create_dir_all(&lower2).unwrap();
+    create_dir_all(&target).unwrap();
+    write_test(&content.join("test"));
+    hard_link(content.join("test"), content.join("link")).unwrap();
+    symlink("test", content.join("symlink")).unwrap();
+    for i in 0..500 {
+        fs::write(
+            content.join("many").join(format!("entry{}", i)),
+            [b'x'; 5000],
+        )
+        .unwrap();
+    }
+    fs::set_permissions(content.join("many"), Permissions::from_mode(0o750)).unwrap();
+    let path = content.join("test").as_os_str().as_encoded_bytes().to_vec();
+    let path = std::ffi::CString::new(path).unwrap();
+    assert_eq!(
+        unsafe {
+            libc::lsetxattr(
+                path.as_ptr(),
+                c"user.damascus".as_ptr(),
+                b"value".as_ptr().cast(),
+                5,
+                0,
+            )
+        },
+        0
+    );
+
+    ImageWriter::new(&content).write(&image).unwrap();
+    assert_eq!(
+        damascus::ImageKind::detect(&image).unwrap(),
+        Some(damascus::ImageKind::Erofs)
+    );
+    let mut o = OverlayFs::readonly([&image, &lower2].iter(), &target).unwrap();
+    o.mount().unwrap();
+    read_test(&target.join("test"));
+    read_test(&target.join("symlink"));
+    assert_eq!(
+        fs::read_link(target.join("symlink")).unwrap().as_os_str(),
+        "test"
+    );
+    let meta = fs::metadata(target.join("test")).unwrap();
+    assert_eq!(meta.nlink(), 2);
+    assert_eq!(meta.ino(), fs::metadata(target.join("link")).unwrap().ino());
+    let meta = fs::metadata(target.join("many")).unwrap();
+    assert_eq!(meta.mode() & 0o777, 0o750);
+    assert_eq!(fs::read_dir(target.join("many")).unwrap().count(), 500);
+    assert_eq!(
+        fs::read(target.join("many").join("entry321")).unwrap(),
+        [b'x'; 5000]
+    );
+    let mut value = [0u8; 16];
+    let path = target.join("test").as_os_str().as_encoded_bytes().to_vec();
+    let path = std::ffi::CString::new(path).unwrap();
+    let len = unsafe {
+        libc::lgetxattr(
+            path.as_ptr(),
+            c"user.damascus".as_ptr(),
+            value.as_mut_ptr().cast(),
+            value.len(),
+        )
+    };
+    assert_eq!(&value[..len as usize], b"value");
+    o.unmount().unwrap();
+    assert!(!target.join("test").exists());
+}
+
+#[cfg(feature = "archive")]
+pub fn mount_overlay_archive_lower() {
+    use damascus::ArchiveMount;
+    use std::{fs::File, io::Write};
+    use zip::{ZipWriter, write::SimpleFileOptions};
+
+    if !OverlayFs::is_available() || !ArchiveMount::is_available() {
+        skip!("OverlayFs or ArchiveMount is not available");
+        return;
+    }
+    if !geteuid().is_root() {
+        skip!("archive are mounted on overlay lower as root");
+        return;
+    }
+    let tmp = TempDir::default().to_path_buf();
+    let archive = tmp.join("lower1.zip");
+    let lower2 = tmp.join("lower2");
+    let target = tmp.join("mount");
+    create_dir_all(&lower2).unwrap();
+    create_dir_all(&target).unwrap();
+    let mut zip = ZipWriter::new(File::create(&archive).unwrap());
+    zip.start_file(
+        "data/test",
+        SimpleFileOptions::default().unix_permissions(0o755),
+    )
+    .unwrap();
+    zip.write_all(SCRIPT_CONTENTS).unwrap();
+    zip.finish().unwrap();
+
+    let mut o = OverlayFs::readonly([&archive, &lower2].iter(), &target).unwrap();
+    o.mount().unwrap();
+    read_test(&target.join("data/test"));
+    execute_test(&target.join("data/test"));
+    read_only_test(&target.join("data/other"));
+
+    let reco = OverlayFs::recover(&target).unwrap();
+    assert_eq!(reco.lower()[0], archive);
+    o.unmount().unwrap();
+    assert!(!target.join("data").exists());
+}
+
+#[cfg(feature = "integrity")]
+pub fn mount_overlay_manifest_drift() {
+    use damascus::Manifest;
+    use std::fs;
+
+    if !OverlayFs::is_available() {
+        skip!("OverlayFs is not available");
+        return;
+    }
+    if !geteuid().is_root()
+        && let Err(_e) = setup_namespaces()
+    {
+        skip!("Cannot setup user namespaces this is not what we are testing");
+        return;
+    }
+    let tmp = TempDir::default().to_path_buf();
+    let lower1 = tmp.join("lower1");
+    let lower2 = tmp.join("lower2");
+    let target = tmp.join("mount");
+    create_dir_all(&lower1).unwrap();
+    create_dir_all(&lower2).unwrap();
+    create_dir_all(&target).unwrap();
+    write_test(&lower1.join("test"));
+    let manifest = Manifest::compute(&lower1).unwrap();
+    let mut o = OverlayFs::readonly([&lower1, &lower2].iter(), &target).unwrap();
+    assert!(o.set_manifest(&target, Some(manifest.clone())).is_err());
+    o.set_manifest(&lower1, Some(manifest.clone())).unwrap();
+    o.mount().unwrap();
+    read_test(&target.join("test"));
+    o.unmount().unwrap();
+
+    fs::write(lower1.join("test"), b"edited by hand").unwrap();
+    fs::write(lower1.join("extra"), b"extra").unwrap();
+    let report = manifest.verify(&lower1).unwrap();
+    assert_eq!(report.modified, vec![std::path::PathBuf::from("test")]);
+    assert_eq!(report.extra, vec![std::path::PathBuf::from("extra")]);
+    assert!(o.mount().is_err());
+    assert!(!target.join("test").exists());
+    o.set_manifest(&lower1, None).unwrap();
+    o.mount().unwrap();
+    o.unmount().unwrap();
+}
+
+pub fn mount_overlay_profile() {
+    use damascus::{Layer, Profile};
+    use std::fs;
+
+    if !OverlayFs::is_available() {
+        skip!("OverlayFs is not available");
+        return;
+    }
+    if !geteuid().is_root()
+        && let Err(_e) = setup_namespaces()
+    {
+        skip!("Cannot setup user namespaces this is not what we are testing");
+        return;
+    }
+    let tmp = TempDir::default().to_path_buf();
+    let (base, patch, extra) = (tmp.join("base"), tmp.join("patch"), tmp.join("extra"));
+    let target = tmp.join("mount");
+    for dir in [&base, &patch, &extra, &target] {
+        create_dir_all(dir).unwrap();
+    }
+    fs::write(base.join("config"), b"base").unwrap();
+    fs::write(patch.join("config"), b"patch").unwrap();
+    fs::write(extra.join("extra"), b"extra").unwrap();
+
+    let mut profile = Profile::new("default");
+    profile.push(Layer::new("base", &base)).unwrap();
+    profile.push(Layer::new("patch", &patch)).unwrap();
+    profile.push(Layer::new("extra", &extra)).unwrap();
+    profile.disable("extra").unwrap();
+    let mut o = OverlayFs::readonly(profile.lower().iter(), &target).unwrap();
+    o.mount().unwrap();
+    assert_eq!(fs::read(target.join("config")).unwrap(), b"patch");
+    assert!(!target.join("extra").exists());
+    assert!(!profile.apply(&mut o).unwrap());
+
+    profile.move_to("base", 2).unwrap();
+    profile.enable("extra").unwrap();
+    assert!(profile.apply(&mut o).unwrap());
+    assert!(o.mounted());
+    assert_eq!(fs::read(target.join("config")).unwrap(), b"base");
+    assert!(target.join("extra").exists());
+
+    // layers are restored when the new stack cannot be mounted
+    profile
+        .push(Layer::new("missing", tmp.join("missing")))
+        .unwrap();
+    assert!(profile.apply(&mut o).is_err());
+    assert!(o.mounted());
+    assert_eq!(fs::read(target.join("config")).unwrap(), b"base");
+    o.unmount().unwrap();
+}
+
+pub fn mount_overlay_hot_swap() {
+    use std::{
+        fs::{self, File},
+        io::{ErrorKind, Read},
+    };
+
+    if !OverlayFs::is_available() {
+        skip!("OverlayFs is not available");
+        return;
+    }
+    if !geteuid().is_root() {
+        skip!("hot swap require root privilege");
+        return;
+    }
+    let tmp = TempDir::default().to_path_buf();
+    let (base, old, new) = (tmp.join("base"), tmp.join("old"), tmp.join("new"));
+    let target = tmp.join("mount");
+    for dir in [&base, &old, &new, &target] {
+        create_dir_all(dir).unwrap();
+    }
+    fs::write(old.join("config"), b"old").unwrap();
+    fs::write(new.join("config"), b"new").unwrap();
+    fs::write(base.join("base"), b"base").unwrap();
+
+    let mut o = OverlayFs::readonly([&old, &base].iter(), &target).unwrap();
+    assert!(o.hot_swap([new.clone(), base.clone()]).is_err());
+    o.mount().unwrap();
+    let mut opened = File::open(target.join("config")).unwrap();
+    match o.hot_swap([new.clone(), base.clone()]) {
+        Err(e) if e.kind() == ErrorKind::Unsupported => {
+            o.unmount().unwrap();
+            skip!("hot swap is not supported by this kernel");
+            return;
+        }
+        x => x.unwrap(),
+    }
+    assert!(o.mounted());
+    assert_eq!(o.lower(), vec![new.as_path(), base.as_path()]);
+    // processes keep their view while new lookups reach the new stack
+    let mut content = vec![];
+    opened.read_to_end(&mut content).unwrap();
+    assert_eq!(content, b"old");
+    assert_eq!(fs::read(target.join("config")).unwrap(), b"new");
+    assert!(target.join("base").exists());
+    drop(opened);
+
+    // the mounted stack is kept when the new one cannot be mounted
+    assert!(o.hot_swap([tmp.join("missing"), base.clone()]).is_err());
+    assert_eq!(o.lower(), vec![new.as_path(), base.as_path()]);
+    assert_eq!(fs::read(target.join("config")).unwrap(), b"new");
+    o.unmount().unwrap();
+    assert!(!target.join("config").exists());
+}
+
+pub fn mount_overlay_unmount_modes() {
+    use damascus::{HoldKind, UnmountMode, holders};
+    use std::{
+        fs::{self, File},
+        io::ErrorKind,
+        process::Command,
+    };
+
+    if !OverlayFs::is_available() {
+        skip!("OverlayFs is not available");
+        return;
+    }
+    if !geteuid().is_root()
+        && let Err(_e) = setup_namespaces()
+    {
+        skip!("Cannot setup user namespaces this is not what we are testing");
+        return;
+    }
+    let tmp = TempDir::default().to_path_buf();
+    let (lower1, lower2) = (tmp.join("lower1"), tmp.join("lower2"));
+    let target = tmp.join("mount");
+    for dir in [&lower1, &lower2, &target] {
+        create_dir_all(dir).unwrap();
+    }
+    fs::write(lower1.join("file"), b"file").unwrap();
+
+    let mut o = OverlayFs::readonly([&lower1, &lower2].iter(), &target).unwrap();
+    o.set_unmount_mode(UnmountMode::Strict);
+    o.mount().unwrap();
+    let held = File::open(target.join("file")).unwrap();
+    let found = holders(&target).unwrap();
+    assert!(
+        found
+            .iter()
+            .any(|x| x.pid == std::process::id() && x.kind == HoldKind::Fd)
+    );
+    let err = o.unmount().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ResourceBusy);
+    assert!(err.to_string().contains(&std::process::id().to_string()));
+    assert!(o.mounted());
+    drop(held);
+
+    // holders are killed before the mount point is released
+    let mut child = Command::new("sleep")
+        .arg("60")
+        .current_dir(&target)
+        .spawn()
+        .unwrap();
+    o.set_unmount_mode(UnmountMode::KillHolders);
+    o.unmount().unwrap();
+    assert!(!child.wait().unwrap().success());
+    assert!(!o.mounted());
+    assert!(holders(&target).unwrap().is_empty());
+}
@@ -8,7 +8,10 @@
 use crate::skip;
 
 use super::{execute_test, read_only_test, read_test, write_test};
-use damascus::{Filesystem, UnionFsFuse};
+use damascus::{
+    Filesystem, LinuxFilesystem, StackableFilesystem, StateRecovery, UnionFsFuse,
+    unionfs_fuse::Branch,
+};
 use nix::unistd::geteuid;
 use std::fs::create_dir_all;
 use temp_testdir::TempDir;
@@ -93,3 +96,35 @@ pub fn mount_unionfs_fuse_rw_on_lower() {
 
     execute_test(&test);
 }
+
+pub fn recover_unionfs_fuse_branches_handle() {
+    if !UnionFsFuse::is_available() {
+        skip!("UnionFsFuse is not available");
+        return;
+    }
+    if geteuid().is_root() {
+        skip!("fuse mount can't be tested as root");
+        return;
+    }
+    let tmp = TempDir::default().to_path_buf();
+    let lower = tmp.join("lower");
+    let saves = tmp.join("saves");
+    let configs = tmp.join("configs");
+    let target = tmp.join("mount");
+    create_dir_all(&lower).unwrap();
+    create_dir_all(&saves).unwrap();
+    create_dir_all(&configs).unwrap();
+    create_dir_all(&target).unwrap();
+    let mut o = UnionFsFuse::with_branches(
+        [Branch::rw(&saves), Branch::rw(&configs), Branch::ro(&lower)],
+        &target,
+    )
+    .unwrap();
+    o.mount().unwrap();
+
+    let reco = UnionFsFuse::recover(&target).unwrap();
+    assert_eq!(reco.options(), o.options());
+    assert_eq!(reco.branches(), o.branches());
+    assert_eq!(reco.upper(), Some(saves.as_path()));
+    assert_eq!(reco.target(), o.target());
+}